@@ -2,6 +2,7 @@ extern crate tonic_build;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
   tonic_build::compile_protos("src/api/v1/log.proto")?;
+  tonic_build::compile_protos("src/api/health/v1/health.proto")?;
 
   Ok(())
 }
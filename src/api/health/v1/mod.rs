@@ -0,0 +1,2 @@
+// Include Rust files generated in build.rs
+tonic::include_proto!("grpc.health.v1");
@@ -1,2 +1,4 @@
+#![allow(non_camel_case_types)]
+
 // Include Rust files generated in build.rs
 tonic::include_proto!("log.v1");
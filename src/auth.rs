@@ -0,0 +1,163 @@
+/// Authenticates gRPC requests with a token shared by the
+/// server and its clients.
+///
+/// Clients send the token in the `authorization` metadata
+/// as `Bearer <token>`.
+use anyhow::{Context, Result};
+use thiserror::Error;
+use tonic::{service::Interceptor, Request, Status};
+
+/// Environment variable that contains the token.
+pub const API_TOKEN_VAR: &str = "API_TOKEN";
+
+/// Interceptor that rejects requests without the token
+/// with `Status::unauthenticated`.
+#[derive(Clone)]
+pub struct TokenAuth {
+  token: String,
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum AuthError {
+  #[error("the token is empty or only contains whitespace")]
+  EmptyToken,
+}
+
+impl std::fmt::Debug for TokenAuth {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    // The token is a secret, it should not end up in logs.
+    f.debug_struct("TokenAuth").finish_non_exhaustive()
+  }
+}
+
+impl TokenAuth {
+  /// Returns `AuthError::EmptyToken` if token is empty or only
+  /// contains whitespace, clients could not send it otherwise
+  /// and a typo in the config would leave the server unusable.
+  pub fn new(token: String) -> Result<Self, AuthError> {
+    if token.trim().is_empty() {
+      return Err(AuthError::EmptyToken);
+    }
+
+    Ok(Self { token })
+  }
+
+  /// Reads the token from `API_TOKEN`, see TokenAuth::new.
+  pub fn from_env() -> Result<Self> {
+    let token =
+      std::env::var(API_TOKEN_VAR).with_context(|| format!("{} is not set", API_TOKEN_VAR))?;
+
+    Self::new(token).with_context(|| format!("{} is not a valid token", API_TOKEN_VAR))
+  }
+
+  /// Returns true when authorization contains the token.
+  fn is_authorized(&self, authorization: &[u8]) -> bool {
+    match authorization.strip_prefix(b"Bearer ") {
+      None => false,
+      Some(token) => constant_time_eq(token, self.token.as_bytes()),
+    }
+  }
+}
+
+impl Interceptor for TokenAuth {
+  fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+    match request.metadata().get("authorization") {
+      None => Err(Status::unauthenticated("missing authorization token")),
+      Some(authorization) if self.is_authorized(authorization.as_bytes()) => Ok(request),
+      Some(_) => Err(Status::unauthenticated("invalid authorization token")),
+    }
+  }
+}
+
+/// Compares a and b in a time that does not depend on
+/// where they differ, so the token cannot be guessed
+/// one byte at a time by timing the responses.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+  use tonic::Code;
+
+  use super::*;
+
+  fn request(authorization: Option<&str>) -> Request<()> {
+    let mut request = Request::new(());
+
+    if let Some(authorization) = authorization {
+      request
+        .metadata_mut()
+        .insert("authorization", authorization.parse().unwrap());
+    }
+
+    request
+  }
+
+  #[test_log::test]
+  fn accepts_requests_with_the_token() {
+    let mut auth = TokenAuth::new(String::from("secret")).unwrap();
+
+    assert!(auth.call(request(Some("Bearer secret"))).is_ok());
+  }
+
+  #[test_log::test]
+  fn rejects_requests_without_a_token() {
+    let mut auth = TokenAuth::new(String::from("secret")).unwrap();
+
+    assert_eq!(
+      Code::Unauthenticated,
+      auth.call(request(None)).unwrap_err().code()
+    );
+  }
+
+  #[test_log::test]
+  fn rejects_requests_with_the_wrong_token() {
+    let mut auth = TokenAuth::new(String::from("secret")).unwrap();
+
+    for authorization in [
+      "Bearer wrong",
+      "Bearer secret2",
+      "Bearer ",
+      "secret",
+      "Basic secret",
+    ] {
+      assert_eq!(
+        Code::Unauthenticated,
+        auth.call(request(Some(authorization))).unwrap_err().code(),
+        "{}",
+        authorization
+      );
+    }
+  }
+
+  #[test_log::test]
+  fn rejects_empty_tokens() {
+    for token in ["", " ", "\t\n"] {
+      assert_eq!(
+        Some(AuthError::EmptyToken),
+        TokenAuth::new(String::from(token)).err(),
+        "{:?}",
+        token
+      );
+    }
+  }
+
+  #[test_log::test]
+  fn from_env_rejects_an_empty_token() {
+    std::env::set_var(API_TOKEN_VAR, "  ");
+
+    let error = TokenAuth::from_env().unwrap_err();
+
+    assert_eq!(
+      Some(&AuthError::EmptyToken),
+      error.downcast_ref::<AuthError>()
+    );
+
+    std::env::set_var(API_TOKEN_VAR, "secret");
+
+    assert!(TokenAuth::from_env().is_ok());
+
+    std::env::remove_var(API_TOKEN_VAR);
+  }
+}
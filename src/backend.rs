@@ -0,0 +1,582 @@
+/// Backends are where the store keeps its bytes.
+///
+/// The store only needs to append bytes, read them back at a position
+/// and know how many bytes it has, so anything that can do that can be
+/// used as storage: a file on disk or a plain Vec<u8> for tests.
+use std::{
+  fs::File,
+  io::{BufWriter, ErrorKind, Write},
+  os::unix::prelude::FileExt,
+};
+
+#[cfg(test)]
+use std::time::Duration;
+
+pub trait StorageBackend: std::fmt::Debug + Send + Sync {
+  /// Appends buffer to the end of the storage.
+  fn append(&mut self, buffer: &[u8]) -> std::io::Result<()>;
+
+  /// Fills buffer with the bytes that start at position.
+  ///
+  /// An error is returned if there are not enough bytes to fill the buffer.
+  fn read_at(&mut self, buffer: &mut [u8], position: u64) -> std::io::Result<()>;
+
+  /// Returns how many bytes the storage contains.
+  fn size(&self) -> u64;
+
+  /// Ensures every appended byte has reached the storage.
+  fn flush(&mut self) -> std::io::Result<()>;
+
+  /// Flushes and waits for the appended bytes to reach the disk,
+  /// so they survive a crash of the machine.
+  fn sync(&mut self) -> std::io::Result<()> {
+    self.flush()
+  }
+
+  /// Removes every byte after the first size bytes.
+  fn truncate(&mut self, size: u64) -> std::io::Result<()>;
+
+  /// Returns how many appended bytes have not reached the storage yet.
+  fn buffered_bytes(&self) -> u64 {
+    0
+  }
+}
+
+#[derive(Debug)]
+pub struct FileBackend {
+  /// File is wrapped in a BufWriter because it can be inefficient
+  /// to work directly with something that implements Write
+  /// because it may issue too many systems calls.
+  ///
+  /// BufWriter will keep an in-memory buffer of data
+  /// and write it to the underlying writer in batches.
+  writer: BufWriter<File>,
+  file_size: u64,
+  /// True when appended bytes may still be in the BufWriter,
+  /// reads only flush it when it is set.
+  dirty: bool,
+  /// How many times the BufWriter was flushed.
+  #[cfg(test)]
+  flushes: usize,
+}
+
+impl FileBackend {
+  pub fn new(file: File) -> std::io::Result<Self> {
+    let file_size = file.metadata()?.len();
+
+    Ok(Self {
+      writer: BufWriter::new(file),
+      file_size,
+      dirty: false,
+      #[cfg(test)]
+      flushes: 0,
+    })
+  }
+}
+
+impl StorageBackend for FileBackend {
+  fn append(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+    self.dirty = true;
+
+    self.writer.write_all(buffer)?;
+
+    self.file_size += buffer.len() as u64;
+
+    Ok(())
+  }
+
+  fn read_at(&mut self, buffer: &mut [u8], position: u64) -> std::io::Result<()> {
+    // Flush BufWriter to ensure that content has been written to the underlying
+    // file before we read it.
+    if self.dirty {
+      self.flush()?;
+    }
+
+    self.writer.get_ref().read_exact_at(buffer, position)
+  }
+
+  fn size(&self) -> u64 {
+    self.file_size
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    self.writer.flush()?;
+
+    self.dirty = false;
+
+    #[cfg(test)]
+    {
+      self.flushes += 1;
+    }
+
+    Ok(())
+  }
+
+  fn sync(&mut self) -> std::io::Result<()> {
+    self.flush()?;
+
+    self.writer.get_ref().sync_data()
+  }
+
+  fn truncate(&mut self, size: u64) -> std::io::Result<()> {
+    self.flush()?;
+
+    self.writer.get_ref().set_len(size)?;
+
+    self.file_size = size;
+
+    Ok(())
+  }
+
+  fn buffered_bytes(&self) -> u64 {
+    self.writer.buffer().len() as u64
+  }
+}
+
+/// Block size that buffers, file positions and lengths are aligned
+/// to when using direct I/O.
+///
+/// 4096 is a multiple of the logical block size of most disks.
+#[cfg(target_os = "linux")]
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Writes to a file opened with `O_DIRECT`, bypassing the page cache
+/// so writes do not stall when the kernel flushes dirty pages.
+///
+/// Direct I/O only accepts buffers whose address, length and file
+/// position are multiples of the block size, so there is no BufWriter
+/// in front of the file: each append rewrites the last, partially
+/// filled, block padded with zeros. The padding is truncated when
+/// the backend is flushed.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct DirectBackend {
+  /// Must have been opened with `O_DIRECT` and without `O_APPEND`.
+  file: File,
+  /// Bytes of the last block that is not full yet.
+  tail: Vec<u8>,
+  file_size: u64,
+  /// When true, the next write fails, see DirectBackend::fail_next_write.
+  #[cfg(test)]
+  fail_next_write: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectBackend {
+  pub fn new(file: File) -> std::io::Result<Self> {
+    let file_size = file.metadata()?.len();
+
+    let mut backend = Self {
+      file,
+      tail: Vec::new(),
+      file_size,
+      #[cfg(test)]
+      fail_next_write: false,
+    };
+
+    // Keep the last block in memory because the next append rewrites it.
+    let tail_len = (file_size % DIRECT_IO_ALIGNMENT as u64) as usize;
+    let mut tail = vec![0u8; tail_len];
+    backend.read_at(&mut tail, file_size - tail_len as u64)?;
+    backend.tail = tail;
+
+    Ok(backend)
+  }
+
+  /// Position of the first byte in the tail.
+  fn tail_position(&self) -> u64 {
+    self.file_size - self.tail.len() as u64
+  }
+
+  /// Makes the next write to the file fail, like `FaultyBackend::fail_write_at`,
+  /// which cannot fail the write from outside the backend.
+  #[cfg(test)]
+  fn fail_next_write(&mut self) {
+    self.fail_next_write = true;
+  }
+
+  #[cfg(not(test))]
+  fn write_all_at(&mut self, buffer: &[u8], position: u64) -> std::io::Result<()> {
+    self.file.write_all_at(buffer, position)
+  }
+
+  #[cfg(test)]
+  fn write_all_at(&mut self, buffer: &[u8], position: u64) -> std::io::Result<()> {
+    if std::mem::take(&mut self.fail_next_write) {
+      return Err(std::io::Error::other("injected write failure"));
+    }
+
+    self.file.write_all_at(buffer, position)
+  }
+}
+
+/// A zeroed buffer whose address is aligned for direct I/O.
+#[cfg(target_os = "linux")]
+struct AlignedBuffer {
+  bytes: Vec<u8>,
+  start: usize,
+  len: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl AlignedBuffer {
+  /// len must be a multiple of DIRECT_IO_ALIGNMENT.
+  fn new(len: usize) -> Self {
+    let bytes = vec![0u8; len + DIRECT_IO_ALIGNMENT];
+    let start = bytes.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+
+    Self { bytes, start, len }
+  }
+
+  fn as_slice(&self) -> &[u8] {
+    &self.bytes[self.start..self.start + self.len]
+  }
+
+  fn as_mut_slice(&mut self) -> &mut [u8] {
+    &mut self.bytes[self.start..self.start + self.len]
+  }
+}
+
+/// Rounds n up to the next multiple of DIRECT_IO_ALIGNMENT.
+#[cfg(target_os = "linux")]
+fn align_up(n: usize) -> usize {
+  n.div_ceil(DIRECT_IO_ALIGNMENT) * DIRECT_IO_ALIGNMENT
+}
+
+#[cfg(target_os = "linux")]
+impl StorageBackend for DirectBackend {
+  fn append(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+    let position = self.tail_position();
+
+    let len = self.tail.len() + buffer.len();
+
+    let mut aligned = AlignedBuffer::new(align_up(len));
+    aligned.as_mut_slice()[..self.tail.len()].copy_from_slice(&self.tail);
+    aligned.as_mut_slice()[self.tail.len()..len].copy_from_slice(buffer);
+
+    // The tail only changes once the write succeeded, otherwise the
+    // next append would write the bytes of the failed one again.
+    self.write_all_at(aligned.as_slice(), position)?;
+
+    self.tail.extend_from_slice(buffer);
+    self.file_size += buffer.len() as u64;
+
+    // Full blocks will not be written again.
+    let full_blocks_len = self.tail.len() / DIRECT_IO_ALIGNMENT * DIRECT_IO_ALIGNMENT;
+    self.tail.drain(..full_blocks_len);
+
+    Ok(())
+  }
+
+  fn read_at(&mut self, buffer: &mut [u8], position: u64) -> std::io::Result<()> {
+    if position + buffer.len() as u64 > self.file_size {
+      return Err(std::io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "failed to fill whole buffer",
+      ));
+    }
+
+    let aligned_position = position / DIRECT_IO_ALIGNMENT as u64 * DIRECT_IO_ALIGNMENT as u64;
+    let skip = (position - aligned_position) as usize;
+
+    let mut aligned = AlignedBuffer::new(align_up(skip + buffer.len()));
+
+    // The last block may be shorter than the aligned length, the
+    // bytes we need are there because position + len <= file_size.
+    let mut read = 0;
+    while read < skip + buffer.len() {
+      let n = self.file.read_at(
+        &mut aligned.as_mut_slice()[read..],
+        aligned_position + read as u64,
+      )?;
+
+      if n == 0 {
+        return Err(std::io::Error::new(
+          ErrorKind::UnexpectedEof,
+          "failed to fill whole buffer",
+        ));
+      }
+
+      read += n;
+    }
+
+    buffer.copy_from_slice(&aligned.as_slice()[skip..skip + buffer.len()]);
+
+    Ok(())
+  }
+
+  fn size(&self) -> u64 {
+    self.file_size
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    // Drop the zeros that pad the last block.
+    self.file.set_len(self.file_size)
+  }
+
+  fn sync(&mut self) -> std::io::Result<()> {
+    self.flush()?;
+
+    self.file.sync_data()
+  }
+
+  fn truncate(&mut self, size: u64) -> std::io::Result<()> {
+    self.file.set_len(size)?;
+
+    self.file_size = size;
+
+    // The new last block is the one the next append rewrites.
+    let tail_len = (size % DIRECT_IO_ALIGNMENT as u64) as usize;
+    let mut tail = vec![0u8; tail_len];
+    self.tail.clear();
+    self.read_at(&mut tail, size - tail_len as u64)?;
+    self.tail = tail;
+
+    Ok(())
+  }
+}
+
+/// Keeps every byte in memory, nothing is ever written to disk.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+  bytes: Vec<u8>,
+}
+
+impl MemoryBackend {
+  pub fn new() -> Self {
+    Self::default()
+  }
+}
+
+impl StorageBackend for MemoryBackend {
+  fn append(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+    self.bytes.extend_from_slice(buffer);
+
+    Ok(())
+  }
+
+  fn read_at(&mut self, buffer: &mut [u8], position: u64) -> std::io::Result<()> {
+    let start = position as usize;
+    let end = start + buffer.len();
+
+    if end > self.bytes.len() {
+      return Err(std::io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "failed to fill whole buffer",
+      ));
+    }
+
+    buffer.copy_from_slice(&self.bytes[start..end]);
+
+    Ok(())
+  }
+
+  fn size(&self) -> u64 {
+    self.bytes.len() as u64
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+
+  fn truncate(&mut self, size: u64) -> std::io::Result<()> {
+    self.bytes.truncate(size as usize);
+
+    Ok(())
+  }
+}
+
+/// Wraps a backend and makes chosen operations fail so error paths
+/// can be tested deterministically.
+///
+/// Operations are counted from 0, in the order they are issued:
+/// `fail_write_at(2)` fails the third call to `append`.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct FaultyBackend {
+  inner: Box<dyn StorageBackend>,
+  writes: usize,
+  reads: usize,
+  fail_write_at: Option<usize>,
+  short_write_at: Option<usize>,
+  fail_read_at: Option<usize>,
+  delay_read_at: Option<(usize, Duration)>,
+}
+
+#[cfg(test)]
+impl FaultyBackend {
+  pub fn new(inner: Box<dyn StorageBackend>) -> Self {
+    Self {
+      inner,
+      writes: 0,
+      reads: 0,
+      fail_write_at: None,
+      short_write_at: None,
+      fail_read_at: None,
+      delay_read_at: None,
+    }
+  }
+
+  /// The nth write fails without writing anything.
+  pub fn fail_write_at(mut self, n: usize) -> Self {
+    self.fail_write_at = Some(n);
+    self
+  }
+
+  /// The nth write only writes the first half of the buffer and then fails.
+  pub fn short_write_at(mut self, n: usize) -> Self {
+    self.short_write_at = Some(n);
+    self
+  }
+
+  /// The nth read fails as if the end of the storage was reached.
+  pub fn fail_read_at(mut self, n: usize) -> Self {
+    self.fail_read_at = Some(n);
+    self
+  }
+
+  /// The nth read blocks for delay before reading, like a read
+  /// from a stalled disk would.
+  pub fn delay_read_at(mut self, n: usize, delay: Duration) -> Self {
+    self.delay_read_at = Some((n, delay));
+    self
+  }
+}
+
+#[cfg(test)]
+impl StorageBackend for FaultyBackend {
+  fn append(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+    let write = self.writes;
+
+    self.writes += 1;
+
+    if self.fail_write_at == Some(write) {
+      return Err(std::io::Error::other("injected write failure"));
+    }
+
+    if self.short_write_at == Some(write) {
+      self.inner.append(&buffer[..buffer.len() / 2])?;
+
+      return Err(std::io::Error::new(
+        ErrorKind::WriteZero,
+        "injected short write",
+      ));
+    }
+
+    self.inner.append(buffer)
+  }
+
+  fn read_at(&mut self, buffer: &mut [u8], position: u64) -> std::io::Result<()> {
+    let read = self.reads;
+
+    self.reads += 1;
+
+    if self.fail_read_at == Some(read) {
+      return Err(std::io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "injected read failure",
+      ));
+    }
+
+    if let Some((n, delay)) = self.delay_read_at {
+      if n == read {
+        std::thread::sleep(delay);
+      }
+    }
+
+    self.inner.read_at(buffer, position)
+  }
+
+  fn size(&self) -> u64 {
+    self.inner.size()
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    self.inner.flush()
+  }
+
+  fn sync(&mut self) -> std::io::Result<()> {
+    self.inner.sync()
+  }
+
+  fn truncate(&mut self, size: u64) -> std::io::Result<()> {
+    self.inner.truncate(size)
+  }
+
+  fn buffered_bytes(&self) -> u64 {
+    self.inner.buffered_bytes()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::NamedTempFile;
+
+  use super::*;
+
+  #[test_log::test]
+  fn reads_only_flush_when_there_are_appended_bytes_to_flush() {
+    let mut backend = FileBackend::new(NamedTempFile::new().unwrap().into_file()).unwrap();
+
+    backend.append(b"hello world").unwrap();
+
+    let mut buffer = [0u8; 5];
+
+    for _ in 0..1000 {
+      backend.read_at(&mut buffer, 6).unwrap();
+
+      assert_eq!(b"world", &buffer);
+    }
+
+    assert_eq!(1, backend.flushes);
+
+    backend.append(b"!").unwrap();
+
+    backend.read_at(&mut buffer[..1], 11).unwrap();
+
+    assert_eq!(b"!", &buffer[..1]);
+    assert_eq!(2, backend.flushes);
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test_log::test]
+  fn direct_backend_appends_after_a_failed_write_are_read_back() {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let file = NamedTempFile::new().unwrap();
+
+    let open = || {
+      std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(file.path())
+        .unwrap()
+    };
+
+    let mut backend = DirectBackend::new(open()).unwrap();
+
+    backend.append(b"abc").unwrap();
+
+    backend.fail_next_write();
+    assert!(backend.append(b"def").is_err());
+
+    backend.append(b"ghi").unwrap();
+
+    assert_eq!(6, backend.size());
+
+    let mut buffer = [0u8; 6];
+    backend.read_at(&mut buffer, 0).unwrap();
+    assert_eq!(b"abcghi", &buffer);
+
+    backend.flush().unwrap();
+
+    // The file contains exactly what was appended successfully.
+    let mut backend = DirectBackend::new(open()).unwrap();
+
+    assert_eq!(6, backend.size());
+
+    backend.read_at(&mut buffer, 0).unwrap();
+    assert_eq!(b"abcghi", &buffer);
+  }
+}
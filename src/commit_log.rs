@@ -1,14 +1,45 @@
-use std::sync::RwLock;
+use sha2::{Digest, Sha256};
+use std::{
+  collections::HashMap,
+  fs::{DirBuilder, File},
+  io,
+  ops::{ControlFlow, Range},
+  os::unix::fs::DirBuilderExt,
+  path::Path,
+  sync::{
+    mpsc::{self, Receiver, SyncSender, TrySendError},
+    Arc, Mutex, RwLock,
+  },
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use thiserror::Error;
 
 use anyhow::Result;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
   api,
-  segment::{self, Segment},
+  index::{self, Index, IndexError},
+  segment::{self, Segment, SegmentError, SegmentRecovery},
+  store::BufferPool,
+  wal::{self, WriteAheadLog},
 };
 
+/// Metadata attached to a record, independent of its value.
+pub type Headers = HashMap<String, Vec<u8>>;
+
+/// A segment shared between the log and its readers, see Log::segment_for.
+pub type SharedSegment = Arc<RwLock<Segment>>;
+
+/// Log does not lock its list of segments: appends and every other
+/// method that changes the log take `&mut self`, so a log shared
+/// between tasks has to be wrapped in a lock, like `LogServer` does.
+/// Reads take `&self` and can run in parallel under a read lock.
+///
+/// Each segment has a lock of its own. Reads only lock the segment
+/// they read from and appends only lock the active segment, so a
+/// reader that got a segment from Log::segment_for can release the
+/// lock around the log and read while records are being appended.
 #[derive(Debug)]
 pub struct Log {
   directory: String,
@@ -20,9 +51,30 @@ pub struct Log {
   /// oof the last segment in segments;
   active_segment: usize,
   /// Segments are ordered from oldest to newest.
-  segments: Vec<Segment>,
-  // TODO: remove me
-  lock: RwLock<bool>,
+  segments: Vec<SharedSegment>,
+  /// When true, segments are kept in memory instead of
+  /// being written to `directory`.
+  in_memory: bool,
+  /// Segment created ahead of time that becomes the active
+  /// segment when the current one is maxed.
+  pre_rolled_segment: Option<Segment>,
+  /// Every appended record is sent to each subscriber.
+  subscribers: Mutex<Vec<SyncSender<(u64, api::v1::Record)>>>,
+  /// When the last record was appended, or when the log was created.
+  last_append_at: Instant,
+  /// True when there were no segments to recover when the log was opened.
+  created: bool,
+  /// The newest record, only kept when consecutive duplicates are skipped.
+  last_record: Option<api::v1::Record>,
+  /// Decides the offset of each appended record.
+  offset_allocator: Box<dyn OffsetAllocator>,
+  /// Records each append before it is applied, when enabled.
+  wal: Option<WriteAheadLog>,
+  /// Hash of the newest record appended by Log::append_audited,
+  /// None until it is needed for the first time.
+  audit_chain_head: Option<[u8; 32]>,
+  /// True between Log::begin_rebuild and Log::end_rebuild.
+  rebuilding: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -30,12 +82,380 @@ pub struct Config {
   initial_offset: u64,
   max_store_bytes_per_segment: u64,
   max_index_bytes_per_segment: u64,
+  /// When the active segment is filled past this percentage,
+  /// the next segment is created ahead of time so rolling over
+  /// does not pay for creating its files.
+  pre_roll_percent: Option<u64>,
+  /// When true, reading a record whose index entry points to
+  /// the wrong position fixes the index entry instead of failing.
+  read_repair: bool,
+  /// What to do with segment files whose name is not an offset.
+  bad_file_policy: BadFilePolicy,
+  /// When true, store files are written using direct I/O for
+  /// predictable write latency. Only supported on Linux.
+  direct_io: bool,
+  /// When true, each append is recorded in a write-ahead log before
+  /// it is applied, so an append interrupted by a crash is finished
+  /// when the log is opened again. Every append waits for the
+  /// write-ahead log to reach the disk. Ignored by in memory logs.
+  write_ahead_log: bool,
+  /// What to do when the index of the active segment is full
+  /// but its store is not.
+  on_index_full: IndexFullPolicy,
+  /// When set, creating a segment past this many segments fails with
+  /// `CommitLogError::TooManySegments` instead of letting a
+  /// misconfigured log create segments until it runs out of files.
+  max_segments_hard_limit: Option<usize>,
+  /// When true, appending a record equal to the newest record in
+  /// the log returns the offset of the newest record instead of
+  /// appending it again. Useful for streams of repeated heartbeats.
+  dedup_consecutive: bool,
+  /// When set, Log::roll_if_idle rolls the active segment once
+  /// no record has been appended for this long, so its records
+  /// are not kept in a half-full segment indefinitely.
+  idle_roll_timeout: Option<Duration>,
+  /// When set, appends fail with `CommitLogError::OffsetCeilingReached`
+  /// once the next offset would not be lower than this, for logs
+  /// that own a fixed range of offsets.
+  max_offset: Option<u64>,
+  /// When set, a store writes its buffered appends to its file once
+  /// this many bytes are buffered, which bounds how many appended
+  /// bytes may be lost on a crash regardless of the buffer capacity.
+  flush_after_bytes: Option<u64>,
+  /// Permissions of the segment files created by the log, `0o640`
+  /// for example. The log directory gets the same permissions plus
+  /// the execute bits that match its read bits, `0o750` for example.
+  /// The umask still applies. When None, the default permissions are used.
+  file_mode: Option<u32>,
+  /// When set, appends fail with `CommitLogError::RecordTooLarge`
+  /// if a record value is bigger than this many bytes.
+  max_record_bytes: Option<u64>,
+  /// When true, records are stored without their offset,
+  /// see `Segment::encode`, which saves the bytes of every offset.
+  /// Only applies to segments created empty, every segment is
+  /// read in the format it was written with.
+  omit_record_offsets: bool,
+  /// When set, store entries bigger than this many bytes are
+  /// not appended, and reading one fails because the store is
+  /// assumed to be corrupted, see `segment::Config::max_entry_bytes`.
+  max_entry_bytes: Option<u64>,
+  /// When true, Log::new runs Log::recover before the log is used.
+  recover_on_open: bool,
+}
+
+/// What Log::new does when it finds a segment file
+/// whose name is not an offset, `abc.store` for example.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BadFilePolicy {
+  /// Fail with `CommitLogError::BadSegmentFile`.
+  Strict,
+  /// Log the file and ignore it.
+  Skip,
+  /// Move the file to the `quarantine` directory inside the log directory.
+  Quarantine,
+}
+
+/// What the log does when the index of the active segment is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexFullPolicy {
+  /// Make a new segment the active one, even if the store has room.
+  RollSegment,
+  /// Grow the index so the segment is only rolled when its store is full.
+  /// Avoids segments whose stores are tiny when records are small.
+  GrowIndex,
 }
 
+/// Directory, inside the log directory, where bad segment files are moved to.
+const QUARANTINE_DIRECTORY: &str = "quarantine";
+
 #[derive(Debug, PartialEq, Error)]
 pub enum CommitLogError {
   #[error("offset is out of bounds, no segment contains the offset {0}")]
   OffsetOutOfBounds(u64),
+  #[error("read of offset {0} did not finish in time")]
+  ReadTimeout(u64),
+  #[error("segment file {0} does not have an offset as its name")]
+  BadSegmentFile(String),
+  #[error("truncating up to offset {0} would remove the active segment")]
+  CannotTruncateActiveSegment(u64),
+  #[error("there is no segment whose base offset is {0}")]
+  SegmentNotFound(u64),
+  #[error("the log already has the maximum of {0} segments")]
+  TooManySegments(usize),
+  #[error("in memory logs have no segments on disk to reopen")]
+  CannotReopenInMemoryLog,
+  #[error("in memory logs have no directory to relocate")]
+  CannotRelocateInMemoryLog,
+  #[error("records can only be appended to a given segment while the log is being rebuilt")]
+  NotRebuilding,
+  #[error("segment {base_offset} expects offset {expected} but the record has offset {found}")]
+  UnexpectedRebuildOffset {
+    base_offset: u64,
+    expected: u64,
+    found: u64,
+  },
+  #[error("offset {offset} belongs to the segment after segment {base_offset}")]
+  OffsetOutsideSegment { base_offset: u64, offset: u64 },
+  #[error("the log cannot be relocated to {0} because it is not empty")]
+  RelocationTargetNotEmpty(String),
+  #[error("record too large, its value has {size} bytes but the maximum is {max} bytes")]
+  RecordTooLarge { size: u64, max: u64 },
+  #[error("a log needs at least one segment")]
+  NoSegments,
+  #[error("offset {offset} is not lower than the offset ceiling {max_offset}")]
+  OffsetCeilingReached { offset: u64, max_offset: u64 },
+  #[error("segment {base_offset} is invalid: {source}")]
+  InvalidSegment {
+    base_offset: u64,
+    #[source]
+    source: SegmentError,
+  },
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum ConfigError {
+  #[error(
+    "max_index_bytes_per_segment is {max_index_bytes} but a segment needs at least {min} bytes to hold one index entry"
+  )]
+  SegmentTooSmall { max_index_bytes: u64, min: u64 },
+}
+
+/// How many bytes the segments of a log use.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DiskUsage {
+  /// Bytes used by the store files.
+  pub store_bytes: u64,
+  /// Bytes reserved for the index files, while a segment is open
+  /// its index file is grown to the max index size.
+  pub index_allocated_bytes: u64,
+  /// Bytes used by the index entries, the size index files
+  /// are truncated to when segments are closed.
+  pub index_live_bytes: u64,
+}
+
+/// Reported by Log::verify after each segment is checked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VerifyProgress {
+  /// Base offset of the segment that was just checked.
+  pub segment: u64,
+  /// Records checked so far, in every segment checked.
+  pub records_checked: u64,
+  /// Store bytes checked so far, in every segment checked.
+  pub bytes_checked: u64,
+  /// Store bytes in the whole log, to estimate how much is left.
+  pub bytes_total: u64,
+}
+
+/// How Log::verify finished when no problem was found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifyOutcome {
+  /// Every segment was checked.
+  Completed,
+  /// The progress callback stopped the scan
+  /// after the segment with this base offset.
+  Canceled { segment: u64 },
+}
+
+/// Records returned by Log::read_range.
+#[derive(Debug, PartialEq)]
+pub struct RangeRead {
+  pub records: Vec<api::v1::Record>,
+  /// Offset of the first record that was not returned, where the
+  /// next read should start to continue where this one stopped.
+  pub next_offset: u64,
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum ContiguityError {
+  #[error("segment starts at offset {found} but the previous segment ends at offset {expected}")]
+  Gap { expected: u64, found: u64 },
+}
+
+/// Header of audited records that contains the hash of the previous audited record.
+pub const AUDIT_PREV_HASH_HEADER: &str = "audit-prev-hash";
+/// Header of audited records that contains when the record was appended.
+pub const AUDIT_TIMESTAMP_HEADER: &str = "audit-timestamp-ms";
+/// Header of audited records that contains the hash of the record.
+pub const AUDIT_RECORD_HASH_HEADER: &str = "audit-record-hash";
+
+/// Returned by Log::append_audited, a client that keeps it
+/// can later check that the record has not been changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppendReceipt {
+  pub offset: u64,
+  /// Milliseconds since the Unix epoch.
+  pub timestamp_ms: u64,
+  /// Hash of the previous audited record, zeroed for the first one.
+  pub prev_hash: [u8; 32],
+  /// SHA-256 of prev_hash, timestamp_ms and the record value.
+  pub record_hash: [u8; 32],
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum ChainError {
+  #[error("the audited record with offset {offset} does not have valid audit headers")]
+  MalformedRecord { offset: u64 },
+  #[error("the audited record with offset {offset} does not point to the previous audited record")]
+  BrokenLink { offset: u64 },
+  #[error("the audited record with offset {offset} does not match its hash")]
+  HashMismatch { offset: u64 },
+}
+
+/// Returns the hash of an audited record.
+fn audit_hash(prev_hash: &[u8; 32], timestamp_ms: u64, value: &[u8]) -> [u8; 32] {
+  let mut hasher = Sha256::new();
+
+  hasher.update(prev_hash);
+  hasher.update(timestamp_ms.to_be_bytes());
+  hasher.update(value);
+
+  hasher.finalize().into()
+}
+
+/// Decides which offset is assigned to each appended record,
+/// so a higher layer can control offsets, to interleave partition
+/// ids into them for example.
+///
+/// Offsets may have gaps but must increase. Methods that walk the log
+/// offset by offset, like Log::since, expect offsets without gaps.
+pub trait OffsetAllocator: std::fmt::Debug + Send + Sync {
+  /// Returns the offset of the next record, lowest is the lowest
+  /// offset that has not been used yet.
+  fn next_offset(&mut self, lowest: u64) -> u64;
+}
+
+/// Assigns offsets one after the other, without gaps.
+#[derive(Debug, Default)]
+pub struct SequentialOffsets;
+
+impl OffsetAllocator for SequentialOffsets {
+  fn next_offset(&mut self, lowest: u64) -> u64 {
+    lowest
+  }
+}
+
+/// State of the active segment that is normally not observable,
+/// returned by Log::active_segment_debug.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, PartialEq)]
+pub struct ActiveSegmentDebug {
+  pub next_offset: u64,
+  /// Bytes appended to the store that are still in memory.
+  pub buffered_bytes: u64,
+}
+
+/// How many records a subscription buffers before
+/// its subscriber is dropped, see Log::subscribe.
+pub const SUBSCRIPTION_CAPACITY: usize = 1024;
+
+/// Receives the records appended to a log after Log::subscribe was called.
+///
+/// Iterating blocks until the next record is appended and ends
+/// when the log is dropped or drops the subscriber.
+#[derive(Debug)]
+pub struct Subscription {
+  receiver: Receiver<(u64, api::v1::Record)>,
+}
+
+/// Reads records from a log, remembering the segment
+/// that contained the last offset read.
+///
+/// Consecutive offsets are almost always in the same segment or
+/// in the next one, both are checked before searching every segment.
+#[derive(Debug)]
+pub struct SequentialReader<'a> {
+  log: &'a Log,
+  /// Position in `segments` of the segment used by the last read.
+  segment: usize,
+  /// How many times every segment had to be searched.
+  searches: usize,
+}
+
+impl SequentialReader<'_> {
+  /// Same as Log::read.
+  pub fn read(&mut self, offset: u64) -> Result<api::v1::Record> {
+    let contains = |index: usize| {
+      self
+        .log
+        .segments
+        .get(index)
+        .map(|segment| segment.read().unwrap())
+        .is_some_and(|segment| segment.base_offset() <= offset && offset < segment.next_offset())
+    };
+
+    if !contains(self.segment) {
+      if contains(self.segment + 1) {
+        self.segment += 1;
+      } else {
+        self.searches += 1;
+
+        self.segment = self
+          .log
+          .find_segment_index(offset)
+          .ok_or(CommitLogError::OffsetOutOfBounds(offset))?;
+      }
+    }
+
+    self.log.segments[self.segment].read().unwrap().read(offset)
+  }
+}
+
+/// Reads the records of a log as a stream of bytes, see Log::reader.
+#[derive(Debug)]
+pub struct LogReader<'a> {
+  reader: SequentialReader<'a>,
+  /// Offset of the next record to read from the log.
+  next_offset: u64,
+  /// The record being read, with its length prefix.
+  entry: Vec<u8>,
+  /// How many bytes of entry were already read.
+  entry_read: usize,
+}
+
+impl io::Read for LogReader<'_> {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    while self.entry_read == self.entry.len() {
+      if self.next_offset >= self.reader.log.highest_offset() {
+        return Ok(0);
+      }
+
+      let offset = self.next_offset;
+
+      self.next_offset += 1;
+
+      let record = match self.reader.read(offset) {
+        Ok(record) => record,
+        // Offsets may have gaps.
+        Err(e) if e.downcast_ref::<IndexError>().is_some() => continue,
+        Err(e) => return Err(io::Error::other(e)),
+      };
+
+      let bytes = segment::encode_record(&record);
+
+      self.entry.clear();
+      self
+        .entry
+        .extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+      self.entry.extend_from_slice(&bytes);
+      self.entry_read = 0;
+    }
+
+    let len = buffer.len().min(self.entry.len() - self.entry_read);
+
+    buffer[..len].copy_from_slice(&self.entry[self.entry_read..self.entry_read + len]);
+
+    self.entry_read += len;
+
+    Ok(len)
+  }
+}
+
+impl Iterator for Subscription {
+  type Item = (u64, api::v1::Record);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.receiver.recv().ok()
+  }
 }
 
 impl Default for Config {
@@ -44,24 +464,211 @@ impl Default for Config {
       initial_offset: 0,
       max_store_bytes_per_segment: 1024,
       max_index_bytes_per_segment: 1024,
+      pre_roll_percent: None,
+      read_repair: false,
+      bad_file_policy: BadFilePolicy::Strict,
+      direct_io: false,
+      dedup_consecutive: false,
+      max_segments_hard_limit: None,
+      on_index_full: IndexFullPolicy::RollSegment,
+      write_ahead_log: false,
+      idle_roll_timeout: None,
+      max_offset: None,
+      flush_after_bytes: None,
+      file_mode: None,
+      max_record_bytes: None,
+      omit_record_offsets: false,
+      max_entry_bytes: None,
+      recover_on_open: false,
     }
   }
 }
 
-impl Log {
-  fn read_segments_from_disk(directory: &str, config: &Config) -> Result<Vec<Segment>> {
-    info!(directory, "reading segments from disk");
+/// Builds a Config, settings that are not set keep their default value.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+  config: Config,
+}
 
-    // Ensure `directory` exists.
-    std::fs::create_dir_all(directory)?;
+impl ConfigBuilder {
+  /// Offset of the first record appended to a new log.
+  pub fn initial_offset(mut self, initial_offset: u64) -> Self {
+    self.config.initial_offset = initial_offset;
+    self
+  }
+
+  /// Size the store of a segment may reach before a new segment is created.
+  pub fn max_store_bytes_per_segment(mut self, max_store_bytes: u64) -> Self {
+    self.config.max_store_bytes_per_segment = max_store_bytes;
+    self
+  }
+
+  /// Size the index of a segment may reach before a new segment is created.
+  pub fn max_index_bytes_per_segment(mut self, max_index_bytes: u64) -> Self {
+    self.config.max_index_bytes_per_segment = max_index_bytes;
+    self
+  }
+
+  /// Stores records without their offset, see `Segment::encode`.
+  pub fn omit_record_offsets(mut self, omit_record_offsets: bool) -> Self {
+    self.config.omit_record_offsets = omit_record_offsets;
+    self
+  }
+
+  /// Runs Log::recover when the log is opened.
+  pub fn recover_on_open(mut self, recover_on_open: bool) -> Self {
+    self.config.recover_on_open = recover_on_open;
+    self
+  }
+
+  /// Makes creating a segment past limit segments fail with
+  /// `CommitLogError::TooManySegments`.
+  pub fn max_segments_hard_limit(mut self, limit: usize) -> Self {
+    self.config.max_segments_hard_limit = Some(limit);
+    self
+  }
+
+  /// Makes appends fail with `CommitLogError::OffsetCeilingReached`
+  /// once the next offset would not be lower than max_offset.
+  pub fn max_offset(mut self, max_offset: u64) -> Self {
+    self.config.max_offset = Some(max_offset);
+    self
+  }
+
+  /// What to do with segment files whose name is not an offset.
+  pub fn bad_file_policy(mut self, bad_file_policy: BadFilePolicy) -> Self {
+    self.config.bad_file_policy = bad_file_policy;
+    self
+  }
+
+  /// What to do when the index of the active segment is full but its store is not.
+  pub fn on_index_full(mut self, on_index_full: IndexFullPolicy) -> Self {
+    self.config.on_index_full = on_index_full;
+    self
+  }
+
+  /// Records each append in a write-ahead log before it is applied.
+  pub fn write_ahead_log(mut self, write_ahead_log: bool) -> Self {
+    self.config.write_ahead_log = write_ahead_log;
+    self
+  }
+
+  /// Creates the next segment ahead of time once the active
+  /// segment is filled past percent.
+  pub fn pre_roll_percent(mut self, percent: u64) -> Self {
+    self.config.pre_roll_percent = Some(percent);
+    self
+  }
+
+  /// Fixes index entries that point to the wrong position when they are read.
+  pub fn read_repair(mut self, read_repair: bool) -> Self {
+    self.config.read_repair = read_repair;
+    self
+  }
+
+  /// Writes store files using direct I/O, only supported on Linux.
+  pub fn direct_io(mut self, direct_io: bool) -> Self {
+    self.config.direct_io = direct_io;
+    self
+  }
+
+  /// Skips appending a record equal to the newest record in the log.
+  pub fn dedup_consecutive(mut self, dedup_consecutive: bool) -> Self {
+    self.config.dedup_consecutive = dedup_consecutive;
+    self
+  }
+
+  /// Lets Log::roll_if_idle roll the active segment once
+  /// no record has been appended for timeout.
+  pub fn idle_roll_timeout(mut self, timeout: Duration) -> Self {
+    self.config.idle_roll_timeout = Some(timeout);
+    self
+  }
+
+  /// Writes the buffered appends of a store to its file once this many bytes are buffered.
+  pub fn flush_after_bytes(mut self, flush_after_bytes: u64) -> Self {
+    self.config.flush_after_bytes = Some(flush_after_bytes);
+    self
+  }
+
+  /// Permissions of the segment files created by the log, `0o640` for example.
+  pub fn file_mode(mut self, file_mode: u32) -> Self {
+    self.config.file_mode = Some(file_mode);
+    self
+  }
+
+  /// Makes appends fail with `CommitLogError::RecordTooLarge`
+  /// if a record value is bigger than max_record_bytes.
+  pub fn max_record_bytes(mut self, max_record_bytes: u64) -> Self {
+    self.config.max_record_bytes = Some(max_record_bytes);
+    self
+  }
+
+  /// Makes appending a store entry bigger than max_entry_bytes fail, and
+  /// reading one fail with `StoreError::ImplausibleLength`.
+  pub fn max_entry_bytes(mut self, max_entry_bytes: u64) -> Self {
+    self.config.max_entry_bytes = Some(max_entry_bytes);
+    self
+  }
+
+  pub fn build(self) -> Config {
+    self.config
+  }
+}
+
+impl Config {
+  pub fn builder() -> ConfigBuilder {
+    ConfigBuilder::default()
+  }
+
+  /// Returns an error if segments created with this config
+  /// could not hold a single record.
+  ///
+  /// max_store_bytes_per_segment is a soft limit, a record bigger
+  /// than it still fits in a segment of its own.
+  pub fn validate(&self) -> Result<(), ConfigError> {
+    if self.max_index_bytes_per_segment < index::ENTRY_WIDTH {
+      return Err(ConfigError::SegmentTooSmall {
+        max_index_bytes: self.max_index_bytes_per_segment,
+        min: index::ENTRY_WIDTH,
+      });
+    }
+
+    Ok(())
+  }
+
+  /// Returns the config used by every segment in the log.
+  pub fn segment_config(&self) -> segment::Config {
+    segment::Config {
+      max_index_bytes: self.max_index_bytes_per_segment,
+      max_store_bytes: self.max_store_bytes_per_segment,
+      read_repair: self.read_repair,
+      direct_io: self.direct_io,
+      grow_index: self.on_index_full == IndexFullPolicy::GrowIndex,
+      flush_after_bytes: self.flush_after_bytes,
+      file_mode: self.file_mode,
+      omit_record_offsets: self.omit_record_offsets,
+      max_entry_bytes: self.max_entry_bytes,
+      ..Default::default()
+    }
+  }
+}
 
+impl Log {
+  /// Returns the base offsets of the segments stored in directory.
+  ///
+  /// Store files whose name is not an offset are handled by policy.
+  fn base_offsets_on_disk(directory: &str, policy: BadFilePolicy) -> Result<Vec<u64>> {
     let file_names: Vec<String> = std::fs::read_dir(directory)?
       .filter(|entry| entry.is_ok())
       .map(|entry| entry.unwrap().file_name())
-      .map(|file_name| file_name.into_string().unwrap())
+      // Files whose name is not valid UTF-8 were not created by the log.
+      .filter_map(|file_name| file_name.into_string().ok())
       // We only care about .store files because store and index files
       // have the same offsets and we only want each offset once.
       .filter(|file_name| file_name.ends_with(".store"))
+      // Pre-rolled segments are never recovered, they are empty.
+      .filter(|file_name| *file_name != format!("{}.store", segment::PRE_ROLLED_FILE_STEM))
       .collect();
 
     info!("store files found on disk: {:?}", &file_names);
@@ -74,12 +681,15 @@ impl Log {
     // 2.store
     // ==>
     // [0, 1, 2]
-    let mut offsets: Vec<u64> = file_names
-      .iter()
-      .map(|file_name| file_name.split(".").collect::<Vec<_>>())
-      .map(|file_name_pieces| file_name_pieces.iter().rev().nth(1).unwrap().to_string())
-      .map(|offset| offset.parse::<u64>().unwrap())
-      .collect();
+    let mut offsets: Vec<u64> = Vec::with_capacity(file_names.len());
+
+    for file_name in file_names {
+      // SAFETY: unwrap() is safe because we only kept .store files.
+      match file_name.strip_suffix(".store").unwrap().parse::<u64>() {
+        Ok(offset) => offsets.push(offset),
+        Err(_) => Self::handle_bad_file(directory, &file_name, policy)?,
+      }
+    }
 
     // Sort offsets in ascending order.
     // Offsets should look like this: 0, 1, 2
@@ -87,19 +697,67 @@ impl Log {
 
     info!("store files offsets found on disk: {:?}", &offsets);
 
+    Ok(offsets)
+  }
+
+  /// Applies policy to the store file file_name and to its index file.
+  fn handle_bad_file(directory: &str, file_name: &str, policy: BadFilePolicy) -> Result<()> {
+    match policy {
+      BadFilePolicy::Strict => Err(CommitLogError::BadSegmentFile(file_name.to_owned()).into()),
+      BadFilePolicy::Skip => {
+        warn!(
+          file_name,
+          "skipping segment file whose name is not an offset"
+        );
+
+        Ok(())
+      }
+      BadFilePolicy::Quarantine => {
+        warn!(
+          file_name,
+          "quarantining segment file whose name is not an offset"
+        );
+
+        let quarantine = Path::new(directory).join(QUARANTINE_DIRECTORY);
+
+        std::fs::create_dir_all(&quarantine)?;
+
+        // SAFETY: unwrap() is safe because file_name is a .store file.
+        let index_file_name = format!("{}.index", file_name.strip_suffix(".store").unwrap());
+
+        for file_name in [file_name, &index_file_name] {
+          let path = Path::new(directory).join(file_name);
+
+          if path.exists() {
+            std::fs::rename(path, quarantine.join(file_name))?;
+          }
+        }
+
+        Ok(())
+      }
+    }
+  }
+
+  fn read_segments_from_disk(directory: &str, config: &Config) -> Result<Vec<Segment>> {
+    info!(directory, "reading segments from disk");
+
+    // Ensure `directory` exists.
+    let mut builder = DirBuilder::new();
+
+    builder.recursive(true);
+
+    if let Some(mode) = config.file_mode {
+      // Directories can only be listed if they are executable.
+      builder.mode(mode | ((mode & 0o444) >> 2));
+    }
+
+    builder.create(directory)?;
+
+    let offsets = Self::base_offsets_on_disk(directory, config.bad_file_policy)?;
+
     let segments = offsets
       .into_iter()
-      .map(|offset| {
-        Segment::new(
-          directory,
-          offset,
-          segment::Config {
-            max_index_bytes: config.max_index_bytes_per_segment,
-            max_store_bytes: config.max_store_bytes_per_segment,
-            initial_offset: 0,
-          },
-        )
-      })
+      .map(|offset| Segment::new(directory, offset, config.segment_config()))
       .collect::<Result<Vec<Segment>, anyhow::Error>>()?;
 
     info!("{} segments found on disk", segments.len());
@@ -107,275 +765,4214 @@ impl Log {
     Ok(segments)
   }
 
+  /// Returns the range of offsets contained by each segment stored
+  /// in directory, ordered from oldest to newest segment.
+  ///
+  /// Index files are searched for their last entry without being
+  /// memory mapped and stores are not opened, which makes it cheap
+  /// enough to be used for stats and retention decisions, even on
+  /// the directory of an open log. Files whose name is not an
+  /// offset are skipped.
+  pub fn offset_spans(directory: &str) -> Result<Vec<Range<u64>>> {
+    Self::base_offsets_on_disk(directory, BadFilePolicy::Skip)?
+      .into_iter()
+      .map(|base_offset| {
+        let index_file_path = Path::new(directory).join(format!("{}.index", base_offset));
+
+        let store_file_path = Path::new(directory).join(format!("{}.store", base_offset));
+
+        let next_offset = match Index::read_last_offset(&index_file_path)? {
+          // The index of an open segment cannot tell if it contains
+          // the first entry, the store can.
+          Some(0) if std::fs::metadata(store_file_path)?.len() == 0 => base_offset,
+          Some(offset) => base_offset + (offset as u64) + 1,
+          None => base_offset,
+        };
+
+        Ok(base_offset..next_offset)
+      })
+      .collect()
+  }
+
   pub fn new(directory: String, config: Config) -> Result<Self> {
     info!("creating log in {}", &directory);
 
-    let mut segments = Self::read_segments_from_disk(&directory, &config)?;
+    config.validate()?;
 
-    // If the log is new and there are no segments on disk,
-    // we create the first one.
-    if segments.is_empty() {
-      info!("creating first segment in the log");
+    let segments = Self::read_segments_from_disk(&directory, &config)?;
 
-      segments.push(Segment::new(
-        &directory,
-        config.initial_offset,
-        segment::Config {
-          max_index_bytes: config.max_index_bytes_per_segment,
-          max_store_bytes: config.max_store_bytes_per_segment,
-          initial_offset: 0,
-        },
-      )?)
+    let mut log = Self::with_segments(directory, config, segments, false)?;
+
+    let mut replayed_append = false;
+
+    if log.config.write_ahead_log {
+      let mut wal = WriteAheadLog::new(&log.directory)?;
+
+      if let Some(entry) = wal.read()? {
+        replayed_append = log.replay(entry)?;
+      }
+
+      wal.checkpoint()?;
+
+      log.wal = Some(wal);
     }
 
-    // Segments are ordered from oldest to newest and the newest segment is the active one.
-    let active_segment = segments.len() - 1;
+    let recovered_segments = if log.config.recover_on_open {
+      log.recover()?
+    } else {
+      Vec::new()
+    };
 
-    Ok(Self {
-      active_segment,
-      config,
-      directory,
-      segments,
-      lock: RwLock::new(false),
-    })
+    let disk_usage = log.disk_usage();
+
+    info!(
+      segments = log.segments.len(),
+      lowest_offset = log.lowest_offset(),
+      highest_offset = log.highest_offset(),
+      total_bytes = disk_usage.store_bytes + disk_usage.index_live_bytes,
+      reopened = !log.is_new(),
+      recovered = replayed_append || !recovered_segments.is_empty(),
+      replayed_append,
+      truncated_records = recovered_segments
+        .iter()
+        .map(|recovery| recovery
+          .previous_next_offset
+          .saturating_sub(recovery.next_offset))
+        .sum::<u64>(),
+      truncated_bytes = recovered_segments
+        .iter()
+        .map(|recovery| recovery.truncated_store_bytes)
+        .sum::<u64>(),
+      "log opened"
+    );
+
+    Ok(log)
   }
 
-  /// Appends a new record to the log to the active segment.
+  /// Creates a log that keeps every segment in memory.
   ///
-  /// If the segment reaches its max size after the new
-  /// record is appended, a new active segment is created.
-  pub fn append(&mut self, value: Vec<u8>) -> Result<u64> {
-    let _lock = self.lock.write().unwrap();
+  /// Nothing is written to disk, which makes it useful for tests.
+  pub fn new_in_memory(config: Config) -> Result<Self> {
+    info!("creating in memory log");
 
-    let segment = &mut self.segments[self.active_segment];
+    config.validate()?;
 
-    let new_record_offset = segment.append(value)?;
-
-    if segment.is_maxed() {
-      self.segments.push(Segment::new(
-        &self.directory,
-        new_record_offset + 1,
-        segment::Config {
-          max_index_bytes: 0,
-          max_store_bytes: 0,
-          initial_offset: 0,
-        },
-      )?);
+    Self::with_segments(String::new(), config, Vec::new(), true)
+  }
 
-      self.active_segment += 1;
-    }
+  /// Creates a log out of segments opened by the caller instead
+  /// of the ones found in a directory.
+  ///
+  /// segments must be ordered from oldest to newest and each one must
+  /// start where the previous one ends. The newest one becomes the
+  /// active segment. New segments are created next to the newest
+  /// segment files, or in memory if it only lives in memory.
+  pub fn from_segments(segments: Vec<Segment>, config: Config) -> Result<Self> {
+    config.validate()?;
 
-    Ok(new_record_offset)
-  }
+    check_contiguity(
+      &segments
+        .iter()
+        .map(|segment| segment.base_offset()..segment.next_offset())
+        .collect::<Vec<_>>(),
+    )?;
 
-  /// Reads the record stored at a given offset.
-  pub fn read(&self, offset: u64) -> Result<api::v1::Record> {
-    let _lock = self.lock.read().unwrap();
+    let directory = match segments.last() {
+      None => return Err(CommitLogError::NoSegments.into()),
+      Some(segment) => segment
+        .directory()
+        .map(|directory| directory.to_string_lossy().into_owned()),
+    };
 
-    // Try to find a segment that contains offset in its range.
-    let segment = self
-      .segments
-      .iter()
-      .find(|segment| segment.base_offset() <= offset && offset < segment.next_offset());
+    info!(segments = segments.len(), "creating log from segments");
 
-    match segment {
-      None => Err(CommitLogError::OffsetOutOfBounds(offset).into()),
-      Some(segment) => segment.read(offset),
+    match directory {
+      None => Self::with_segments(String::new(), config, segments, true),
+      Some(directory) => Self::with_segments(directory, config, segments, false),
     }
   }
 
-  /// Closes every segment in the log.
-  pub fn close(self) -> Result<()> {
-    // Take ownership of the mutex data since we are cleaning it up.
-    let _lock = self.lock.write().unwrap();
+  pub(crate) fn with_segments(
+    directory: String,
+    config: Config,
+    segments: Vec<Segment>,
+    in_memory: bool,
+  ) -> Result<Self> {
+    let mut log = Self {
+      created: segments.is_empty(),
+      last_record: None,
+      offset_allocator: Box::new(SequentialOffsets),
+      wal: None,
+      audit_chain_head: None,
+      rebuilding: false,
+      active_segment: 0,
+      config,
+      directory,
+      segments: segments.into_iter().map(share).collect(),
+      in_memory,
+      pre_rolled_segment: None,
+      subscribers: Mutex::new(Vec::new()),
+      last_append_at: Instant::now(),
+    };
 
-    for segment in self.segments.into_iter() {
-      segment.close()?;
-    }
+    // If the log is new and there are no segments on disk,
+    // we create the first one.
+    if log.segments.is_empty() {
+      info!("creating first segment in the log");
 
-    Ok(())
-  }
+      let segment = log.create_segment(log.config.initial_offset)?;
 
-  /// Deletes the log directory and then closes every segment in the log.
-  pub fn remove(self) -> Result<()> {
-    let directory = self.directory.clone();
+      log.segments.push(share(segment));
+    }
 
-    self.close()?;
+    // Segments are ordered from oldest to newest and the newest segment is the active one.
+    log.active_segment = log.segments.len() - 1;
 
-    // TODO: is this a waste?
-    // We are flushing the store and index to disk
-    // because of Store::close and Index::close
-    // and then deleting the folder containing the flushed data.
-    std::fs::remove_dir_all(directory)?;
+    if log.config.dedup_consecutive {
+      log.last_record = Self::read_last_record(&log.segments)?;
+    }
 
-    Ok(())
+    Ok(log)
   }
 
-  /// Returns the base offset of the first segment.
-  ///
-  /// The lowest offset will be used for consensus
-  /// in the replicated cluster.
-  pub fn lowest_offset(&self) -> u64 {
-    let _lock = self.lock.read().unwrap();
+  /// Returns the newest record stored in segments.
+  fn read_last_record(segments: &[SharedSegment]) -> Result<Option<api::v1::Record>> {
+    segments
+      .iter()
+      .rev()
+      .map(|segment| segment.read().unwrap())
+      .find(|segment| segment.base_offset() < segment.next_offset())
+      .map(|segment| segment.read(segment.next_offset() - 1))
+      .transpose()
+  }
 
-    self.segments.first().unwrap().base_offset()
+  /// Makes the log use allocator to decide the offset of each record.
+  pub fn with_offset_allocator(mut self, allocator: Box<dyn OffsetAllocator>) -> Self {
+    self.offset_allocator = allocator;
+    self
   }
 
-  /// Returns the next offset of the last segment.
+  /// Finishes the append recorded by entry if it was interrupted.
   ///
-  /// The highest offset will be used for consensus
-  /// in the replicated cluster.
-  pub fn highest_offset(&self) -> u64 {
-    let _lock = self.lock.read().unwrap();
+  /// Returns true if the append had to be finished.
+  fn replay(&mut self, entry: wal::Entry) -> Result<bool> {
+    let mut segment = match self
+      .segments
+      .iter()
+      .map(|segment| segment.write().unwrap())
+      .find(|segment| segment.base_offset() == entry.base_offset)
+    {
+      Some(segment) => segment,
+      None => {
+        warn!(
+          entry.base_offset,
+          "write-ahead log entry belongs to a segment that does not exist"
+        );
+        return Ok(false);
+      }
+    };
+
+    // The append was applied before the log was closed.
+    if entry.offset < segment.next_offset() {
+      return Ok(false);
+    }
+
+    if segment.store_contains(entry.position, &entry.bytes) {
+      info!(entry.offset, "writing index entry of interrupted append");
+
+      segment.complete_append(entry.offset, entry.position)?;
+    } else {
+      info!(entry.offset, "redoing interrupted append");
+
+      segment.append_encoded(entry.offset, &entry.bytes)?;
+    }
+
+    // The write-ahead log is emptied once the entry is replayed.
+    segment.sync()?;
 
-    self.segments.last().unwrap().next_offset()
+    Ok(true)
   }
 
-  /// Removes segments whose highest offset is lower than lowest.
+  /// Creates a segment starting at base_offset using the log config.
+  fn create_segment(&self, base_offset: u64) -> Result<Segment> {
+    if self.in_memory {
+      Segment::in_memory(base_offset, self.config.segment_config())
+    } else {
+      Segment::new(&self.directory, base_offset, self.config.segment_config())
+    }
+  }
+
+  /// Creates the segment that will follow the active segment.
   ///
-  /// It is called periodically to remove old segments whose
-  /// data has already been processed.
+  /// Its base offset is only known when the active segment is
+  /// maxed, so it is set when the segment becomes active.
+  fn create_pre_rolled_segment(&self) -> Result<Segment> {
+    info!("pre-rolling next segment");
+
+    if self.in_memory {
+      Segment::in_memory(0, self.config.segment_config())
+    } else {
+      Segment::pre_roll(&self.directory, self.config.segment_config())
+    }
+  }
+
+  /// Returns `CommitLogError::TooManySegments` if no more
+  /// segments may be created.
+  fn check_segment_limit(&self) -> Result<(), CommitLogError> {
+    match self.config.max_segments_hard_limit {
+      Some(limit) if self.segments.len() >= limit => Err(CommitLogError::TooManySegments(limit)),
+      _ => Ok(()),
+    }
+  }
+
+  /// Returns true when the active segment is filled past
+  /// the pre-roll threshold.
+  fn should_pre_roll(&self) -> bool {
+    match self.config.pre_roll_percent {
+      None => false,
+      Some(percent) => self.segments[self.active_segment]
+        .read()
+        .unwrap()
+        .is_filled_past(percent),
+    }
+  }
+
+  /// Appends a new record to the log to the active segment.
   ///
-  /// TODO: add diagram [removed, removed, removed, kept, kept]
-  pub fn truncate(&mut self, lowest: u64) -> Result<()> {
-    info!(lowest, "truncating segments");
+  /// If the segment reaches its max size after the new
+  /// record is appended, a new active segment is created.
+  pub fn append(&mut self, value: Vec<u8>) -> Result<u64> {
+    self.append_with_headers(value, HashMap::new())
+  }
+
+  /// Same as Log::append but the record also contains headers.
+  pub fn append_with_headers(&mut self, value: Vec<u8>, headers: Headers) -> Result<u64> {
+    self.append_record(api::v1::Record {
+      value,
+      headers,
+      ..Default::default()
+    })
+  }
+
+  /// Same as Log::append but every field of record, like its
+  /// headers or schema id, is stored. The offset of record
+  /// is ignored, the record gets the next offset.
+  pub fn append_record(&mut self, record: api::v1::Record) -> Result<u64> {
+    let offsets = self.append_records(vec![(None, record)])?;
+
+    Ok(offsets[0])
+  }
+
+  /// Same as Log::append but the record is added to a hash chain:
+  /// its hash covers the hash of the previous audited record, so
+  /// changing any audited record can be detected by Log::verify_chain.
+  ///
+  /// The chain is stored in the headers of the audited records.
+  pub fn append_audited(&mut self, value: Vec<u8>) -> Result<AppendReceipt> {
+    let prev_hash = match self.audit_chain_head {
+      Some(hash) => hash,
+      None => self.find_audit_chain_head()?,
+    };
+
+    let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+
+    let record_hash = audit_hash(&prev_hash, timestamp_ms, &value);
+
+    let headers = [
+      (AUDIT_PREV_HASH_HEADER, prev_hash.to_vec()),
+      (AUDIT_TIMESTAMP_HEADER, timestamp_ms.to_be_bytes().to_vec()),
+      (AUDIT_RECORD_HASH_HEADER, record_hash.to_vec()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_owned(), value))
+    .collect();
+
+    let offset = self.append_with_headers(value, headers)?;
+
+    self.audit_chain_head = Some(record_hash);
+
+    Ok(AppendReceipt {
+      offset,
+      timestamp_ms,
+      prev_hash,
+      record_hash,
+    })
+  }
+
+  /// Returns the hash of the newest audited record in the log,
+  /// zeroed if there is none.
+  ///
+  /// Records are read from the newest one until an audited one is found.
+  fn find_audit_chain_head(&self) -> Result<[u8; 32]> {
+    for offset in (self.lowest_offset()..self.highest_offset()).rev() {
+      let record = match self.read(offset) {
+        Ok(record) => record,
+        // Offsets may have gaps.
+        Err(e) if e.downcast_ref::<IndexError>().is_some() => continue,
+        Err(e) => return Err(e),
+      };
+
+      if let Some(hash) = record.headers.get(AUDIT_RECORD_HASH_HEADER) {
+        return hash
+          .as_slice()
+          .try_into()
+          .map_err(|_| ChainError::MalformedRecord { offset }.into());
+      }
+    }
+
+    Ok([0; 32])
+  }
+
+  /// Recomputes the hash of every audited record and checks that
+  /// each one points to the previous audited record.
+  ///
+  /// The first audited record in the log is trusted to point to the
+  /// right record, older records may have been truncated away.
+  /// Returns the first `ChainError` found.
+  pub fn verify_chain(&self) -> Result<()> {
+    let mut prev_hash: Option<[u8; 32]> = None;
+
+    for offset in self.lowest_offset()..self.highest_offset() {
+      let record = match self.read(offset) {
+        Ok(record) => record,
+        // Offsets may have gaps.
+        Err(e) if e.downcast_ref::<IndexError>().is_some() => continue,
+        Err(e) => return Err(e),
+      };
+
+      let header = |name: &str| record.headers.get(name).map(Vec::as_slice);
+
+      let record_hash = match header(AUDIT_RECORD_HASH_HEADER) {
+        None => continue,
+        Some(hash) => hash,
+      };
+
+      let (record_prev_hash, timestamp_ms) = match (
+        header(AUDIT_PREV_HASH_HEADER).and_then(|hash| <[u8; 32]>::try_from(hash).ok()),
+        header(AUDIT_TIMESTAMP_HEADER).and_then(|timestamp| <[u8; 8]>::try_from(timestamp).ok()),
+      ) {
+        (Some(hash), Some(timestamp)) => (hash, u64::from_be_bytes(timestamp)),
+        _ => return Err(ChainError::MalformedRecord { offset }.into()),
+      };
+
+      if prev_hash.is_some_and(|hash| hash != record_prev_hash) {
+        return Err(ChainError::BrokenLink { offset }.into());
+      }
+
+      let hash = audit_hash(&record_prev_hash, timestamp_ms, &record.value);
+
+      if record_hash != hash {
+        return Err(ChainError::HashMismatch { offset }.into());
+      }
+
+      prev_hash = Some(hash);
+    }
+
+    Ok(())
+  }
+
+  /// Appends every value to the log and returns the offsets
+  /// assigned to them, in the same order as the values.
+  ///
+  /// Like `Log::append`, a new active segment is created whenever
+  /// the active segment reaches its max size, so a batch may
+  /// end up spread across several segments.
+  pub fn append_batch(&mut self, values: Vec<Vec<u8>>) -> Result<Vec<u64>> {
+    self.append_records(
+      values
+        .into_iter()
+        .map(|value| {
+          (
+            None,
+            api::v1::Record {
+              value,
+              ..Default::default()
+            },
+          )
+        })
+        .collect(),
+    )
+  }
+
+  /// Appends each record to the log and returns
+  /// the offsets assigned to them.
+  ///
+  /// Records without an offset get the offset chosen by the offset allocator.
+  fn append_records(&mut self, records: Vec<(Option<u64>, api::v1::Record)>) -> Result<Vec<u64>> {
+    // Checked before anything is appended so a batch is never appended in part.
+    if let Some(max) = self.config.max_record_bytes {
+      if let Some((_, record)) = records
+        .iter()
+        .find(|(_, record)| record.value.len() as u64 > max)
+      {
+        return Err(
+          CommitLogError::RecordTooLarge {
+            size: record.value.len() as u64,
+            max,
+          }
+          .into(),
+        );
+      }
+    }
+
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+
+    let mut offsets = Vec::with_capacity(records.len());
+
+    for (offset, mut record) in records {
+      // Records copied at their own offset are never skipped.
+      if let (None, Some(last_record)) = (offset, &self.last_record) {
+        if last_record.value == record.value
+          && last_record.headers == record.headers
+          && last_record.schema_id == record.schema_id
+        {
+          offsets.push(last_record.offset);
+          continue;
+        }
+      }
+
+      let active_segment = Arc::clone(&self.segments[self.active_segment]);
+
+      let mut segment = active_segment.write().unwrap();
+
+      // The active segment was not rolled because of the segment limit.
+      if segment.is_maxed() {
+        self.check_segment_limit()?;
+      }
+
+      let subscribers = self.subscribers.get_mut().unwrap();
+
+      let offset =
+        offset.unwrap_or_else(|| self.offset_allocator.next_offset(segment.next_offset()));
+
+      if let Some(max_offset) = self.config.max_offset {
+        if offset >= max_offset {
+          return Err(CommitLogError::OffsetCeilingReached { offset, max_offset }.into());
+        }
+      }
+
+      record.offset = offset;
+
+      // Records copied from another log keep the time they were appended at.
+      if record.timestamp_ms == 0 {
+        record.timestamp_ms = now_ms;
+      }
+
+      let bytes = segment.encode(&record);
+
+      let new_record_offset = match &mut self.wal {
+        None => segment.append_encoded(offset, &bytes)?,
+        Some(wal) => {
+          let entry = wal::Entry {
+            base_offset: segment.base_offset(),
+            offset,
+            position: segment.store_size(),
+            bytes,
+          };
+
+          wal.write(&entry)?;
+
+          segment.append_encoded(offset, &entry.bytes)?;
+
+          // The entry is only needed until the append reaches the disk.
+          segment.sync()?;
+
+          wal.checkpoint()?;
+
+          offset
+        }
+      };
+
+      // Only keep the record if someone is going to use it.
+      if !subscribers.is_empty() || self.config.dedup_consecutive {
+        // Subscriptions that have been dropped are forgotten.
+        subscribers.retain(|subscriber| {
+          match subscriber.try_send((new_record_offset, record.clone())) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+              warn!(new_record_offset, "dropping subscriber that fell behind");
+              false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+          }
+        });
+
+        if self.config.dedup_consecutive {
+          self.last_record = Some(record);
+        }
+      }
+
+      if new_record_offset == segment.base_offset()
+        && segment.store_size() > self.config.max_store_bytes_per_segment
+      {
+        warn!(
+          offset = new_record_offset,
+          size = segment.store_size(),
+          max = self.config.max_store_bytes_per_segment,
+          "record is bigger than max_store_bytes_per_segment, it gets a segment of its own"
+        );
+      }
+
+      let is_maxed = segment.is_maxed();
+
+      drop(segment);
+
+      if is_maxed && self.check_segment_limit().is_ok() {
+        let segment = match self.pre_rolled_segment.take() {
+          Some(mut segment) => {
+            segment.rebase(new_record_offset + 1)?;
+            segment
+          }
+          None => self.create_segment(new_record_offset + 1)?,
+        };
+
+        self.segments.push(share(segment));
+
+        self.active_segment += 1;
+      } else if self.pre_rolled_segment.is_none() && !is_maxed && self.should_pre_roll() {
+        self.pre_rolled_segment = Some(self.create_pre_rolled_segment()?);
+      }
+
+      offsets.push(new_record_offset);
+    }
+
+    self.last_append_at = Instant::now();
+
+    Ok(offsets)
+  }
+
+  /// Makes a new segment the active one, even if the active
+  /// segment is not maxed.
+  ///
+  /// Nothing happens if the active segment is empty.
+  pub fn roll_active_segment(&mut self) -> Result<()> {
+    let active_segment = self.segments[self.active_segment].read().unwrap();
+
+    if active_segment.base_offset() == active_segment.next_offset() {
+      return Ok(());
+    }
+
+    let next_offset = active_segment.next_offset();
+
+    drop(active_segment);
+
+    self.check_segment_limit()?;
+
+    info!(next_offset, "rolling active segment");
+
+    let segment = match self.pre_rolled_segment.take() {
+      Some(mut segment) => {
+        segment.rebase(next_offset)?;
+        segment
+      }
+      None => self.create_segment(next_offset)?,
+    };
+
+    self.segments.push(share(segment));
+
+    self.active_segment += 1;
+
+    Ok(())
+  }
+
+  /// Rolls the active segment if no record has been appended
+  /// for longer than the idle roll timeout at now.
+  ///
+  /// Meant to be called periodically. Returns true if the
+  /// active segment was rolled.
+  pub fn roll_if_idle(&mut self, now: Instant) -> Result<bool> {
+    let timeout = match self.config.idle_roll_timeout {
+      None => return Ok(false),
+      Some(timeout) => timeout,
+    };
+
+    let active_segment = self.segments[self.active_segment].read().unwrap();
+
+    if active_segment.base_offset() == active_segment.next_offset()
+      || now.saturating_duration_since(self.last_append_at) < timeout
+    {
+      return Ok(false);
+    }
+
+    drop(active_segment);
+
+    self.roll_active_segment()?;
+
+    Ok(true)
+  }
+
+  /// Moves the segments whose offsets are all lower than up_to_offset
+  /// to other, the records keep their offsets.
+  ///
+  /// Meant to move old records from a hot log to a cold one, other
+  /// must not contain records whose offsets are higher than the moved ones.
+  /// The active segment is never moved.
+  ///
+  /// Returns how many segments were moved. If a record cannot be appended
+  /// to other, the segments copied before it are still removed and the
+  /// rest are kept, even though other may have some of their records.
+  pub fn drain_to(&mut self, other: &mut Log, up_to_offset: u64) -> Result<usize> {
+    info!(up_to_offset, "draining segments to another log");
+
+    let drained = self
+      .segments
+      .partition_point(|segment| segment.read().unwrap().next_offset() <= up_to_offset)
+      .min(self.active_segment);
+
+    // Segments are only removed once every record of them has been copied.
+    let mut copied = 0;
+
+    let result = self.segments[..drained].iter().try_for_each(|segment| {
+      let segment = segment.read().unwrap();
+
+      for offset in segment.base_offset()..segment.next_offset() {
+        let record = match segment.read(offset) {
+          Ok(record) => record,
+          // Offsets may have gaps.
+          Err(e) if e.downcast_ref::<IndexError>().is_some() => continue,
+          Err(e) => return Err(e),
+        };
+
+        other.append_copy(record)?;
+      }
+
+      copied += 1;
+
+      Ok(())
+    });
+
+    for segment in self.segments.drain(0..copied) {
+      remove_segment(segment)?;
+    }
+
+    self.active_segment = self.segments.len() - 1;
+
+    result.map(|_| drained)
+  }
+
+  /// Appends record at its own offset.
+  fn append_copy(&mut self, record: api::v1::Record) -> Result<()> {
+    let mut active_segment = self.segments[self.active_segment].write().unwrap();
+
+    // Avoid leaving a gap before the first copied record.
+    if active_segment.base_offset() == active_segment.next_offset()
+      && active_segment.base_offset() < record.offset
+    {
+      active_segment.rebase(record.offset)?;
+    }
+
+    drop(active_segment);
+
+    self.append_records(vec![(Some(record.offset), record)])?;
+
+    Ok(())
+  }
+
+  /// Returns a subscription that receives every record appended
+  /// to the log from now on, in offset order.
+  ///
+  /// Records are sent while the record is being appended, so no record
+  /// appended after this call is missed.
+  ///
+  /// At most `SUBSCRIPTION_CAPACITY` records are buffered for the
+  /// subscription. Appends do not wait for a subscriber that falls
+  /// that far behind, it is dropped instead and the subscription ends
+  /// after the buffered records, so one slow subscriber can neither
+  /// hold up appends nor make the log buffer records without bound.
+  pub fn subscribe(&self) -> Subscription {
+    let (sender, receiver) = mpsc::sync_channel(SUBSCRIPTION_CAPACITY);
+
+    self.subscribers.lock().unwrap().push(sender);
+
+    Subscription { receiver }
+  }
+
+  /// Reads the record stored at a given offset.
+  pub fn read(&self, offset: u64) -> Result<api::v1::Record> {
+    match self.find_segment(offset) {
+      None => Err(CommitLogError::OffsetOutOfBounds(offset).into()),
+      Some(segment) => segment.read().unwrap().read(offset),
+    }
+  }
+
+  /// Returns the segment that contains offset, None if there is none.
+  ///
+  /// The segment can be read after the lock around the log is
+  /// released, so a slow read does not hold up appends. If the segment
+  /// is removed from the log in the meantime, its files are deleted
+  /// once the returned segment is dropped.
+  pub fn segment_for(&self, offset: u64) -> Option<SharedSegment> {
+    self.find_segment(offset).cloned()
+  }
+
+  /// Returns every segment except the active one
+  /// whose store entries are not compressed.
+  ///
+  /// The segments can be compressed with Segment::write_compressed
+  /// after the lock around the log is released, and swapped in with
+  /// Log::replace_with_compressed.
+  pub fn uncompressed_sealed_segments(&self) -> Vec<SharedSegment> {
+    if self.in_memory {
+      return Vec::new();
+    }
+
+    self.segments[..self.active_segment]
+      .iter()
+      .filter(|segment| !segment.read().unwrap().is_compressed())
+      .cloned()
+      .collect()
+  }
+
+  /// Replaces the files of segment with the compressed copy written by
+  /// Segment::write_compressed, see Segment::replace_with_compressed.
+  ///
+  /// Returns false, and deletes the copy, if segment is not part of the
+  /// log anymore or if someone else is still using it, like a slow read,
+  /// instead of waiting for them. If replacing the files fails, the
+  /// segment is opened again from disk, uncompressed or compressed,
+  /// and the error is returned.
+  pub fn replace_with_compressed(&mut self, segment: SharedSegment) -> Result<bool> {
+    let index = self
+      .segments
+      .iter()
+      .position(|candidate| Arc::ptr_eq(candidate, &segment));
+
+    let index = match index {
+      // The log and the caller have the only handles.
+      Some(index) if Arc::strong_count(&segment) == 2 => index,
+      _ => {
+        segment.read().unwrap().discard_compressed()?;
+        return Ok(false);
+      }
+    };
+
+    drop(segment);
+
+    let segment = unshare(self.segments.remove(index));
+
+    let base_offset = segment.base_offset();
+
+    match segment.replace_with_compressed() {
+      Ok(segment) => {
+        self.segments.insert(index, share(segment));
+
+        Ok(true)
+      }
+      Err(err) => {
+        warn!(base_offset, error = %err, "replacing segment with its compressed copy failed");
+
+        let segment = Segment::new(&self.directory, base_offset, self.config.segment_config())?;
+
+        self.segments.insert(index, share(segment));
+
+        Err(err)
+      }
+    }
+  }
+
+  /// Reads the record stored at position in the store of the segment
+  /// whose base offset is segment_base_offset, without looking up
+  /// the position in the index.
+  ///
+  /// Meant for tools that already know where records are stored.
+  pub fn read_at_position(
+    &self,
+    segment_base_offset: u64,
+    position: u64,
+  ) -> Result<api::v1::Record> {
+    let segment = self
+      .segments
+      .binary_search_by_key(&segment_base_offset, |segment| {
+        segment.read().unwrap().base_offset()
+      })
+      .map(|i| &self.segments[i])
+      .map_err(|_| CommitLogError::SegmentNotFound(segment_base_offset))?;
+
+    segment.read().unwrap().read_record_at(position)
+  }
+
+  /// Same as Log::read but the store entry is read into a buffer
+  /// taken from pool instead of a newly allocated one.
+  ///
+  /// Useful for consumers that read many records in a loop. Only the
+  /// allocation of the store entry is saved, decoding the record
+  /// still allocates its fields.
+  pub fn read_pooled(&self, offset: u64, pool: &BufferPool) -> Result<api::v1::Record> {
+    match self.find_segment(offset) {
+      None => Err(CommitLogError::OffsetOutOfBounds(offset).into()),
+      Some(segment) => segment
+        .read()
+        .unwrap()
+        .read_into(offset, &mut pool.checkout()),
+    }
+  }
+
+  /// Returns the segment that contains offset in its range.
+  ///
+  /// Segments are ordered by base offset, so a binary search is
+  /// used instead of looking at every segment.
+  fn find_segment(&self, offset: u64) -> Option<&SharedSegment> {
+    self
+      .find_segment_index(offset)
+      .map(|index| &self.segments[index])
+  }
+
+  /// Same as Log::find_segment but the position
+  /// of the segment in `segments` is returned.
+  fn find_segment_index(&self, offset: u64) -> Option<usize> {
+    // Number of segments that start at or before offset,
+    // the last one of them is the only one that may contain it.
+    let candidates = self
+      .segments
+      .partition_point(|segment| segment.read().unwrap().base_offset() <= offset);
+
+    let index = candidates.checked_sub(1)?;
+
+    if offset < self.segments[index].read().unwrap().next_offset() {
+      Some(index)
+    } else {
+      None
+    }
+  }
+
+  /// Returns a reader that is faster than Log::read
+  /// when offsets are read in order.
+  pub fn sequential_reader(&self) -> SequentialReader<'_> {
+    SequentialReader {
+      log: self,
+      segment: 0,
+      searches: 0,
+    }
+  }
+
+  /// Returns a reader that reads the records from start_offset to the
+  /// highest offset as bytes, each record encoded the way it is
+  /// stored and preceded by its length as an 8 byte big endian number.
+  ///
+  /// The reader returns end of file once the highest offset is
+  /// reached, it does not wait for new records.
+  pub fn reader(&self, start_offset: u64) -> LogReader<'_> {
+    LogReader {
+      reader: self.sequential_reader(),
+      next_offset: start_offset.max(self.lowest_offset()),
+      entry: Vec::new(),
+      entry_read: 0,
+    }
+  }
+
+  /// Returns up to max records whose offset is greater than offset,
+  /// in offset order.
+  ///
+  /// Meant for clients that sync incrementally: they pass the offset of
+  /// the last record they have seen and are caught up with the log
+  /// when fewer than max records are returned.
+  pub fn since(&self, offset: u64, max: usize) -> Result<Vec<api::v1::Record>> {
+    // Records that have been truncated away are skipped.
+    let start = offset.saturating_add(1).max(self.lowest_offset());
+    let end = self.highest_offset().min(start.saturating_add(max as u64));
+
+    (start..end).map(|offset| self.read(offset)).collect()
+  }
+
+  /// Returns the records in range, stopping once max_records
+  /// records have been read or before the values read add up
+  /// to more than max_bytes, whichever happens first.
+  ///
+  /// The first record is always returned, even if its value is
+  /// bigger than max_bytes, so reads always make progress.
+  /// Records that have been truncated away are skipped.
+  ///
+  /// The read also stops at the first offset without a record, the
+  /// records before it are returned and next_offset is the missing
+  /// offset. Only the segment of the first record is searched for,
+  /// the next records are read walking forward across segments.
+  pub fn read_range(
+    &self,
+    range: Range<u64>,
+    max_records: usize,
+    max_bytes: usize,
+  ) -> Result<RangeRead> {
+    let mut next_offset = range.start.max(self.lowest_offset());
+    let end = range.end.min(self.highest_offset());
+
+    let mut reader = self.sequential_reader();
+
+    let mut records = Vec::new();
+    let mut bytes = 0;
+
+    while next_offset < end && records.len() < max_records {
+      let record = match reader.read(next_offset) {
+        Ok(record) => record,
+        // A gap inside a segment or between two segments.
+        Err(e)
+          if e.downcast_ref::<IndexError>().is_some()
+            || e.downcast_ref::<CommitLogError>()
+              == Some(&CommitLogError::OffsetOutOfBounds(next_offset)) =>
+        {
+          break
+        }
+        Err(e) => return Err(e),
+      };
+
+      if !records.is_empty() && bytes + record.value.len() > max_bytes {
+        break;
+      }
+
+      bytes += record.value.len();
+      records.push(record);
+      next_offset += 1;
+    }
+
+    Ok(RangeRead {
+      records,
+      next_offset,
+    })
+  }
+
+  /// Reads the record stored at a given offset and also returns
+  /// whether the record is the last one in the log.
+  ///
+  /// Consumers can use it to decide whether they should wait
+  /// for new records instead of asking for the next offset right away.
+  pub fn read_with_tail_info(&self, offset: u64) -> Result<(api::v1::Record, bool)> {
+    let record = self.read(offset)?;
+
+    let is_at_tail = offset + 1 >= self.highest_offset();
+
+    Ok((record, is_at_tail))
+  }
+
+  /// Closes every segment in the log.
+  pub fn close(self) -> Result<()> {
+    for segment in self.segments.into_iter() {
+      close_segment(segment)?;
+    }
+
+    // The pre-rolled segment is empty, there is nothing to keep.
+    if let Some(segment) = self.pre_rolled_segment {
+      segment.remove()?;
+    }
+
+    Ok(())
+  }
+
+  /// Opens the segments stored in the log directory again, so changes
+  /// made to the directory while the log was open, like restoring
+  /// a backup, become visible.
+  ///
+  /// The config and the subscriptions are kept. If opening the
+  /// segments fails, the log keeps using the segments it had open.
+  pub fn reopen(&mut self) -> Result<()> {
+    info!(directory = %self.directory, "reopening log");
+
+    if self.in_memory {
+      return Err(CommitLogError::CannotReopenInMemoryLog.into());
+    }
+
+    // The pre-rolled segment is empty, there is nothing to keep.
+    if let Some(segment) = self.pre_rolled_segment.take() {
+      segment.remove()?;
+    }
+
+    self.trim_segments()?;
+
+    self.replace_segments()
+  }
+
+  /// Trims every open segment, see Segment::trim, so the
+  /// segment files can be opened again.
+  ///
+  /// Untrims the segments if trimming one of them fails.
+  fn trim_segments(&self) -> Result<()> {
+    let result = self
+      .segments
+      .iter()
+      .try_for_each(|segment| segment.read().unwrap().trim());
+
+    if result.is_err() {
+      self.untrim_segments()?;
+    }
+
+    result
+  }
+
+  fn untrim_segments(&self) -> Result<()> {
+    self
+      .segments
+      .iter()
+      .try_for_each(|segment| segment.read().unwrap().untrim())
+  }
+
+  /// Replaces the trimmed open segments with the segments
+  /// stored in the log directory.
+  ///
+  /// The segments are only replaced once every one of them was
+  /// opened. Otherwise the open segments are untrimmed and kept.
+  fn replace_segments(&mut self) -> Result<()> {
+    let mut segments = match Self::read_segments_from_disk(&self.directory, &self.config) {
+      Ok(segments) => segments,
+      Err(err) => {
+        self.untrim_segments()?;
+        return Err(err);
+      }
+    };
+
+    // Someone may have emptied the directory.
+    if segments.is_empty() {
+      match self.create_segment(self.config.initial_offset) {
+        Ok(segment) => segments.push(segment),
+        Err(err) => {
+          self.untrim_segments()?;
+          return Err(err);
+        }
+      }
+    }
+
+    // The new segments own the files now, the old segments are
+    // dropped instead of closed because closing them would
+    // truncate the index files under the new segments.
+    self.segments = segments.into_iter().map(share).collect();
+
+    self.active_segment = self.segments.len() - 1;
+
+    Ok(())
+  }
+
+  /// Moves the log directory to new_directory and opens
+  /// the segments from there.
+  ///
+  /// The directory is renamed when both paths are in the same file
+  /// system, otherwise it is copied and the copy is only made visible
+  /// at new_directory once every file has been copied and verified.
+  /// The old directory is deleted last, so an interrupted relocation
+  /// leaves at least one of the directories complete.
+  ///
+  /// If opening the segments fails, the directory is moved back
+  /// and the log keeps using the segments it had open.
+  pub fn relocate(&mut self, new_directory: &str) -> Result<()> {
+    info!(from = %self.directory, to = new_directory, "relocating log");
+
+    if self.in_memory {
+      return Err(CommitLogError::CannotRelocateInMemoryLog.into());
+    }
+
+    if Path::new(new_directory).exists() && std::fs::read_dir(new_directory)?.next().is_some() {
+      return Err(CommitLogError::RelocationTargetNotEmpty(new_directory.to_owned()).into());
+    }
+
+    // The pre-rolled segment is empty, there is nothing to keep.
+    if let Some(segment) = self.pre_rolled_segment.take() {
+      segment.remove()?;
+    }
+
+    self.trim_segments()?;
+
+    if let Err(err) = move_directory(Path::new(&self.directory), Path::new(new_directory)) {
+      self.untrim_segments()?;
+      return Err(err);
+    }
+
+    let old_directory = std::mem::replace(&mut self.directory, new_directory.to_owned());
+
+    if let Err(err) = self.replace_segments() {
+      // Put the files back where the open segments expect them.
+      move_directory(Path::new(&self.directory), Path::new(&old_directory))?;
+      self.directory = old_directory;
+      return Err(err);
+    }
+
+    // The write-ahead log is empty between appends,
+    // it is opened again in the new directory.
+    if self.config.write_ahead_log {
+      self.wal = Some(WriteAheadLog::new(&self.directory)?);
+    }
+
+    Ok(())
+  }
+
+  /// Compresses every segment except the active one, see
+  /// Segment::write_compressed, and returns how many were compressed.
+  ///
+  /// Appends only pay for compression once their segment is sealed.
+  /// Holds the log for the whole compression, a maintenance task
+  /// should use Log::uncompressed_sealed_segments and
+  /// Log::replace_with_compressed instead, see `LogServer::run_maintenance`.
+  pub fn compress_sealed_segments(&mut self) -> Result<usize> {
+    let mut compressed = 0;
+
+    for segment in self.uncompressed_sealed_segments() {
+      let written = segment.read().unwrap().write_compressed()?;
+
+      if written && self.replace_with_compressed(segment)? {
+        compressed += 1;
+      }
+    }
+
+    info!(compressed, "compressed sealed segments");
+
+    Ok(compressed)
+  }
+
+  /// Deletes the log directory and then closes every segment in the log.
+  pub fn remove(self) -> Result<()> {
+    let directory = self.directory.clone();
+    let in_memory = self.in_memory;
+
+    self.close()?;
+
+    if in_memory {
+      return Ok(());
+    }
+
+    // TODO: is this a waste?
+    // We are flushing the store and index to disk
+    // because of Store::close and Index::close
+    // and then deleting the folder containing the flushed data.
+    std::fs::remove_dir_all(directory)?;
+
+    Ok(())
+  }
+
+  /// Returns the base offset and the digest of every segment,
+  /// see Segment::digest, ordered from oldest to newest.
+  ///
+  /// Two copies of a log can compare digests to find
+  /// the segments that differ.
+  pub fn segment_digests(&self) -> Result<Vec<(u64, [u8; 32])>> {
+    self
+      .segments
+      .iter()
+      .map(|segment| {
+        let segment = segment.read().unwrap();
+
+        Ok((segment.base_offset(), segment.digest()?))
+      })
+      .collect()
+  }
+
+  /// Checks that each segment starts where the previous one ends.
+  ///
+  /// Only the segment offsets are looked at, no record is read,
+  /// so it is cheap enough to run before trusting a log.
+  /// Returns the first gap found.
+  pub fn verify_contiguity(&self) -> Result<(), ContiguityError> {
+    check_contiguity(
+      &self
+        .segments
+        .iter()
+        .map(|segment| {
+          let segment = segment.read().unwrap();
+
+          segment.base_offset()..segment.next_offset()
+        })
+        .collect::<Vec<_>>(),
+    )
+  }
+
+  /// Makes every segment consistent after a crash, see
+  /// `Segment::recover`, and returns what was changed in the
+  /// segments that were not consistent.
+  ///
+  /// A segment that loses records leaves a gap before the next
+  /// segment, which Log::verify_contiguity reports.
+  pub fn recover(&mut self) -> Result<Vec<SegmentRecovery>> {
+    let mut recovered = Vec::new();
+
+    for segment in self.segments.iter() {
+      let recovery = segment.write().unwrap().recover()?;
+
+      if !recovery.is_clean() {
+        warn!(?recovery, "recovered segment");
+        recovered.push(recovery);
+      }
+    }
+
+    self.active_segment = self.segments.len() - 1;
+    self.audit_chain_head = None;
+
+    if self.config.dedup_consecutive {
+      self.last_record = Self::read_last_record(&self.segments)?;
+    }
+
+    Ok(recovered)
+  }
+
+  /// Returns the offset, the store position and the record of every
+  /// record in the log, read by scanning the stores instead of using
+  /// the indexes, so positions can be compared with what the indexes say.
+  ///
+  /// Positions are relative to the store of the segment that contains
+  /// the record. A segment is no longer scanned after an entry of it
+  /// cannot be read.
+  ///
+  /// The records of a segment are read at once when the iterator
+  /// reaches the segment, so its lock is not held between items.
+  pub fn records_with_positions(
+    &self,
+  ) -> impl Iterator<Item = Result<(u64, u64, api::v1::Record)>> + '_ {
+    self.segments.iter().flat_map(|segment| {
+      segment
+        .read()
+        .unwrap()
+        .records_with_positions()
+        .map(|result| result.map(|(position, record)| (record.offset, position, record)))
+        .collect::<Vec<_>>()
+    })
+  }
+
+  /// Checks that the store and the index of every segment agree,
+  /// see Segment::validate, from the oldest segment to the newest.
+  ///
+  /// Every record is read, so on big logs this takes a while.
+  /// on_progress is called after each segment is checked and the
+  /// scan stops early if it returns ControlFlow::Break.
+  pub fn verify<F>(&self, mut on_progress: F) -> Result<VerifyOutcome, CommitLogError>
+  where
+    F: FnMut(VerifyProgress) -> ControlFlow<()>,
+  {
+    let mut progress = VerifyProgress {
+      segment: 0,
+      records_checked: 0,
+      bytes_checked: 0,
+      bytes_total: self
+        .segments
+        .iter()
+        .map(|segment| segment.read().unwrap().store_size())
+        .sum(),
+    };
+
+    for segment in self.segments.iter() {
+      let segment = segment.read().unwrap();
+
+      let records = segment
+        .validate()
+        .map_err(|source| CommitLogError::InvalidSegment {
+          base_offset: segment.base_offset(),
+          source,
+        })?;
+
+      progress.segment = segment.base_offset();
+      progress.records_checked += records;
+      progress.bytes_checked += segment.store_size();
+
+      if on_progress(progress).is_break() {
+        info!(segment = progress.segment, "log verification canceled");
+        return Ok(VerifyOutcome::Canceled {
+          segment: progress.segment,
+        });
+      }
+    }
+
+    Ok(VerifyOutcome::Completed)
+  }
+
+  /// Lets Log::append_to_segment be used, for tools that
+  /// restore segments in parallel or out of order.
+  ///
+  /// Meant for logs that are not being served.
+  pub fn begin_rebuild(&mut self) {
+    info!("rebuilding log");
+
+    self.rebuilding = true;
+  }
+
+  /// Appends record, at its own offset, to the segment whose base offset
+  /// is base_offset instead of to the active segment. The segment is
+  /// created if the log does not have it.
+  ///
+  /// The offset of record must be the next offset of the segment and must
+  /// be lower than the base offset of the following segment.
+  /// Returns `CommitLogError::NotRebuilding` unless Log::begin_rebuild was called.
+  pub fn append_to_segment(&mut self, base_offset: u64, record: api::v1::Record) -> Result<()> {
+    if !self.rebuilding {
+      return Err(CommitLogError::NotRebuilding.into());
+    }
+
+    let index = match self.segments.binary_search_by_key(&base_offset, |segment| {
+      segment.read().unwrap().base_offset()
+    }) {
+      Ok(index) => index,
+      Err(index) => {
+        let segment = self.create_segment(base_offset)?;
+
+        self.segments.insert(index, share(segment));
+
+        // The newest segment is still the active one.
+        self.active_segment = self.segments.len() - 1;
+
+        index
+      }
+    };
+
+    let next_base_offset = self
+      .segments
+      .get(index + 1)
+      .map(|segment| segment.read().unwrap().base_offset());
+
+    let mut segment = self.segments[index].write().unwrap();
+
+    if record.offset != segment.next_offset() {
+      return Err(
+        CommitLogError::UnexpectedRebuildOffset {
+          base_offset,
+          expected: segment.next_offset(),
+          found: record.offset,
+        }
+        .into(),
+      );
+    }
+
+    if next_base_offset.is_some_and(|next_base_offset| record.offset >= next_base_offset) {
+      return Err(
+        CommitLogError::OffsetOutsideSegment {
+          base_offset,
+          offset: record.offset,
+        }
+        .into(),
+      );
+    }
+
+    let bytes = segment.encode(&record);
+
+    segment.append_encoded(record.offset, &bytes)?;
+
+    Ok(())
+  }
+
+  /// Leaves the mode started by Log::begin_rebuild once
+  /// every segment starts where the previous one ends.
+  pub fn end_rebuild(&mut self) -> Result<(), ContiguityError> {
+    self.verify_contiguity()?;
+
+    self.rebuilding = false;
+
+    info!("log rebuilt");
+
+    Ok(())
+  }
+
+  /// Returns true when the log was created empty instead of
+  /// being recovered from segments stored by a prior log.
+  ///
+  /// Callers can use it to decide whether the log needs to be bootstrapped.
+  pub fn is_new(&self) -> bool {
+    self.created
+  }
+
+  /// Returns the state of the active segment, useful to test
+  /// when appended records reach the files.
+  #[cfg(any(test, feature = "test-util"))]
+  pub fn active_segment_debug(&self) -> ActiveSegmentDebug {
+    let segment = self.segments[self.active_segment].read().unwrap();
+
+    ActiveSegmentDebug {
+      next_offset: segment.next_offset(),
+      buffered_bytes: segment.buffered_bytes(),
+    }
+  }
+
+  /// Returns the base offset of the first segment.
+  ///
+  /// The lowest offset will be used for consensus
+  /// in the replicated cluster.
+  pub fn lowest_offset(&self) -> u64 {
+    self.segments.first().unwrap().read().unwrap().base_offset()
+  }
+
+  /// Returns the next offset of the last segment.
+  ///
+  /// The highest offset will be used for consensus
+  /// in the replicated cluster.
+  pub fn highest_offset(&self) -> u64 {
+    self.segments.last().unwrap().read().unwrap().next_offset()
+  }
+
+  /// Returns how many bytes can still be written to the active
+  /// segment before a new segment is created.
+  ///
+  /// Producers can use it to align their batches to segment boundaries.
+  pub fn active_segment_remaining_bytes(&self) -> u64 {
+    self.segments[self.active_segment]
+      .read()
+      .unwrap()
+      .remaining_bytes()
+  }
+
+  /// Returns how many bytes the segments use, including the
+  /// pre-rolled segment.
+  ///
+  /// In memory logs report the memory used by their segments.
+  pub fn disk_usage(&self) -> DiskUsage {
+    let add = |usage: DiskUsage, segment: &Segment| DiskUsage {
+      store_bytes: usage.store_bytes + segment.store_size(),
+      index_allocated_bytes: usage.index_allocated_bytes + segment.index_allocated_size(),
+      index_live_bytes: usage.index_live_bytes + segment.index_size(),
+    };
+
+    let usage = self
+      .segments
+      .iter()
+      .fold(DiskUsage::default(), |usage, segment| {
+        add(usage, &segment.read().unwrap())
+      });
+
+    self.pre_rolled_segment.iter().fold(usage, add)
+  }
+
+  /// Removes segments whose highest offset is lower than lowest.
+  ///
+  /// It is called periodically to remove old segments whose
+  /// data has already been processed.
+  ///
+  /// The active segment is never removed. If it contains records
+  /// and all of them would be removed,
+  /// `CommitLogError::CannotTruncateActiveSegment` is returned
+  /// and nothing is removed.
+  ///
+  /// TODO: add diagram [removed, removed, removed, kept, kept]
+  pub fn truncate(&mut self, lowest: u64) -> Result<()> {
+    info!(lowest, "truncating segments");
+
+    let active_segment = self.segments[self.active_segment].read().unwrap();
+
+    if active_segment.base_offset() < active_segment.next_offset()
+      && active_segment.next_offset() <= lowest + 1
+    {
+      return Err(CommitLogError::CannotTruncateActiveSegment(lowest).into());
+    }
+
+    drop(active_segment);
+
+    let mut end_index = 0;
+
+    // Find index of the last segment that does not pass the threshold.
+    for (i, segment) in self.segments.iter().enumerate() {
+      if segment.read().unwrap().next_offset() <= lowest + 1 {
+        end_index = i;
+      }
+    }
+
+    // Segments that come before the active one are the only ones
+    // that may be removed.
+    let end_index = end_index.min(self.active_segment);
+
+    // TODO: does drain change element order?
+    for segment in self.segments.drain(0..end_index) {
+      remove_segment(segment)?;
+    }
+
+    self.active_segment = self.segments.len() - 1;
+
+    Ok(())
+  }
+
+  /// Removes the segments whose newest record was appended
+  /// more than max_age ago, returning how many were removed.
+  ///
+  /// Segments are removed from the oldest one until a segment that has
+  /// not expired is found, so no gap is left between segments. Segments
+  /// whose newest record has no timestamp are never removed. The active
+  /// segment is never removed.
+  pub fn enforce_retention(&mut self, max_age: Duration) -> Result<usize> {
+    let cutoff_ms = SystemTime::now()
+      .duration_since(UNIX_EPOCH)?
+      .saturating_sub(max_age)
+      .as_millis() as u64;
+
+    let mut expired = 0;
+
+    for segment in &self.segments[..self.active_segment] {
+      let segment = segment.read().unwrap();
+
+      if segment.base_offset() == segment.next_offset() {
+        break;
+      }
+
+      let newest = segment.read(segment.next_offset() - 1)?;
+
+      if newest.timestamp_ms == 0 || newest.timestamp_ms >= cutoff_ms {
+        break;
+      }
+
+      expired += 1;
+    }
+
+    info!(expired, cutoff_ms, "enforcing retention");
+
+    for segment in self.segments.drain(..expired) {
+      remove_segment(segment)?;
+    }
+
+    self.active_segment -= expired;
+
+    Ok(expired)
+  }
+
+  /// Removes every record except the latest count records.
+  ///
+  /// Segments that only contain older records are removed and the
+  /// oldest kept segment is rewritten without the older records.
+  /// The active segment is never removed, so new records keep
+  /// being appended after the kept ones.
+  pub fn keep_latest(&mut self, count: u64) -> Result<()> {
+    let cutoff = self.highest_offset().saturating_sub(count);
+
+    info!(count, cutoff, "keeping latest records");
+
+    // Number of segments whose records are all older than cutoff.
+    let removed = self
+      .segments
+      .partition_point(|segment| segment.read().unwrap().next_offset() <= cutoff)
+      .min(self.segments.len() - 1);
+
+    for segment in self.segments.drain(0..removed) {
+      remove_segment(segment)?;
+    }
+
+    let head = self.segments[0].read().unwrap();
+
+    if head.base_offset() < cutoff {
+      let mut rewritten = self.create_segment(cutoff)?;
+
+      // Records are copied whole and keep their offsets.
+      let copied = (cutoff..head.next_offset()).try_for_each(|offset| {
+        let record = match head.read(offset) {
+          Ok(record) => record,
+          // Offsets may have gaps.
+          Err(e) if e.downcast_ref::<IndexError>().is_some() => return Ok(()),
+          Err(e) => return Err(e),
+        };
+
+        let bytes = rewritten.encode(&record);
+
+        rewritten.append_encoded(record.offset, &bytes)?;
+
+        Ok(())
+      });
+
+      drop(head);
+
+      if let Err(e) = copied {
+        // The log still has the original segment.
+        rewritten.remove()?;
+
+        return Err(e);
+      }
+
+      remove_segment(std::mem::replace(&mut self.segments[0], share(rewritten)))?;
+    }
+
+    self.active_segment = self.segments.len() - 1;
+
+    Ok(())
+  }
+
+  /// Creates a new segment, appends it to the list of segments
+  /// and makes it the active segment.
+  pub fn new_segment(&mut self, offset: u64) -> Result<()> {
+    info!("creating new segment at offset {}", offset);
+
+    self.check_segment_limit()?;
+
+    let segment = self.create_segment(self.config.initial_offset + offset)?;
+
+    self.segments.push(share(segment));
+    self.active_segment = self.segments.len() - 1;
+
+    Ok(())
+  }
+}
+
+/// Returns the first gap between the offsets of segments,
+/// which are ordered from oldest to newest.
+fn check_contiguity(spans: &[Range<u64>]) -> Result<(), ContiguityError> {
+  for pair in spans.windows(2) {
+    let (previous, span) = (&pair[0], &pair[1]);
+
+    if previous.end != span.start {
+      return Err(ContiguityError::Gap {
+        expected: previous.end,
+        found: span.start,
+      });
+    }
+  }
+
+  Ok(())
+}
+
+fn share(segment: Segment) -> SharedSegment {
+  Arc::new(RwLock::new(segment))
+}
+
+/// Takes segment back from the readers that got it from Log::segment_for.
+///
+/// Readers only keep a segment while they read from it,
+/// so this waits for the reads in progress to finish.
+fn unshare(mut segment: SharedSegment) -> Segment {
+  loop {
+    match Arc::try_unwrap(segment) {
+      Ok(segment) => return segment.into_inner().unwrap(),
+      Err(shared) => {
+        // Waits for the readers that hold the lock.
+        drop(shared.write().unwrap());
+
+        std::thread::yield_now();
+
+        segment = shared;
+      }
+    }
+  }
+}
+
+fn close_segment(segment: SharedSegment) -> Result<()> {
+  unshare(segment).close()
+}
+
+/// Deletes the files of segment.
+///
+/// A reader that is still using the segment can keep reading from
+/// the deleted files, they are closed once the reader drops it.
+fn remove_segment(segment: SharedSegment) -> Result<()> {
+  match Arc::try_unwrap(segment) {
+    Ok(segment) => segment.into_inner().unwrap().remove(),
+    Err(segment) => segment.read().unwrap().unlink(),
+  }
+}
+
+/// Moves the directory from to the empty or missing directory to.
+fn move_directory(from: &Path, to: &Path) -> Result<()> {
+  if let Some(parent) = to.parent() {
+    std::fs::create_dir_all(parent)?;
+  }
+
+  match std::fs::rename(from, to) {
+    Err(error) if error.kind() == io::ErrorKind::CrossesDevices => copy_then_rename(from, to),
+    result => Ok(result?),
+  }
+}
+
+/// Moves a directory to another file system.
+///
+/// The files are copied to a staging directory next to to, which
+/// is renamed once every file has been copied, so to never
+/// contains a partial copy. from is deleted at the end.
+fn copy_then_rename(from: &Path, to: &Path) -> Result<()> {
+  let mut staging = to.as_os_str().to_owned();
+  staging.push(".relocating");
+  let staging = Path::new(&staging);
+
+  // Left behind by a relocation that was interrupted.
+  if staging.exists() {
+    std::fs::remove_dir_all(staging)?;
+  }
+
+  copy_directory(from, staging)?;
+
+  // rename fails if to is not empty.
+  if to.exists() {
+    std::fs::remove_dir(to)?;
+  }
+
+  std::fs::rename(staging, to)?;
+
+  std::fs::remove_dir_all(from)?;
+
+  Ok(())
+}
+
+/// Copies every file in from to to, waiting for the copies to
+/// reach the disk and checking that they are as long as the originals.
+fn copy_directory(from: &Path, to: &Path) -> Result<()> {
+  std::fs::create_dir_all(to)?;
+
+  for entry in std::fs::read_dir(from)? {
+    let entry = entry?;
+    let target = to.join(entry.file_name());
+
+    if entry.file_type()?.is_dir() {
+      copy_directory(&entry.path(), &target)?;
+      continue;
+    }
+
+    std::fs::copy(entry.path(), &target)?;
+
+    let copy = File::open(&target)?;
+    copy.sync_all()?;
+
+    let (expected, found) = (entry.metadata()?.len(), copy.metadata()?.len());
+
+    if expected != found {
+      return Err(
+        io::Error::other(format!(
+          "copy of {:?} has {} bytes, expected {}",
+          entry.path(),
+          found,
+          expected
+        ))
+        .into(),
+      );
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::backend::{FaultyBackend, MemoryBackend};
+  use crate::store::StoreError;
+  use std::io::Write;
+
+  /// Never assigns offset 7.
+  #[derive(Debug)]
+  struct SkipSeven;
+
+  impl OffsetAllocator for SkipSeven {
+    fn next_offset(&mut self, lowest: u64) -> u64 {
+      if lowest == 7 {
+        8
+      } else {
+        lowest
+      }
+    }
+  }
+
+  /// Reads the record at offset without its timestamp,
+  /// which depends on when the record was appended.
+  fn read_untimed(log: &Log, offset: u64) -> api::v1::Record {
+    api::v1::Record {
+      timestamp_ms: 0,
+      ..log.read(offset).unwrap()
+    }
+  }
+
+  fn new_log() -> Log {
+    Log::new(
+      tempfile::tempdir()
+        .unwrap()
+        .into_path()
+        .to_str()
+        .unwrap()
+        .to_owned(),
+      Config::default(),
+    )
+    .unwrap()
+  }
+
+  #[test_log::test]
+  fn append_then_read() {
+    let mut log = new_log();
+
+    let tests = vec![("a", 0), ("b", 1), ("c", 2)];
+
+    for (input, expected_offset) in tests {
+      let input = input.as_bytes().to_vec();
+
+      let offset = log.append(input.clone()).unwrap();
+
+      assert_eq!(expected_offset, offset);
+
+      assert_eq!(
+        api::v1::Record {
+          offset: expected_offset,
+          value: input,
+          ..Default::default()
+        },
+        read_untimed(&log, offset),
+      );
+    }
+  }
+
+  #[test_log::test]
+  fn append_then_read_in_memory() {
+    let mut log = Log::new_in_memory(Config::default()).unwrap();
+
+    let tests = vec![("a", 0), ("b", 1), ("c", 2)];
+
+    for (input, expected_offset) in tests {
+      let input = input.as_bytes().to_vec();
+
+      let offset = log.append(input.clone()).unwrap();
+
+      assert_eq!(expected_offset, offset);
+
+      assert_eq!(
+        api::v1::Record {
+          offset: expected_offset,
+          value: input,
+          ..Default::default()
+        },
+        read_untimed(&log, offset),
+      );
+    }
+  }
+
+  #[test_log::test]
+  fn append_batch_rolls_segments_within_the_batch() {
+    let mut log = Log::new_in_memory(Config {
+      initial_offset: 0,
+      max_store_bytes_per_segment: 64,
+      max_index_bytes_per_segment: 1024,
+      ..Config::default()
+    })
+    .unwrap();
+
+    let values: Vec<Vec<u8>> = (0..8).map(|i| vec![i]).collect();
+
+    let offsets = log.append_batch(values.clone()).unwrap();
+
+    assert_eq!((0..8).collect::<Vec<u64>>(), offsets);
+
+    // The batch did not fit in the first two segments.
+    assert!(log.segments.len() >= 3);
+
+    for (offset, value) in offsets.into_iter().zip(values) {
+      assert_eq!(
+        api::v1::Record {
+          offset,
+          value,
+          ..Default::default()
+        },
+        read_untimed(&log, offset)
+      );
+    }
+  }
+
+  #[test_log::test]
+  fn rolled_segments_hold_as_many_records_as_the_config_allows() {
+    let mut log = Log::new_in_memory(
+      Config::builder()
+        .max_store_bytes_per_segment(64 * 1024)
+        .max_index_bytes_per_segment(64 * 1024)
+        .build(),
+    )
+    .unwrap();
+
+    for i in 0..10_000u32 {
+      log.append(i.to_be_bytes().to_vec()).unwrap();
+    }
+
+    // Each record takes about 26 store bytes, so a segment holds about
+    // 2500 of them. A segment created with a zeroed config would be
+    // maxed after a single record.
+    assert!(log.segments.len() <= 10, "{} segments", log.segments.len());
+  }
+
+  #[test_log::test]
+  fn consecutive_duplicates_are_only_appended_once_when_dedup_is_enabled() {
+    let mut log = Log::new_in_memory(Config::builder().dedup_consecutive(true).build()).unwrap();
+
+    for _ in 0..3 {
+      assert_eq!(0, log.append("heartbeat".as_bytes().to_vec()).unwrap());
+    }
+
+    assert_eq!(1, log.highest_offset());
+
+    // Only consecutive duplicates are skipped.
+    assert_eq!(1, log.append("a".as_bytes().to_vec()).unwrap());
+    assert_eq!(2, log.append("heartbeat".as_bytes().to_vec()).unwrap());
+  }
+
+  #[test_log::test]
+  fn records_are_stored_at_the_offsets_chosen_by_the_offset_allocator() {
+    /// Only assigns even offsets.
+    #[derive(Debug)]
+    struct EvenOffsets;
+
+    impl OffsetAllocator for EvenOffsets {
+      fn next_offset(&mut self, lowest: u64) -> u64 {
+        lowest + lowest % 2
+      }
+    }
+
+    let mut log = Log::new_in_memory(Config {
+      max_store_bytes_per_segment: 64,
+      ..Config::default()
+    })
+    .unwrap()
+    .with_offset_allocator(Box::new(EvenOffsets));
+
+    // 3 records per segment, so records are spread across segments.
+    for i in 0..8 {
+      assert_eq!(i * 2, log.append(vec![i as u8]).unwrap());
+    }
+
+    assert_eq!(3, log.segments.len());
+
+    for i in 0..8 {
+      assert_eq!(vec![i as u8], log.read(i * 2).unwrap().value);
+    }
+
+    // Skipped offsets are not in the log.
+    assert!(log.read(1).is_err());
+    assert!(log.read(15).is_err());
+  }
+
+  #[test_log::test]
+  fn drain_to_moves_old_segments_to_another_log() {
+    let config = Config {
+      max_store_bytes_per_segment: 64,
+      ..Config::default()
+    };
+
+    let mut hot = Log::new_in_memory(config.clone()).unwrap();
+
+    // 3 records per segment: [0, 3), [3, 6), [6, 8).
+    for i in 0..8 {
+      hot.append(vec![i]).unwrap();
+    }
+
+    let mut cold = Log::new_in_memory(config).unwrap();
+
+    assert_eq!(2, hot.drain_to(&mut cold, 6).unwrap());
+
+    for offset in 0..6 {
+      assert_eq!(vec![offset as u8], cold.read(offset).unwrap().value);
+      assert!(hot.read(offset).is_err());
+    }
+
+    assert_eq!(6, hot.lowest_offset());
+    assert_eq!(vec![7], hot.read(7).unwrap().value);
+  }
+
+  #[test_log::test]
+  fn drain_to_keeps_the_segments_that_were_not_copied_completely() {
+    let mut hot =
+      Log::new_in_memory(Config::builder().max_store_bytes_per_segment(64).build()).unwrap();
+
+    // 3 records per segment: [0, 3), [3, 6), [6, 9) and the empty active segment.
+    for i in 0..9 {
+      hot.append(vec![i]).unwrap();
+    }
+
+    let mut cold = Log::new_in_memory(Config::builder().max_offset(5).build()).unwrap();
+
+    // Offset 5 cannot be copied.
+    assert_eq!(
+      Some(&CommitLogError::OffsetCeilingReached {
+        offset: 5,
+        max_offset: 5
+      }),
+      hot
+        .drain_to(&mut cold, 9)
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+
+    // Only the first segment was moved.
+    assert_eq!(3, hot.lowest_offset());
+    assert_eq!(3, hot.segments.len());
+
+    for offset in 3..9 {
+      assert_eq!(vec![offset as u8], hot.read(offset).unwrap().value);
+    }
+
+    assert_eq!(9, hot.append(vec![9]).unwrap());
+  }
+
+  #[test_log::test]
+  fn enforce_retention_removes_segments_whose_records_expired() {
+    let mut log = Log::new_in_memory(Config {
+      max_store_bytes_per_segment: 64,
+      ..Config::default()
+    })
+    .unwrap();
+
+    let two_hours_ago_ms = (SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_millis() as u64;
+
+    let append_old = |log: &mut Log, value: u8| {
+      log
+        .append_record(api::v1::Record {
+          value: vec![value],
+          timestamp_ms: two_hours_ago_ms,
+          ..Default::default()
+        })
+        .unwrap()
+    };
+
+    // The active segment is never removed, even if its records expired.
+    append_old(&mut log, 0);
+
+    assert_eq!(
+      0,
+      log.enforce_retention(Duration::from_secs(60 * 60)).unwrap()
+    );
+
+    // 3 records per segment: [0, 3) expired, [3, 6) did not.
+    for value in 1..3 {
+      append_old(&mut log, value);
+    }
+    for value in 3..6 {
+      log.append(vec![value]).unwrap();
+    }
+
+    assert_eq!(3, log.segments.len());
+
+    assert_eq!(
+      1,
+      log.enforce_retention(Duration::from_secs(60 * 60)).unwrap()
+    );
+
+    assert_eq!(2, log.segments.len());
+    assert_eq!(3, log.lowest_offset());
+    assert!(log.read(2).is_err());
+    assert_eq!(vec![3], log.read(3).unwrap().value);
+
+    assert_eq!(
+      0,
+      log.enforce_retention(Duration::from_secs(60 * 60)).unwrap()
+    );
+
+    assert_eq!(6, log.append(vec![6]).unwrap());
+  }
+
+  #[test_log::test]
+  fn records_with_positions_match_the_positions_in_the_indexes() {
+    let mut log = Log::new_in_memory(Config {
+      max_store_bytes_per_segment: 64,
+      ..Config::default()
+    })
+    .unwrap();
+
+    // 3 records per segment, 3 segments.
+    for value in 0..7 {
+      log.append(vec![value]).unwrap();
+    }
+
+    let records = log
+      .records_with_positions()
+      .collect::<Result<Vec<_>>>()
+      .unwrap();
+
+    assert_eq!(
+      (0..7).collect::<Vec<_>>(),
+      records
+        .iter()
+        .map(|(offset, _, _)| *offset)
+        .collect::<Vec<_>>()
+    );
+
+    for (offset, position, record) in records {
+      let segment = log.segments[log.find_segment_index(offset).unwrap()]
+        .read()
+        .unwrap();
+
+      assert_eq!(position, segment.position_of(offset).unwrap());
+      assert_eq!(vec![offset as u8], record.value);
+    }
+  }
+
+  #[test_log::test]
+  fn reader_reads_every_record_across_segments() {
+    use prost::Message;
+    use std::io::Read;
+
+    let mut log = Log::new_in_memory(Config {
+      max_store_bytes_per_segment: 64,
+      ..Config::default()
+    })
+    .unwrap();
+
+    // 3 records per segment, 2 segments.
+    for value in 0..5 {
+      log.append(vec![value]).unwrap();
+    }
+
+    let decode = |bytes: &[u8]| {
+      let mut records = Vec::new();
+      let mut position = 0;
+
+      while position < bytes.len() {
+        let len = u64::from_be_bytes(bytes[position..position + 8].try_into().unwrap()) as usize;
+        position += 8;
+
+        records.push(api::v1::Record::decode(&bytes[position..position + len]).unwrap());
+        position += len;
+      }
+
+      records
+    };
+
+    let mut bytes = Vec::new();
+    log.reader(0).read_to_end(&mut bytes).unwrap();
+
+    let records = decode(&bytes);
+
+    assert_eq!(
+      (0..5)
+        .map(|offset| read_untimed(&log, offset))
+        .collect::<Vec<_>>(),
+      records
+        .into_iter()
+        .map(|record| api::v1::Record {
+          timestamp_ms: 0,
+          ..record
+        })
+        .collect::<Vec<_>>()
+    );
+
+    let mut bytes = Vec::new();
+    log.reader(3).read_to_end(&mut bytes).unwrap();
+
+    assert_eq!(
+      vec![3, 4],
+      decode(&bytes)
+        .iter()
+        .map(|record| record.offset)
+        .collect::<Vec<_>>()
+    );
+  }
+
+  #[test_log::test]
+  fn segments_are_read_in_the_format_they_were_written_with() {
+    let directory = tempfile::tempdir()
+      .unwrap()
+      .into_path()
+      .to_str()
+      .unwrap()
+      .to_owned();
+
+    let config = |omit_record_offsets: bool| {
+      Config::builder()
+        .max_store_bytes_per_segment(64)
+        .omit_record_offsets(omit_record_offsets)
+        .build()
+    };
+
+    let mut log = Log::new(directory.clone(), config(true)).unwrap();
+
+    for value in 0..5 {
+      log.append(vec![value]).unwrap();
+    }
+
+    log.close().unwrap();
+
+    // The segments written without offsets are still read
+    // with their offsets once the config changes.
+    let mut log = Log::new(directory.clone(), config(false)).unwrap();
+
+    for value in 5..10 {
+      log.append(vec![value]).unwrap();
+    }
+
+    for offset in 0..10 {
+      assert_eq!(offset, log.read(offset).unwrap().offset);
+    }
+
+    for segment in &log.segments {
+      let segment = segment.read().unwrap();
+
+      assert_eq!(
+        Ok(segment.next_offset() - segment.base_offset()),
+        segment.validate()
+      );
+    }
+
+    log.close().unwrap();
+
+    let log = Log::new(directory.clone(), config(true)).unwrap();
+
+    for offset in 0..10 {
+      assert_eq!(offset, log.read(offset).unwrap().offset);
+    }
+
+    for segment in &log.segments {
+      let segment = segment.read().unwrap();
+
+      assert_eq!(
+        Ok(segment.next_offset() - segment.base_offset()),
+        segment.validate()
+      );
+    }
+  }
+
+  #[test_log::test]
+  fn records_stored_without_offsets_are_read_with_their_offsets() {
+    let new_log = |omit_record_offsets: bool| {
+      let mut log = Log::new(
+        tempfile::tempdir()
+          .unwrap()
+          .into_path()
+          .to_str()
+          .unwrap()
+          .to_owned(),
+        Config::builder()
+          .omit_record_offsets(omit_record_offsets)
+          .build(),
+      )
+      .unwrap();
+
+      for value in 0..100 {
+        log.append(vec![value]).unwrap();
+      }
+
+      log
+    };
+
+    let redundant = new_log(false);
+    let omitted = new_log(true);
+
+    for offset in 0..100 {
+      assert_eq!(
+        read_untimed(&redundant, offset),
+        read_untimed(&omitted, offset)
+      );
+    }
+
+    let segment = omitted.segments[0].read().unwrap();
+    let (_, position, record) = omitted.records_with_positions().nth(7).unwrap().unwrap();
+
+    assert_eq!(7, record.offset);
+    assert_eq!(7, segment.read_record_at(position).unwrap().offset);
+    assert_eq!(Ok(segment.next_offset()), segment.validate());
+
+    // Offsets 1 to 99 take 2 bytes each, a field tag and a 1 byte varint.
+    assert_eq!(
+      redundant.disk_usage().store_bytes - 99 * 2,
+      omitted.disk_usage().store_bytes
+    );
+  }
+
+  #[test_log::test]
+  fn config_builder_sets_the_segment_sizes() {
+    let config = Config::builder()
+      .initial_offset(10)
+      .max_store_bytes_per_segment(64)
+      .max_index_bytes_per_segment(1024)
+      .build();
+
+    let mut log = Log::new_in_memory(config).unwrap();
+
+    // 3 records per segment.
+    for value in 0..7 {
+      assert_eq!(10 + value as u64, log.append(vec![value]).unwrap());
+    }
+
+    assert_eq!(
+      vec![10, 13, 16],
+      log
+        .segments
+        .iter()
+        .map(|segment| segment.read().unwrap().base_offset())
+        .collect::<Vec<_>>()
+    );
+  }
+
+  #[test_log::test]
+  fn batches_with_a_record_bigger_than_the_max_record_size_are_rejected() {
+    let mut log = Log::new_in_memory(Config::builder().max_record_bytes(4).build()).unwrap();
+
+    assert_eq!(0, log.append(vec![0; 4]).unwrap());
+
+    assert_eq!(
+      Some(&CommitLogError::RecordTooLarge { size: 5, max: 4 }),
+      log
+        .append_batch(vec![vec![1], vec![2; 5]])
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+
+    // No record of the batch was appended.
+    assert_eq!(1, log.highest_offset());
+  }
+
+  #[test_log::test]
+  fn entries_bigger_than_the_max_entry_size_are_not_appended_or_read() {
+    let mut log = new_log();
+
+    assert_eq!(0, log.append(vec![0; 100]).unwrap());
+
+    let directory = log.directory.clone();
+
+    log.close().unwrap();
+
+    let mut log = Log::new(directory, Config::builder().max_entry_bytes(64).build()).unwrap();
+
+    // The entry was appended before there was a max entry size,
+    // it looks like a corrupted length now.
+    assert!(matches!(
+      log.read(0).unwrap_err().downcast_ref::<StoreError>(),
+      Some(StoreError::ImplausibleLength { max: 64, .. })
+    ));
+
+    assert!(matches!(
+      log
+        .append(vec![1; 100])
+        .unwrap_err()
+        .downcast_ref::<StoreError>(),
+      Some(StoreError::EntryTooLarge { max: 64, .. })
+    ));
+
+    assert_eq!(1, log.append(vec![2]).unwrap());
+
+    assert_eq!(vec![2], log.read(1).unwrap().value);
+  }
+
+  #[test_log::test]
+  fn appends_past_the_offset_ceiling_are_rejected() {
+    let mut log = Log::new_in_memory(Config::builder().max_offset(3).build()).unwrap();
+
+    for i in 0..3 {
+      assert_eq!(i, log.append(vec![i as u8]).unwrap());
+    }
+
+    assert_eq!(
+      Some(&CommitLogError::OffsetCeilingReached {
+        offset: 3,
+        max_offset: 3
+      }),
+      log
+        .append(vec![3])
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+
+    assert_eq!(3, log.highest_offset());
+  }
+
+  #[test_log::test]
+  fn segments_are_not_created_past_the_hard_limit() {
+    let mut log = Log::new_in_memory(
+      Config::builder()
+        .max_store_bytes_per_segment(64)
+        .max_segments_hard_limit(2)
+        .build(),
+    )
+    .unwrap();
+
+    // 3 records per segment, the second segment is maxed but not rolled.
+    for i in 0..6 {
+      log.append(vec![i]).unwrap();
+    }
+
+    assert_eq!(2, log.segments.len());
+
+    assert_eq!(
+      Some(&CommitLogError::TooManySegments(2)),
+      log
+        .append(vec![6])
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+    assert_eq!(
+      Some(&CommitLogError::TooManySegments(2)),
+      log
+        .new_segment(6)
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+
+    assert_eq!(2, log.segments.len());
+    assert_eq!(6, log.highest_offset());
+  }
+
+  #[test_log::test]
+  fn grow_index_policy_only_rolls_segments_when_the_store_is_full() {
+    let directory = tempfile::tempdir().unwrap();
+    let directory = directory.path().to_str().unwrap().to_owned();
+
+    // The index fits 2 entries and the store fits 39 records.
+    let config = Config::builder()
+      .max_index_bytes_per_segment(24)
+      .on_index_full(IndexFullPolicy::GrowIndex)
+      .build();
+
+    let mut log = Log::new(directory.clone(), config.clone()).unwrap();
+
+    for i in 0..30 {
+      log.append(vec![i]).unwrap();
+    }
+
+    assert_eq!(1, log.segments.len());
+
+    log.close().unwrap();
+
+    // The grown index is not shrunk back when the segment is opened again.
+    let log = Log::new(directory, config).unwrap();
+
+    assert_eq!(30, log.highest_offset());
+
+    for i in 0..30 {
+      assert_eq!(vec![i as u8], log.read(i).unwrap().value);
+    }
+  }
+
+  #[test_log::test]
+  fn subscription_ends_when_the_subscriber_falls_behind() {
+    let mut log = Log::new_in_memory(Config::default()).unwrap();
+
+    let subscription = log.subscribe();
+
+    for value in 0..SUBSCRIPTION_CAPACITY + 1 {
+      log.append(value.to_string().into_bytes()).unwrap();
+    }
+
+    // The log does not wait for the subscriber, it is dropped
+    // once its buffer is full and the log keeps going.
+    assert_eq!(
+      SUBSCRIPTION_CAPACITY as u64 + 1,
+      log.append("a".as_bytes().to_vec()).unwrap()
+    );
+    assert!(log.subscribers.get_mut().unwrap().is_empty());
+
+    let offsets: Vec<u64> = subscription.map(|(offset, _)| offset).collect();
+
+    assert_eq!(
+      (0..SUBSCRIPTION_CAPACITY as u64).collect::<Vec<_>>(),
+      offsets
+    );
+  }
+
+  #[test_log::test]
+  fn subscription_receives_every_appended_record_in_order() {
+    let mut log = Log::new_in_memory(Config::default()).unwrap();
+
+    log.append("before".as_bytes().to_vec()).unwrap();
+
+    let subscription = log.subscribe();
+
+    for value in ["a", "b", "c"] {
+      log.append(value.as_bytes().to_vec()).unwrap();
+    }
+
+    // Dropping the log ends the subscription.
+    drop(log);
+
+    let received: Vec<(u64, api::v1::Record)> = subscription
+      .map(|(offset, record)| {
+        (
+          offset,
+          api::v1::Record {
+            timestamp_ms: 0,
+            ..record
+          },
+        )
+      })
+      .collect();
+
+    let expected: Vec<(u64, api::v1::Record)> = [(1, "a"), (2, "b"), (3, "c")]
+      .into_iter()
+      .map(|(offset, value)| {
+        (
+          offset,
+          api::v1::Record {
+            offset,
+            value: value.as_bytes().to_vec(),
+            ..Default::default()
+          },
+        )
+      })
+      .collect();
+
+    assert_eq!(expected, received);
+  }
+
+  #[test_log::test]
+  fn sequential_reader_only_searches_when_offsets_jump() {
+    let mut log = Log::new_in_memory(Config {
+      max_store_bytes_per_segment: 64,
+      ..Config::default()
+    })
+    .unwrap();
+
+    for i in 0..100 {
+      log.append(vec![i]).unwrap();
+    }
+
+    assert!(log.segments.len() > 10);
+
+    let mut reader = log.sequential_reader();
+
+    for offset in 0..100 {
+      assert_eq!(log.read(offset).unwrap(), reader.read(offset).unwrap());
+    }
+
+    assert_eq!(0, reader.searches);
+
+    // Going back to an older segment needs a search.
+    assert_eq!(log.read(5).unwrap(), reader.read(5).unwrap());
+    assert_eq!(1, reader.searches);
+
+    assert!(reader.read(100).is_err());
+  }
+
+  #[test_log::test]
+  fn find_segment_returns_the_same_segment_as_a_linear_scan() {
+    let mut log = Log::new_in_memory(Config {
+      initial_offset: 5,
+      max_store_bytes_per_segment: 64,
+      max_index_bytes_per_segment: 1024,
+      ..Config::default()
+    })
+    .unwrap();
+
+    for i in 0..100 {
+      log.append(vec![i]).unwrap();
+    }
+
+    assert!(log.segments.len() > 10);
+
+    // Includes offsets before the first and after the last record.
+    for offset in 0..110 {
+      let expected = log
+        .segments
+        .iter()
+        .map(|segment| segment.read().unwrap())
+        .find(|segment| segment.base_offset() <= offset && offset < segment.next_offset())
+        .map(|segment| segment.base_offset());
+
+      assert_eq!(
+        expected,
+        log
+          .find_segment(offset)
+          .map(|segment| segment.read().unwrap().base_offset())
+      );
+    }
+  }
+
+  #[test_log::test]
+  fn failed_append_does_not_consume_an_offset() {
+    let config = Config::default();
+
+    // Writes 0 and 1 are the length and contents of the first record,
+    // write 3 is the contents of the second one.
+    let backend = FaultyBackend::new(Box::new(MemoryBackend::new())).short_write_at(3);
+
+    let segment = Segment::with_backend(
+      config.initial_offset,
+      config.segment_config(),
+      Box::new(backend),
+    )
+    .unwrap();
+
+    let mut log = Log::with_segments(String::new(), config, vec![segment], true).unwrap();
+
+    assert_eq!(0, log.append("a".as_bytes().to_vec()).unwrap());
+
+    assert!(log.append("b".as_bytes().to_vec()).is_err());
+
+    // The failed record is not visible and its offset is reused.
+    assert_eq!(1, log.highest_offset());
+    assert!(log.read(1).is_err());
+
+    assert_eq!(1, log.append("c".as_bytes().to_vec()).unwrap());
+
+    assert_eq!(
+      api::v1::Record {
+        offset: 0,
+        value: "a".as_bytes().to_vec(),
+        ..Default::default()
+      },
+      read_untimed(&log, 0)
+    );
+    assert_eq!(
+      api::v1::Record {
+        offset: 1,
+        value: "c".as_bytes().to_vec(),
+        ..Default::default()
+      },
+      read_untimed(&log, 1)
+    );
+  }
+
+  #[test_log::test]
+  fn read_with_tail_info_returns_true_only_for_the_last_record() {
+    let mut log = new_log();
+
+    log.append("a".as_bytes().to_vec()).unwrap();
+
+    assert!(log.read_with_tail_info(0).unwrap().1);
+
+    log.append("b".as_bytes().to_vec()).unwrap();
+
+    // The tail has advanced, so offset 0 is no longer the last record.
+    assert!(!log.read_with_tail_info(0).unwrap().1);
+    assert!(log.read_with_tail_info(1).unwrap().1);
+  }
+
+  #[test_log::test]
+  fn since_returns_the_records_appended_after_offset() {
+    let mut log = new_log();
+
+    for i in 0..4 {
+      log.append(vec![i]).unwrap();
+    }
+
+    // Caught up after syncing up to offset 3.
+    assert!(log.since(3, 10).unwrap().is_empty());
+
+    log.append(vec![4]).unwrap();
+    log.append(vec![5]).unwrap();
+
+    let offsets: Vec<u64> = log
+      .since(3, 10)
+      .unwrap()
+      .into_iter()
+      .map(|record| record.offset)
+      .collect();
+
+    assert_eq!(vec![4, 5], offsets);
+
+    assert_eq!(1, log.since(3, 1).unwrap().len());
+  }
+
+  #[test_log::test]
+  fn since_the_highest_possible_offset_returns_nothing() {
+    let mut log = new_log();
+
+    log.append(vec![0]).unwrap();
+
+    assert!(log.since(u64::MAX, 10).unwrap().is_empty());
+  }
+
+  #[test_log::test]
+  fn read_range_stops_at_the_first_budget_reached() {
+    let mut log = new_log();
+
+    for i in 0..10 {
+      log.append(vec![i; 10]).unwrap();
+    }
+
+    let offsets =
+      |read: &RangeRead| -> Vec<u64> { read.records.iter().map(|record| record.offset).collect() };
+
+    // 35 bytes fit 3 values of 10 bytes, before the 8 records are read.
+    let read = log.read_range(0..10, 8, 35).unwrap();
+
+    assert_eq!(vec![0, 1, 2], offsets(&read));
+    assert_eq!(3, read.next_offset);
+
+    let read = log.read_range(read.next_offset..10, 8, 35).unwrap();
+
+    assert_eq!(vec![3, 4, 5], offsets(&read));
+    assert_eq!(6, read.next_offset);
+
+    // The record count is reached first.
+    let read = log.read_range(read.next_offset..10, 2, 35).unwrap();
+
+    assert_eq!(vec![6, 7], offsets(&read));
+    assert_eq!(8, read.next_offset);
+
+    let read = log.read_range(read.next_offset..10, 8, 35).unwrap();
+
+    assert_eq!(vec![8, 9], offsets(&read));
+    assert_eq!(10, read.next_offset);
+
+    // A value bigger than the byte budget is still returned.
+    assert_eq!(vec![0], offsets(&log.read_range(0..10, 8, 5).unwrap()));
+  }
+
+  #[test_log::test]
+  fn read_range_walks_across_segments_and_stops_at_the_first_gap() {
+    let mut log = Log::new_in_memory(Config {
+      max_store_bytes_per_segment: 64,
+      ..Config::default()
+    })
+    .unwrap()
+    .with_offset_allocator(Box::new(SkipSeven));
+
+    // 3 records per segment: 0 to 2, 3 to 5 and 6, 8 and 9.
+    for i in 0..9 {
+      log.append(vec![i]).unwrap();
+    }
+
+    let offsets =
+      |read: &RangeRead| -> Vec<u64> { read.records.iter().map(|record| record.offset).collect() };
+
+    let read = log.read_range(1..5, usize::MAX, usize::MAX).unwrap();
+
+    assert_eq!(vec![1, 2, 3, 4], offsets(&read));
+    assert_eq!(5, read.next_offset);
+
+    let read = log.read_range(4..10, usize::MAX, usize::MAX).unwrap();
+
+    assert_eq!(vec![4, 5, 6], offsets(&read));
+    assert_eq!(7, read.next_offset);
+
+    let read = log.read_range(8..100, usize::MAX, usize::MAX).unwrap();
+
+    assert_eq!(vec![8, 9], offsets(&read));
+    assert_eq!(10, read.next_offset);
+  }
+
+  #[test_log::test]
+  fn read_at_position_returns_the_same_record_as_read() {
+    let mut log = new_log();
+
+    log.append("a".as_bytes().to_vec()).unwrap();
+    log.append("b".as_bytes().to_vec()).unwrap();
+
+    // The first entry takes 8 bytes for the length + 4 bytes for the crc32
+    // + 12 bytes for the record.
+    assert_eq!(log.read(1).unwrap(), log.read_at_position(0, 24).unwrap());
+
+    assert_eq!(
+      Some(&CommitLogError::SegmentNotFound(1)),
+      log
+        .read_at_position(1, 24)
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+  }
+
+  /// Counts the bytes allocated by the current thread,
+  /// so tests running in parallel are not counted.
+  struct CountingAllocator;
+
+  thread_local! {
+    static ALLOCATED_BYTES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+  }
+
+  unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+      ALLOCATED_BYTES.with(|bytes| bytes.set(bytes.get() + layout.size()));
+      std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+      std::alloc::System.dealloc(ptr, layout)
+    }
+  }
+
+  #[global_allocator]
+  static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+  /// Returns how many bytes f allocated.
+  fn allocated_bytes<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATED_BYTES.with(|bytes| bytes.get());
+
+    let result = f();
+
+    let allocated = ALLOCATED_BYTES.with(|bytes| bytes.get()) - before;
+
+    drop(result);
+
+    allocated
+  }
+
+  #[test_log::test]
+  fn read_pooled_does_not_allocate_a_buffer_for_the_store_entry() {
+    let mut log = new_log();
+
+    let value_len = 64 * 1024;
+
+    log.append(vec![1; value_len]).unwrap();
+
+    let pool = BufferPool::new();
+
+    // The first read allocates the pooled buffer.
+    log.read_pooled(0, &pool).unwrap();
+
+    let unpooled = allocated_bytes(|| log.read(0).unwrap());
+    let pooled = allocated_bytes(|| log.read_pooled(0, &pool).unwrap());
+
+    // Both allocate the decoded record, only read allocates the entry.
+    assert!(
+      unpooled - pooled >= value_len,
+      "read allocated {} bytes, read_pooled {} bytes",
+      unpooled,
+      pooled
+    );
+  }
+
+  #[test_log::test]
+  fn read_pooled_returns_the_same_records_as_read() {
+    let mut log = new_log();
+
+    for i in 0..10 {
+      log.append(vec![i; i as usize]).unwrap();
+    }
+
+    let pool = BufferPool::new();
+
+    for offset in 0..10 {
+      assert_eq!(
+        log.read(offset).unwrap(),
+        log.read_pooled(offset, &pool).unwrap()
+      );
+    }
+
+    assert!(log.read_pooled(10, &pool).is_err());
+  }
+
+  #[test_log::test]
+  fn log_reuses_data_stored_on_disk_by_prior_log_instances() {
+    let mut log = new_log();
+
+    let data = vec![(0, "a"), (1, "b"), (2, "c")];
+
+    for (_, input) in &data {
+      log.append(input.as_bytes().to_vec()).unwrap();
+    }
+
+    let directory = log.directory.clone();
+    let config = log.config.clone();
+
+    // Ensure contents are flushed to storage.
+    log.close().unwrap();
+
+    // Create a new log that should reuse the files that contain
+    // the data created by the first log.
+    let log = Log::new(directory, config).unwrap();
+
+    for (expected_offset, input) in data {
+      assert_eq!(
+        api::v1::Record {
+          offset: expected_offset,
+          value: input.as_bytes().to_vec(),
+          ..Default::default()
+        },
+        read_untimed(&log, expected_offset)
+      );
+    }
+  }
+
+  #[test_log::test]
+  fn reopen_makes_segments_added_to_the_directory_visible() {
+    let mut log = new_log();
+
+    log.append("a".as_bytes().to_vec()).unwrap();
+    log.append("b".as_bytes().to_vec()).unwrap();
+
+    let subscription = log.subscribe();
+
+    // Add a segment to the directory behind the log's back.
+    let mut segment = Segment::new(&log.directory, 100, log.config.segment_config()).unwrap();
+    segment.append("c".as_bytes().to_vec()).unwrap();
+    segment.close().unwrap();
+
+    assert!(log.read(100).is_err());
+
+    log.reopen().unwrap();
+
+    assert_eq!(2, log.segments.len());
+    assert_eq!("a".as_bytes().to_vec(), log.read(0).unwrap().value);
+    assert_eq!("c".as_bytes().to_vec(), log.read(100).unwrap().value);
+
+    // The added segment is the active one and subscriptions are kept.
+    assert_eq!(101, log.append("d".as_bytes().to_vec()).unwrap());
+    assert_eq!(101, subscription.receiver.recv().unwrap().0);
+  }
+
+  #[test_log::test]
+  fn log_keeps_its_segments_when_reopen_fails() {
+    let mut log = new_log();
+
+    log.append("a".as_bytes().to_vec()).unwrap();
+    log.append("b".as_bytes().to_vec()).unwrap();
+
+    // The default bad file policy is strict.
+    std::fs::write(Path::new(&log.directory).join("garbage.store"), b"garbage").unwrap();
+
+    assert!(log.reopen().is_err());
+
+    assert_eq!("a".as_bytes().to_vec(), log.read(0).unwrap().value);
+    assert_eq!(2, log.append("c".as_bytes().to_vec()).unwrap());
+    assert_eq!("c".as_bytes().to_vec(), log.read(2).unwrap().value);
+
+    // Once the bad file is gone, reopen sees every record.
+    std::fs::remove_file(Path::new(&log.directory).join("garbage.store")).unwrap();
+
+    log.reopen().unwrap();
+
+    assert_eq!("b".as_bytes().to_vec(), log.read(1).unwrap().value);
+    assert_eq!("c".as_bytes().to_vec(), log.read(2).unwrap().value);
+    assert_eq!(3, log.append("d".as_bytes().to_vec()).unwrap());
+  }
+
+  #[test_log::test]
+  fn relocate_moves_every_segment_to_the_new_directory() {
+    let old_directory = tempfile::tempdir().unwrap();
+    let new_directory = tempfile::tempdir().unwrap();
+    let new_directory = new_directory.path().join("log");
+
+    let mut log = Log::new(
+      old_directory.path().to_str().unwrap().to_owned(),
+      Config {
+        max_store_bytes_per_segment: 64,
+        ..Config::default()
+      },
+    )
+    .unwrap();
+
+    // 3 records per segment, 3 segments.
+    for i in 0..7 {
+      log.append(vec![i]).unwrap();
+    }
+
+    log.relocate(new_directory.to_str().unwrap()).unwrap();
+
+    assert!(!old_directory.path().join("0.store").exists());
+    assert!(new_directory.join("0.store").exists());
+
+    assert_eq!(3, log.segments.len());
+    for i in 0..7 {
+      assert_eq!(vec![i], log.read(i as u64).unwrap().value);
+    }
+
+    // New segments are created in the new directory.
+    for i in 7..9 {
+      log.append(vec![i]).unwrap();
+    }
+    assert!(new_directory.join("9.store").exists());
+
+    log.close().unwrap();
+
+    let log = Log::new(
+      new_directory.to_str().unwrap().to_owned(),
+      Config::default(),
+    )
+    .unwrap();
+
+    for i in 0..9 {
+      assert_eq!(vec![i], log.read(i as u64).unwrap().value);
+    }
+  }
+
+  #[test_log::test]
+  fn compress_sealed_segments_shrinks_stores_and_keeps_values() {
+    let directory = tempfile::tempdir().unwrap();
+    let directory = directory.path().to_str().unwrap().to_owned();
+
+    let mut log = Log::new(directory.clone(), Config::default()).unwrap();
+
+    let value = |i: u8| -> Vec<u8> { [vec![i], vec![b'a'; 200]].concat() };
+
+    // 5 records per segment, 2 sealed segments.
+    for i in 0..12 {
+      log.append(value(i)).unwrap();
+    }
+
+    assert_eq!(3, log.segments.len());
+
+    let sealed_size = |log: &Log| -> u64 {
+      log.segments[..2]
+        .iter()
+        .map(|segment| segment.read().unwrap().store_size())
+        .sum()
+    };
+
+    let uncompressed_size = sealed_size(&log);
+
+    assert_eq!(2, log.compress_sealed_segments().unwrap());
+    assert_eq!(0, log.compress_sealed_segments().unwrap());
+
+    assert!(sealed_size(&log) < uncompressed_size / 2);
+
+    for i in 0..12 {
+      assert_eq!(value(i), log.read(i as u64).unwrap().value);
+    }
+
+    log.close().unwrap();
+
+    let log = Log::new(directory, Config::default()).unwrap();
+
+    assert!(log.segments[0].read().unwrap().is_compressed());
+    assert!(!log.segments[2].read().unwrap().is_compressed());
+
+    for i in 0..12 {
+      assert_eq!(value(i), log.read(i as u64).unwrap().value);
+    }
+  }
+
+  #[test_log::test]
+  fn compressed_copies_are_not_swapped_in_while_the_segment_is_in_use() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let mut log = Log::new(
+      directory.path().to_str().unwrap().to_owned(),
+      Config::default(),
+    )
+    .unwrap();
+
+    let value = vec![b'a'; 200];
+
+    for _ in 0..6 {
+      log.append(value.clone()).unwrap();
+    }
+
+    let segments = log.uncompressed_sealed_segments();
+
+    assert_eq!(1, segments.len());
+
+    let segment = segments.into_iter().next().unwrap();
+
+    assert!(segment.read().unwrap().write_compressed().unwrap());
+
+    // A slow read is still using the segment.
+    let reader = log.segment_for(0).unwrap();
+
+    assert!(!log.replace_with_compressed(segment).unwrap());
+
+    assert!(!reader.read().unwrap().is_compressed());
+    assert!(!directory.path().join("compressing").exists());
+
+    drop(reader);
+
+    // The next run compresses it.
+    assert_eq!(1, log.compress_sealed_segments().unwrap());
+    assert_eq!(value, log.read(0).unwrap().value);
+  }
+
+  #[test_log::test]
+  fn segment_digests_only_differ_for_segments_with_different_records() {
+    let new_log_with = |values: &[u8]| {
+      let mut log = Log::new(
+        tempfile::tempdir()
+          .unwrap()
+          .into_path()
+          .to_str()
+          .unwrap()
+          .to_owned(),
+        Config {
+          max_store_bytes_per_segment: 64,
+          ..Config::default()
+        },
+      )
+      .unwrap();
+
+      // Records appended at the same time, so only their values differ.
+      for value in values {
+        log
+          .append_record(api::v1::Record {
+            value: vec![*value],
+            timestamp_ms: 1,
+            ..Default::default()
+          })
+          .unwrap();
+      }
+
+      log
+    };
+
+    // 3 records per segment, 3 segments.
+    let digests = new_log_with(&[0, 1, 2, 3, 4, 5, 6])
+      .segment_digests()
+      .unwrap();
+
+    assert_eq!(
+      vec![0, 3, 6],
+      digests
+        .iter()
+        .map(|(base_offset, _)| *base_offset)
+        .collect::<Vec<_>>()
+    );
+
+    assert_eq!(
+      digests,
+      new_log_with(&[0, 1, 2, 3, 4, 5, 6])
+        .segment_digests()
+        .unwrap()
+    );
+
+    let changed = new_log_with(&[0, 1, 2, 3, 9, 5, 6])
+      .segment_digests()
+      .unwrap();
+
+    assert_eq!(digests[0], changed[0]);
+    assert_ne!(digests[1], changed[1]);
+    assert_eq!(digests[2], changed[2]);
+  }
+
+  #[test_log::test]
+  fn verify_chain_detects_records_changed_on_disk() {
+    let directory = tempfile::tempdir().unwrap();
+    let directory = directory.path().to_str().unwrap().to_owned();
+
+    let mut log = Log::new(directory.clone(), Config::default()).unwrap();
+
+    let first = log.append_audited("value-a".as_bytes().to_vec()).unwrap();
+    // Records that are not audited are not part of the chain.
+    log.append("plain".as_bytes().to_vec()).unwrap();
+    let second = log.append_audited("value-b".as_bytes().to_vec()).unwrap();
+
+    assert_eq!([0; 32], first.prev_hash);
+    assert_eq!(first.record_hash, second.prev_hash);
+    assert_eq!(2, second.offset);
+
+    log.verify_chain().unwrap();
+
+    log.close().unwrap();
+
+    // The chain continues after the log is opened again.
+    let mut log = Log::new(directory.clone(), Config::default()).unwrap();
+    let third = log.append_audited("value-c".as_bytes().to_vec()).unwrap();
+    assert_eq!(second.record_hash, third.prev_hash);
+    log.verify_chain().unwrap();
+    log.close().unwrap();
+
+    // Change value-b to value-x in the store file, updating the
+    // crc32 of the entry so only the chain can tell.
+    let store_path = Path::new(&directory).join("0.store");
+    let mut store = std::fs::read(&store_path).unwrap();
+    let position = store
+      .windows(7)
+      .position(|window| window == "value-b".as_bytes())
+      .unwrap();
+    store[position + 6] = b'x';
+
+    let mut entry = 0;
+    loop {
+      let length = u64::from_be_bytes(store[entry..entry + 8].try_into().unwrap()) as usize;
+      let contents = entry + 12..entry + 12 + length;
+
+      if contents.contains(&position) {
+        let crc = crc32fast::hash(&store[contents]);
+        store[entry + 8..entry + 12].copy_from_slice(&crc.to_be_bytes());
+        break;
+      }
+
+      entry = contents.end;
+    }
+
+    std::fs::write(&store_path, store).unwrap();
+
+    let log = Log::new(directory, Config::default()).unwrap();
+
+    assert_eq!("value-x".as_bytes(), log.read(2).unwrap().value);
+
+    assert_eq!(
+      Some(&ChainError::HashMismatch { offset: 2 }),
+      log.verify_chain().unwrap_err().downcast_ref::<ChainError>()
+    );
+  }
+
+  #[test_log::test]
+  fn segment_files_are_created_with_the_configured_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = tempfile::tempdir().unwrap().into_path().join("log");
+
+    let mut log = Log::new(
+      directory.to_str().unwrap().to_owned(),
+      Config::builder().file_mode(0o600).build(),
+    )
+    .unwrap();
+
+    log.append(vec![0]).unwrap();
+
+    let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    assert_eq!(0o700, mode(&directory));
+    assert_eq!(0o600, mode(&directory.join("0.store")));
+    assert_eq!(0o600, mode(&directory.join("0.index")));
+  }
+
+  #[test_log::test]
+  fn verify_reports_progress_per_segment_and_can_be_canceled() {
+    let mut log = Log::new(
+      tempfile::tempdir()
+        .unwrap()
+        .into_path()
+        .to_str()
+        .unwrap()
+        .to_owned(),
+      Config {
+        max_store_bytes_per_segment: 64,
+        ..Config::default()
+      },
+    )
+    .unwrap();
+
+    // 3 records per segment, 3 segments.
+    for value in 0..7 {
+      log.append(vec![value]).unwrap();
+    }
+
+    let mut events = Vec::new();
+
+    assert_eq!(
+      Ok(VerifyOutcome::Completed),
+      log.verify(|progress| {
+        events.push(progress);
+        ControlFlow::Continue(())
+      })
+    );
+
+    assert_eq!(
+      vec![(0, 3), (3, 6), (6, 7)],
+      events
+        .iter()
+        .map(|progress| (progress.segment, progress.records_checked))
+        .collect::<Vec<_>>()
+    );
+    assert!(events
+      .windows(2)
+      .all(|pair| pair[0].bytes_checked < pair[1].bytes_checked));
+    assert_eq!(
+      log.disk_usage().store_bytes,
+      events.last().unwrap().bytes_checked
+    );
+    assert!(events
+      .iter()
+      .all(|progress| progress.bytes_total == log.disk_usage().store_bytes));
+
+    let mut events = 0;
+
+    assert_eq!(
+      Ok(VerifyOutcome::Canceled { segment: 3 }),
+      log.verify(|progress| {
+        events += 1;
+
+        if progress.segment == 3 {
+          ControlFlow::Break(())
+        } else {
+          ControlFlow::Continue(())
+        }
+      })
+    );
+    assert_eq!(2, events);
+  }
+
+  #[test_log::test]
+  fn log_can_be_created_from_segments_opened_by_the_caller() {
+    let segment = |base_offset: u64, values: &[&str]| {
+      let mut segment =
+        Segment::in_memory(base_offset, Config::default().segment_config()).unwrap();
+
+      for value in values {
+        segment.append(value.as_bytes().to_vec()).unwrap();
+      }
+
+      segment
+    };
+
+    assert_eq!(
+      Some(&ContiguityError::Gap {
+        expected: 2,
+        found: 3
+      }),
+      Log::from_segments(
+        vec![segment(0, &["a", "b"]), segment(3, &["d"])],
+        Config::default()
+      )
+      .unwrap_err()
+      .downcast_ref::<ContiguityError>()
+    );
+    assert_eq!(
+      Some(&CommitLogError::NoSegments),
+      Log::from_segments(Vec::new(), Config::default())
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+
+    let mut log = Log::from_segments(
+      vec![
+        segment(0, &["a", "b"]),
+        segment(2, &["c", "d"]),
+        segment(4, &["e"]),
+      ],
+      Config::default(),
+    )
+    .unwrap();
+
+    for (offset, value) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+      assert_eq!(value.as_bytes(), log.read(offset as u64).unwrap().value);
+    }
+
+    // Records are appended to the newest segment.
+    assert_eq!(5, log.append("f".as_bytes().to_vec()).unwrap());
+    assert_eq!(
+      4,
+      log.segments[log.active_segment]
+        .read()
+        .unwrap()
+        .base_offset()
+    );
+
+    // Removes the first segment, the ones after it contain offset 3.
+    log.truncate(3).unwrap();
+
+    assert_eq!(2, log.lowest_offset());
+    assert_eq!(6, log.highest_offset());
+    assert_eq!("f".as_bytes(), log.read(5).unwrap().value);
+  }
+
+  #[test_log::test]
+  fn segments_can_be_rebuilt_out_of_order() {
+    let mut log = new_log();
+
+    let record = |offset: u64| api::v1::Record {
+      value: vec![offset as u8],
+      offset,
+      ..Default::default()
+    };
+
+    assert_eq!(
+      Some(&CommitLogError::NotRebuilding),
+      log
+        .append_to_segment(0, record(0))
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+
+    log.begin_rebuild();
+
+    for offset in 3..6 {
+      log.append_to_segment(3, record(offset)).unwrap();
+    }
+
+    assert_eq!(
+      Some(&CommitLogError::UnexpectedRebuildOffset {
+        base_offset: 0,
+        expected: 0,
+        found: 1
+      }),
+      log
+        .append_to_segment(0, record(1))
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+
+    // The segments have a gap until the first one is rebuilt.
+    assert!(log.end_rebuild().is_err());
+
+    for offset in 0..3 {
+      log.append_to_segment(0, record(offset)).unwrap();
+    }
+
+    assert_eq!(
+      Some(&CommitLogError::OffsetOutsideSegment {
+        base_offset: 0,
+        offset: 3
+      }),
+      log
+        .append_to_segment(0, record(3))
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+
+    log.end_rebuild().unwrap();
+
+    for offset in 0..6 {
+      assert_eq!(record(offset), log.read(offset).unwrap());
+    }
+
+    assert_eq!(6, log.append(vec![6]).unwrap());
+  }
+
+  #[test_log::test]
+  fn log_stays_in_its_directory_when_relocate_fails() {
+    let old_directory = tempfile::tempdir().unwrap();
+    let new_directory = tempfile::tempdir().unwrap();
+    let new_directory = new_directory.path().join("log");
+
+    let mut log = Log::new(
+      old_directory.path().to_str().unwrap().to_owned(),
+      Config::default(),
+    )
+    .unwrap();
+
+    log.append("a".as_bytes().to_vec()).unwrap();
+
+    // The default bad file policy is strict, so the
+    // segments cannot be opened in the new directory.
+    std::fs::write(old_directory.path().join("garbage.store"), b"garbage").unwrap();
+
+    assert!(log.relocate(new_directory.to_str().unwrap()).is_err());
+
+    assert!(!new_directory.exists());
+    assert!(old_directory.path().join("0.store").exists());
+    assert_eq!(old_directory.path().to_str().unwrap(), log.directory);
+
+    assert_eq!("a".as_bytes().to_vec(), log.read(0).unwrap().value);
+    assert_eq!(1, log.append("b".as_bytes().to_vec()).unwrap());
+
+    std::fs::remove_file(old_directory.path().join("garbage.store")).unwrap();
+
+    log.close().unwrap();
+
+    let log = Log::new(
+      old_directory.path().to_str().unwrap().to_owned(),
+      Config::default(),
+    )
+    .unwrap();
+
+    assert_eq!("b".as_bytes().to_vec(), log.read(1).unwrap().value);
+  }
+
+  #[test_log::test]
+  fn relocate_fails_if_the_new_directory_is_not_empty() {
+    let mut log = new_log();
+
+    log.append(vec![0]).unwrap();
+
+    let new_directory = tempfile::tempdir().unwrap();
+    std::fs::write(new_directory.path().join("0.store"), "").unwrap();
+
+    let new_directory = new_directory.path().to_str().unwrap();
+
+    assert_eq!(
+      Some(&CommitLogError::RelocationTargetNotEmpty(
+        new_directory.to_owned()
+      )),
+      log
+        .relocate(new_directory)
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+
+    assert_eq!(vec![0], log.read(0).unwrap().value);
+  }
+
+  #[test_log::test]
+  fn copy_then_rename_copies_every_file_and_removes_the_original() {
+    let from = tempfile::tempdir().unwrap().into_path();
+    let to = tempfile::tempdir().unwrap().into_path().join("log");
+
+    std::fs::write(from.join("0.store"), "hello").unwrap();
+    std::fs::create_dir(from.join(QUARANTINE_DIRECTORY)).unwrap();
+    std::fs::write(from.join(QUARANTINE_DIRECTORY).join("abc.store"), "world").unwrap();
+
+    copy_then_rename(&from, &to).unwrap();
+
+    assert!(!from.exists());
+    assert_eq!(
+      "hello",
+      std::fs::read_to_string(to.join("0.store")).unwrap()
+    );
+    assert_eq!(
+      "world",
+      std::fs::read_to_string(to.join(QUARANTINE_DIRECTORY).join("abc.store")).unwrap()
+    );
+  }
+
+  #[test_log::test]
+  fn is_new_is_only_true_when_there_were_no_segments_to_recover() {
+    let mut log = Log::new(
+      tempfile::tempdir()
+        .unwrap()
+        .into_path()
+        .to_str()
+        .unwrap()
+        .to_owned(),
+      Config {
+        max_store_bytes_per_segment: 64,
+        ..Config::default()
+      },
+    )
+    .unwrap();
+
+    assert!(log.is_new());
+
+    // 3 records per segment, 2 segments.
+    for i in 0..4 {
+      log.append(vec![i]).unwrap();
+    }
+
+    let directory = log.directory.clone();
+    let config = log.config.clone();
+
+    log.close().unwrap();
+
+    let log = Log::new(directory, config).unwrap();
+
+    assert!(!log.is_new());
+    assert_eq!(2, log.segments.len());
+  }
+
+  /// Keeps the fields of every event named "log opened".
+  struct LogOpenedEvents(std::sync::Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+  impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for LogOpenedEvents {
+    fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+      struct Fields(HashMap<String, String>);
+
+      impl tracing::field::Visit for Fields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+          self
+            .0
+            .insert(field.name().to_owned(), format!("{:?}", value));
+        }
+      }
+
+      let mut fields = Fields(HashMap::new());
+
+      event.record(&mut fields);
+
+      if fields.0.get("message").map(String::as_str) == Some("log opened") {
+        self.0.lock().unwrap().push(fields.0);
+      }
+    }
+  }
+
+  /// Opens the log in directory, closes it and returns
+  /// the fields of the "log opened" event it emitted.
+  fn log_opened_event(directory: String, config: Config) -> HashMap<String, String> {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let events = std::sync::Arc::new(Mutex::new(Vec::new()));
+
+    let subscriber =
+      tracing_subscriber::registry().with(LogOpenedEvents(std::sync::Arc::clone(&events)));
+
+    tracing::subscriber::with_default(subscriber, || {
+      Log::new(directory, config).unwrap().close().unwrap()
+    });
+
+    let mut events = events.lock().unwrap();
+
+    assert_eq!(1, events.len());
+
+    events.pop().unwrap()
+  }
+
+  fn event_fields(fields: &[(&str, &str)]) -> HashMap<String, String> {
+    fields
+      .iter()
+      .map(|(name, value)| (name.to_string(), value.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn log_new_summarizes_the_recovered_state() {
+    let mut log = new_log();
+
+    for i in 0..3 {
+      log.append(vec![i]).unwrap();
+    }
+
+    let directory = log.directory.clone();
+    let config = log.config.clone();
+
+    log.close().unwrap();
+
+    assert_eq!(
+      event_fields(&[
+        ("message", "log opened"),
+        ("segments", "1"),
+        ("lowest_offset", "0"),
+        ("highest_offset", "3"),
+        // 8 bytes for the length + 4 bytes for the crc32 for each record,
+        // 12 bytes for the first record and 14 bytes for each one of
+        // the others, 12 bytes per index entry.
+        ("total_bytes", "112"),
+        ("reopened", "true"),
+        ("recovered", "false"),
+        ("replayed_append", "false"),
+        ("truncated_records", "0"),
+        ("truncated_bytes", "0"),
+      ]),
+      log_opened_event(directory.clone(), config.clone())
+    );
+
+    // The last record does not match its crc32.
+    let store_path = Path::new(&directory).join("0.store");
+    let mut store = std::fs::read(&store_path).unwrap();
+    *store.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&store_path, store).unwrap();
+
+    assert_eq!(
+      event_fields(&[
+        ("message", "log opened"),
+        ("segments", "1"),
+        ("lowest_offset", "0"),
+        ("highest_offset", "2"),
+        // The last record and its index entry are gone.
+        ("total_bytes", "74"),
+        ("reopened", "true"),
+        ("recovered", "true"),
+        ("replayed_append", "false"),
+        ("truncated_records", "1"),
+        ("truncated_bytes", "26"),
+      ]),
+      log_opened_event(
+        directory,
+        Config {
+          recover_on_open: true,
+          ..config
+        }
+      )
+    );
+  }
+
+  #[test_log::test]
+  fn write_ahead_log_finishes_appends_interrupted_by_a_crash() {
+    let directory = tempfile::tempdir().unwrap();
+    let directory = directory.path().to_str().unwrap().to_owned();
+
+    let config = Config::builder().write_ahead_log(true).build();
+
+    let mut log = Log::new(directory.clone(), config.clone()).unwrap();
+
+    log.append("a".as_bytes().to_vec()).unwrap();
+
+    log.close().unwrap();
+
+    let record = |offset: u64, value: &str| {
+      segment::encode_record(&api::v1::Record {
+        value: value.as_bytes().to_vec(),
+        offset,
+        ..Default::default()
+      })
+    };
+
+    // Crash after the record reached the store but before the index
+    // entry was written: the store has the entry, the index does not.
+    let bytes = record(1, "b");
+
+    let mut store = std::fs::OpenOptions::new()
+      .append(true)
+      .open(Path::new(&directory).join("0.store"))
+      .unwrap();
+    store
+      .write_all(&(bytes.len() as u64).to_be_bytes())
+      .unwrap();
+    store
+      .write_all(&crc32fast::hash(&bytes).to_be_bytes())
+      .unwrap();
+    store.write_all(&bytes).unwrap();
+
+    WriteAheadLog::new(&directory)
+      .unwrap()
+      .write(&wal::Entry {
+        base_offset: 0,
+        offset: 1,
+        // 8 bytes for the length + 4 bytes for the crc32
+        // + 12 bytes for the first record.
+        position: 24,
+        bytes,
+      })
+      .unwrap();
+
+    let mut log = Log::new(directory.clone(), config.clone()).unwrap();
+
+    assert_eq!("b".as_bytes().to_vec(), log.read(1).unwrap().value);
+    assert_eq!(2, log.append("c".as_bytes().to_vec()).unwrap());
+
+    log.close().unwrap();
+
+    // Crash before anything was written.
+    WriteAheadLog::new(&directory)
+      .unwrap()
+      .write(&wal::Entry {
+        base_offset: 0,
+        offset: 3,
+        position: 37,
+        bytes: record(3, "d"),
+      })
+      .unwrap();
+
+    let log = Log::new(directory, config).unwrap();
+
+    for (offset, value) in [(0, "a"), (1, "b"), (2, "c"), (3, "d")] {
+      assert_eq!(value.as_bytes().to_vec(), log.read(offset).unwrap().value);
+    }
+
+    assert_eq!(4, log.highest_offset());
+  }
+
+  #[test_log::test]
+  fn offset_spans_match_the_offsets_of_the_segments_of_an_open_log() {
+    let directory = tempfile::tempdir()
+      .unwrap()
+      .into_path()
+      .to_str()
+      .unwrap()
+      .to_owned();
+
+    let mut log = Log::new(
+      directory.clone(),
+      Config {
+        max_store_bytes_per_segment: 64,
+        max_index_bytes_per_segment: 1024,
+        ..Config::default()
+      },
+    )
+    .unwrap();
+
+    let spans = |log: &Log| -> Vec<Range<u64>> {
+      log
+        .segments
+        .iter()
+        .map(|segment| segment.read().unwrap())
+        .map(|segment| segment.base_offset()..segment.next_offset())
+        .collect()
+    };
+
+    // The index file of the empty segment is zeroed.
+    assert_eq!(vec![0..0], Log::offset_spans(&directory).unwrap());
+
+    for _ in 0..10 {
+      log.append("a".as_bytes().to_vec()).unwrap();
+
+      // Flush the store so the first record of a segment is on disk.
+      log.segments[log.active_segment]
+        .read()
+        .unwrap()
+        .flush()
+        .unwrap();
+
+      assert_eq!(spans(&log), Log::offset_spans(&directory).unwrap());
+    }
+
+    // Records do not fit in a single segment.
+    assert!(log.segments.len() > 1);
+  }
+
+  #[test_log::test]
+  fn offset_spans_match_the_offsets_of_the_opened_segments() {
+    let directory = tempfile::tempdir()
+      .unwrap()
+      .into_path()
+      .to_str()
+      .unwrap()
+      .to_owned();
+
+    let config = Config {
+      initial_offset: 0,
+      max_store_bytes_per_segment: 64,
+      max_index_bytes_per_segment: 1024,
+      ..Config::default()
+    };
+
+    let mut log = Log::new(directory.clone(), config.clone()).unwrap();
+
+    for _ in 0..10 {
+      log.append("a".as_bytes().to_vec()).unwrap();
+    }
+
+    // Ensure index files are truncated to their entries.
+    log.close().unwrap();
+
+    let spans = Log::offset_spans(&directory).unwrap();
+
+    let log = Log::new(directory, config).unwrap();
+
+    // Records do not fit in a single segment.
+    assert!(log.segments.len() > 1);
+
+    let expected: Vec<Range<u64>> = log
+      .segments
+      .iter()
+      .map(|segment| segment.read().unwrap())
+      .map(|segment| segment.base_offset()..segment.next_offset())
+      .collect();
+
+    assert_eq!(expected, spans);
+  }
+
+  /// Returns a directory containing a store file whose name is not an offset.
+  fn directory_with_bad_segment_file() -> String {
+    let directory = tempfile::tempdir()
+      .unwrap()
+      .into_path()
+      .to_str()
+      .unwrap()
+      .to_owned();
+
+    std::fs::write(Path::new(&directory).join("garbage.store"), "").unwrap();
+
+    directory
+  }
 
-    let _lock = self.lock.write().unwrap();
+  fn config_with_bad_file_policy(bad_file_policy: BadFilePolicy) -> Config {
+    Config::builder().bad_file_policy(bad_file_policy).build()
+  }
 
-    let mut end_index = 0;
+  #[test_log::test]
+  fn strict_bad_file_policy_fails_naming_the_bad_file() {
+    let directory = directory_with_bad_segment_file();
 
-    // Find index of the last segment that does not pass the threshold.
-    for (i, segment) in self.segments.iter().enumerate() {
-      if segment.next_offset() <= lowest + 1 {
-        end_index = i;
-      }
+    let error = Log::new(
+      directory,
+      config_with_bad_file_policy(BadFilePolicy::Strict),
+    )
+    .unwrap_err();
+
+    assert_eq!(
+      Some(&CommitLogError::BadSegmentFile(String::from(
+        "garbage.store"
+      ))),
+      error.downcast_ref::<CommitLogError>()
+    );
+  }
+
+  #[test_log::test]
+  fn skip_bad_file_policy_ignores_the_bad_file() {
+    let directory = directory_with_bad_segment_file();
+
+    let mut log = Log::new(
+      directory.clone(),
+      config_with_bad_file_policy(BadFilePolicy::Skip),
+    )
+    .unwrap();
+
+    assert_eq!(0, log.append("a".as_bytes().to_vec()).unwrap());
+    assert!(Path::new(&directory).join("garbage.store").exists());
+  }
+
+  #[test_log::test]
+  fn quarantine_bad_file_policy_moves_the_bad_file_to_the_quarantine_directory() {
+    let directory = directory_with_bad_segment_file();
+
+    let mut log = Log::new(
+      directory.clone(),
+      config_with_bad_file_policy(BadFilePolicy::Quarantine),
+    )
+    .unwrap();
+
+    assert_eq!(0, log.append("a".as_bytes().to_vec()).unwrap());
+    assert!(!Path::new(&directory).join("garbage.store").exists());
+    assert!(Path::new(&directory)
+      .join(QUARANTINE_DIRECTORY)
+      .join("garbage.store")
+      .exists());
+  }
+
+  #[test_log::test]
+  fn records_bigger_than_the_max_store_size_get_a_segment_of_their_own() {
+    let mut log = Log::new_in_memory(Config {
+      max_store_bytes_per_segment: 16,
+      ..Config::default()
+    })
+    .unwrap();
+
+    for i in 0..3 {
+      assert_eq!(i, log.append(vec![i as u8; 100]).unwrap());
     }
 
-    // TODO: does drain change element order?
-    for segment in self.segments.drain(0..end_index) {
-      segment.remove()?;
+    // One segment per record plus the empty active segment.
+    assert_eq!(4, log.segments.len());
+
+    for (i, segment) in log.segments.iter().enumerate() {
+      assert_eq!(i as u64, segment.read().unwrap().base_offset());
     }
 
-    Ok(())
+    for i in 0..3 {
+      assert_eq!(vec![i as u8; 100], log.read(i).unwrap().value);
+    }
   }
 
-  /// Creates a new segment, appends it to the list of segments
-  /// and makes it the active segment.
-  pub fn new_segment(&mut self, offset: u64) -> Result<()> {
-    info!("creating new segment at offset {}", offset);
+  #[test_log::test]
+  fn log_cannot_be_created_with_segments_that_cannot_hold_an_index_entry() {
+    let config = Config {
+      max_index_bytes_per_segment: 8,
+      ..Config::default()
+    };
+
+    assert_eq!(
+      Some(&ConfigError::SegmentTooSmall {
+        max_index_bytes: 8,
+        min: 12,
+      }),
+      Log::new_in_memory(config)
+        .unwrap_err()
+        .downcast_ref::<ConfigError>()
+    );
+  }
 
-    let segment = Segment::new(
-      &self.directory,
-      self.config.initial_offset + offset,
-      // TODO: use actual config
-      segment::Config {
-        max_index_bytes: self.config.max_index_bytes_per_segment,
-        max_store_bytes: self.config.max_store_bytes_per_segment,
-        initial_offset: offset,
+  #[test_log::test]
+  fn appended_records_are_buffered_until_the_store_is_flushed() {
+    let mut log = new_log();
+
+    log.append("a".as_bytes().to_vec()).unwrap();
+
+    // 8 bytes for the length + 4 bytes for the crc32
+    // + 12 bytes for the encoded record.
+    assert_eq!(
+      ActiveSegmentDebug {
+        next_offset: 1,
+        buffered_bytes: 24,
       },
-    )?;
+      log.active_segment_debug()
+    );
 
-    self.segments.push(segment);
-    self.active_segment = self.segments.len() - 1;
+    // Reads flush the store before reading from the file.
+    log.read(0).unwrap();
 
-    Ok(())
+    assert_eq!(0, log.active_segment_debug().buffered_bytes);
   }
-}
 
-#[cfg(test)]
-mod tests {
-  use super::*;
-  use tempfile;
+  #[test_log::test]
+  fn lowest_offset_returns_base_offset_of_the_first_segment() {
+    let mut log = new_log();
 
-  fn new_log() -> Log {
-    Log::new(
-      tempfile::tempdir()
-        .unwrap()
-        .into_path()
-        .to_str()
-        .unwrap()
-        .to_owned(),
-      Config::default(),
-    )
-    .unwrap()
+    assert_eq!(log.config.initial_offset, log.lowest_offset());
+
+    log.new_segment(log.config.initial_offset + 1).unwrap();
+
+    assert_eq!(log.config.initial_offset, log.lowest_offset());
   }
 
   #[test_log::test]
-  fn append_then_read() {
+  fn highest_offset_returns_the_next_offset_that_will_be_used_by_the_newest_segment() {
     let mut log = new_log();
 
-    let tests = vec![("a", 0), ("b", 1), ("c", 2)];
+    // The last used offset of the last segment will be the initial offset
+    // because the log is empty.
+    assert_eq!(log.config.initial_offset, log.highest_offset());
 
-    for (input, expected_offset) in tests {
-      let input = input.as_bytes().to_vec();
+    log.append("hello world".as_bytes().to_vec()).unwrap();
 
-      let offset = log.append(input.clone()).unwrap();
+    assert_eq!(log.config.initial_offset + 1, log.highest_offset());
+  }
 
-      assert_eq!(expected_offset, offset);
+  #[test_log::test]
+  fn disk_usage_distinguishes_allocated_and_live_index_bytes() {
+    let directory = tempfile::tempdir().unwrap();
 
-      assert_eq!(
-        api::v1::Record {
-          offset: expected_offset,
-          value: input,
-        },
-        log.read(offset).unwrap(),
-      );
+    let mut log = Log::new(
+      directory.path().to_str().unwrap().to_owned(),
+      Config {
+        max_store_bytes_per_segment: 64,
+        max_index_bytes_per_segment: 1024,
+        ..Config::default()
+      },
+    )
+    .unwrap();
+
+    // 3 records per segment, 3 segments.
+    for i in 0..8 {
+      log.append(vec![i]).unwrap();
     }
+
+    // Index files are grown to their max size while they are open.
+    let index_file_bytes = std::fs::read_dir(directory.path())
+      .unwrap()
+      .map(|entry| entry.unwrap().path())
+      .filter(|path| path.extension().unwrap() == "index")
+      .map(|path| std::fs::metadata(path).unwrap().len())
+      .sum::<u64>();
+
+    assert_eq!(
+      DiskUsage {
+        // 8 bytes for the length + 4 bytes for the crc32 for each record,
+        // 12 bytes for the first record and 14 bytes for each one of the others.
+        store_bytes: 24 + 7 * 26,
+        index_allocated_bytes: 3 * 1024,
+        index_live_bytes: 8 * 12,
+      },
+      log.disk_usage()
+    );
+    assert_eq!(index_file_bytes, log.disk_usage().index_allocated_bytes);
   }
 
   #[test_log::test]
-  fn log_reuses_data_stored_on_disk_by_prior_log_instances() {
-    let mut log = new_log();
+  fn idle_active_segment_is_rolled_after_the_idle_timeout() {
+    let mut log = Log::new_in_memory(
+      Config::builder()
+        .idle_roll_timeout(Duration::from_secs(60))
+        .build(),
+    )
+    .unwrap();
 
-    let data = vec![(0, "a"), (1, "b"), (2, "c")];
+    log.append(vec![0]).unwrap();
 
-    for (_, input) in &data {
-      log.append(input.as_bytes().to_vec()).unwrap();
+    let appended_at = log.last_append_at;
+
+    assert!(!log
+      .roll_if_idle(appended_at + Duration::from_secs(59))
+      .unwrap());
+    assert_eq!(1, log.segments.len());
+
+    assert!(log
+      .roll_if_idle(appended_at + Duration::from_secs(60))
+      .unwrap());
+    assert_eq!(2, log.segments.len());
+    assert_eq!(1, log.segments[1].read().unwrap().base_offset());
+
+    // The new active segment is empty, there is nothing to roll.
+    assert!(!log
+      .roll_if_idle(appended_at + Duration::from_secs(600))
+      .unwrap());
+    assert_eq!(2, log.segments.len());
+
+    assert_eq!(1, log.append(vec![1]).unwrap());
+    assert_eq!(vec![0], log.read(0).unwrap().value);
+  }
+
+  #[test_log::test]
+  fn active_segment_remaining_bytes_shrinks_with_appends_and_resets_after_rollover() {
+    let mut log = Log::new_in_memory(Config {
+      initial_offset: 0,
+      max_store_bytes_per_segment: 64,
+      max_index_bytes_per_segment: 1024,
+      ..Config::default()
+    })
+    .unwrap();
+
+    assert_eq!(64, log.active_segment_remaining_bytes());
+
+    log.append("a".as_bytes().to_vec()).unwrap();
+
+    // 8 bytes for the length + 4 bytes for the crc32
+    // + 12 bytes for the encoded record.
+    assert_eq!(64 - 24, log.active_segment_remaining_bytes());
+
+    // Fill the active segment so a new one is created.
+    log.append(vec![0u8; 32]).unwrap();
+
+    assert_eq!(2, log.segments.len());
+    assert_eq!(64, log.active_segment_remaining_bytes());
+  }
+
+  #[test_log::test]
+  fn next_segment_files_exist_before_the_active_segment_is_maxed() {
+    let directory = tempfile::tempdir()
+      .unwrap()
+      .into_path()
+      .to_str()
+      .unwrap()
+      .to_owned();
+
+    let mut log = Log::new(
+      directory.clone(),
+      Config::builder()
+        .max_store_bytes_per_segment(64)
+        .max_index_bytes_per_segment(1024)
+        .pre_roll_percent(50)
+        .build(),
+    )
+    .unwrap();
+
+    let pre_rolled_store = Path::new(&directory).join("next.store");
+
+    // 11 bytes out of 32.
+    log.append("a".as_bytes().to_vec()).unwrap();
+
+    assert!(!pre_rolled_store.exists());
+
+    // 24 bytes out of 32.
+    log.append("b".as_bytes().to_vec()).unwrap();
+
+    assert!(pre_rolled_store.exists());
+
+    // 37 bytes out of 32, the pre-rolled segment becomes the active one.
+    log.append("c".as_bytes().to_vec()).unwrap();
+
+    assert!(!pre_rolled_store.exists());
+    assert!(Path::new(&directory).join("3.store").exists());
+    assert_eq!(
+      3,
+      log.segments[log.active_segment]
+        .read()
+        .unwrap()
+        .base_offset()
+    );
+
+    assert_eq!(3, log.append("d".as_bytes().to_vec()).unwrap());
+
+    for (offset, value) in ["a", "b", "c", "d"].into_iter().enumerate() {
+      assert_eq!(
+        value.as_bytes().to_vec(),
+        log.read(offset as u64).unwrap().value
+      );
     }
+  }
 
-    let directory = log.directory.clone();
-    let config = log.config.clone();
+  #[test_log::test]
+  fn keep_latest_only_keeps_the_latest_records() {
+    let mut log = Log::new(
+      tempfile::tempdir()
+        .unwrap()
+        .into_path()
+        .to_str()
+        .unwrap()
+        .to_owned(),
+      Config {
+        initial_offset: 0,
+        max_store_bytes_per_segment: 64,
+        max_index_bytes_per_segment: 1024,
+        ..Config::default()
+      },
+    )
+    .unwrap();
 
-    // Ensure contents are flushed to storage.
-    log.close().unwrap();
+    for i in 0..10 {
+      log.append(vec![i]).unwrap();
+    }
 
-    // Create a new log that should reuse the files that contain
-    // the data created by the first log.
-    let log = Log::new(directory, config).unwrap();
+    log.keep_latest(3).unwrap();
 
-    for (expected_offset, input) in data {
+    for offset in 0..7 {
+      assert!(log.read(offset).is_err());
+    }
+
+    for offset in 7..10 {
       assert_eq!(
         api::v1::Record {
-          offset: expected_offset,
-          value: input.as_bytes().to_vec(),
+          offset,
+          value: vec![offset as u8],
+          ..Default::default()
         },
-        log.read(expected_offset).unwrap()
+        read_untimed(&log, offset)
       );
     }
+
+    assert_eq!(7, log.lowest_offset());
+
+    // New records are appended after the kept ones.
+    assert_eq!(10, log.append(vec![10]).unwrap());
   }
 
   #[test_log::test]
-  fn lowest_offset_returns_base_offset_of_the_first_segment() {
-    let mut log = new_log();
+  fn keep_latest_keeps_every_field_and_the_offset_of_the_kept_records() {
+    let directory = tempfile::tempdir().unwrap();
+    let directory = directory.path().to_str().unwrap().to_owned();
 
-    assert_eq!(log.config.initial_offset, log.lowest_offset());
+    let mut log = Log::new(directory.clone(), Config::default())
+      .unwrap()
+      .with_offset_allocator(Box::new(SkipSeven));
 
-    log.new_segment(log.config.initial_offset + 1).unwrap();
+    // Offsets 0 to 6 and 8 to 10, in a single segment.
+    for i in 0..10 {
+      log
+        .append_record(api::v1::Record {
+          value: vec![i],
+          headers: [("trace-id".to_owned(), vec![i])].into_iter().collect(),
+          schema_id: 42,
+          ..Default::default()
+        })
+        .unwrap();
+    }
 
-    assert_eq!(log.config.initial_offset, log.lowest_offset());
+    let kept: Vec<api::v1::Record> = [6, 8, 9, 10]
+      .into_iter()
+      .map(|offset| log.read(offset).unwrap())
+      .collect();
+
+    log.keep_latest(5).unwrap();
+
+    assert_eq!(6, log.lowest_offset());
+    assert!(log.read(7).is_err());
+
+    for record in kept {
+      assert_eq!(record, log.read(record.offset).unwrap());
+    }
+
+    // The segment was rewritten starting at the oldest kept offset.
+    assert!(!Path::new(&directory).join("0.store").exists());
+    assert!(Path::new(&directory).join("6.store").exists());
+
+    assert_eq!(11, log.append(vec![11]).unwrap());
   }
 
   #[test_log::test]
-  fn highest_offset_returns_the_next_offset_that_will_be_used_by_the_newest_segment() {
+  fn verify_contiguity_reports_gaps_between_segments() {
     let mut log = new_log();
 
-    // The last used offset of the last segment will be the initial offset
-    // because the log is empty.
-    assert_eq!(log.config.initial_offset, log.highest_offset());
+    log.append(vec![0]).unwrap();
+    log.append(vec![1]).unwrap();
+    log.new_segment(2).unwrap();
 
-    log.append("hello world".as_bytes().to_vec()).unwrap();
+    assert_eq!(Ok(()), log.verify_contiguity());
 
-    assert_eq!(log.config.initial_offset + 1, log.highest_offset());
+    log.append(vec![2]).unwrap();
+    // Offsets 3 and 4 are missing.
+    log.new_segment(5).unwrap();
+
+    assert_eq!(
+      Err(ContiguityError::Gap {
+        expected: 3,
+        found: 5
+      }),
+      log.verify_contiguity()
+    );
   }
 
   #[test_log::test]
@@ -391,6 +4988,261 @@ mod tests {
     log.truncate(1).unwrap();
 
     assert_eq!(1, log.segments.len());
-    assert_eq!(2, log.segments[0].base_offset())
+    assert_eq!(2, log.segments[0].read().unwrap().base_offset());
+  }
+
+  #[test_log::test]
+  fn truncate_never_removes_the_active_segment() {
+    let mut log = Log::new_in_memory(Config {
+      max_store_bytes_per_segment: 64,
+      ..Config::default()
+    })
+    .unwrap();
+
+    // 3 records per segment, the active segment contains offsets 6 and 7.
+    for i in 0..8 {
+      log.append(vec![i]).unwrap();
+    }
+
+    assert_eq!(3, log.segments.len());
+
+    // Every record in the active segment would be removed.
+    assert_eq!(
+      Some(&CommitLogError::CannotTruncateActiveSegment(7)),
+      log
+        .truncate(7)
+        .unwrap_err()
+        .downcast_ref::<CommitLogError>()
+    );
+    assert_eq!(3, log.segments.len());
+
+    log.truncate(6).unwrap();
+
+    assert_eq!(2, log.segments.len());
+    assert_eq!(1, log.active_segment);
+
+    // The active segment is still usable.
+    assert_eq!(8, log.append(vec![8]).unwrap());
+    assert_eq!(vec![6], log.read(6).unwrap().value);
+  }
+
+  #[test_log::test]
+  fn recover_makes_a_corrupted_tail_segment_consistent() {
+    let config = Config::builder()
+      .max_store_bytes_per_segment(64)
+      .recover_on_open(true)
+      .build();
+
+    // 3 records per segment, the active segment contains offsets 6 and 7.
+    let new_log = || {
+      let directory = tempfile::tempdir().unwrap().into_path();
+
+      let mut log = Log::new(directory.to_str().unwrap().to_owned(), config.clone()).unwrap();
+
+      for i in 0..8 {
+        log.append(vec![i]).unwrap();
+      }
+
+      log.close().unwrap();
+
+      directory
+    };
+
+    let open = |directory: &Path| {
+      let mut log = Log::new(
+        directory.to_str().unwrap().to_owned(),
+        Config {
+          recover_on_open: false,
+          ..config.clone()
+        },
+      )
+      .unwrap();
+
+      let recovered = log.recover().unwrap();
+
+      (log, recovered)
+    };
+
+    // A partially written entry after the last record
+    // and an index that lost the entry of the last record.
+    let directory = new_log();
+
+    let mut store = std::fs::OpenOptions::new()
+      .append(true)
+      .open(directory.join("6.store"))
+      .unwrap();
+    store.write_all(&100_u64.to_be_bytes()).unwrap();
+    store.write_all(&[0, 0, 0, 0, 1, 2, 3, 4, 5]).unwrap();
+
+    std::fs::OpenOptions::new()
+      .write(true)
+      .open(directory.join("6.index"))
+      .unwrap()
+      .set_len(12)
+      .unwrap();
+
+    let (mut log, recovered) = open(&directory);
+
+    assert_eq!(
+      vec![SegmentRecovery {
+        base_offset: 6,
+        // 8 bytes for the length + 4 bytes for the crc32 + 5 bytes.
+        truncated_store_bytes: 17,
+        rebuilt_index: true,
+        previous_next_offset: 7,
+        next_offset: 8,
+      }],
+      recovered
+    );
+    assert_eq!(vec![7], read_untimed(&log, 7).value);
+    assert_eq!(8, log.append(vec![8]).unwrap());
+    assert_eq!(Ok(()), log.verify_contiguity());
+
+    // The last record does not match its crc32 and the index
+    // was not truncated to its entries before the crash.
+    let directory = new_log();
+
+    let mut store = std::fs::read(directory.join("6.store")).unwrap();
+    *store.last_mut().unwrap() ^= 0xff;
+    std::fs::write(directory.join("6.store"), store).unwrap();
+
+    std::fs::OpenOptions::new()
+      .write(true)
+      .open(directory.join("6.index"))
+      .unwrap()
+      .set_len(1024)
+      .unwrap();
+
+    let (mut log, recovered) = open(&directory);
+
+    assert_eq!(
+      vec![SegmentRecovery {
+        base_offset: 6,
+        // 8 bytes for the length + 4 bytes for the crc32 + 14 bytes for the record.
+        truncated_store_bytes: 26,
+        rebuilt_index: true,
+        previous_next_offset: 7,
+        next_offset: 7,
+      }],
+      recovered
+    );
+    assert!(log.read(7).is_err());
+    assert_eq!(7, log.append(vec![9]).unwrap());
+    assert_eq!(vec![9], read_untimed(&log, 7).value);
+
+    // Recovering a consistent log changes nothing.
+    log.close().unwrap();
+
+    let (log, recovered) = open(&directory);
+
+    assert!(recovered.is_empty());
+    assert_eq!(8, log.highest_offset());
+
+    // The log can recover when it is opened.
+    let directory = new_log();
+
+    std::fs::OpenOptions::new()
+      .write(true)
+      .open(directory.join("6.index"))
+      .unwrap()
+      .set_len(12)
+      .unwrap();
+
+    let log = Log::new(directory.to_str().unwrap().to_owned(), config.clone()).unwrap();
+
+    assert_eq!(8, log.highest_offset());
+    assert_eq!(vec![7], read_untimed(&log, 7).value);
+  }
+
+  #[test_log::test]
+  fn readers_run_in_parallel_with_a_writer_under_a_read_write_lock() {
+    let log = std::sync::RwLock::new(
+      Log::new_in_memory(Config {
+        max_store_bytes_per_segment: 64,
+        ..Config::default()
+      })
+      .unwrap(),
+    );
+
+    let records: u64 = 300;
+
+    std::thread::scope(|scope| {
+      scope.spawn(|| {
+        for i in 0..records {
+          let offset = log
+            .write()
+            .unwrap()
+            .append(i.to_be_bytes().to_vec())
+            .unwrap();
+
+          assert_eq!(i, offset);
+        }
+      });
+
+      for _ in 0..4 {
+        scope.spawn(|| loop {
+          let log = log.read().unwrap();
+
+          // Every record appended so far can be read.
+          let highest_offset = log.highest_offset();
+
+          for offset in 0..highest_offset {
+            assert_eq!(
+              offset.to_be_bytes().to_vec(),
+              log.read(offset).unwrap().value
+            );
+          }
+
+          if highest_offset == records {
+            break;
+          }
+        });
+      }
+    });
+  }
+
+  #[test_log::test]
+  fn segments_are_read_without_holding_up_appends_or_reads_of_other_segments() {
+    let log = std::sync::RwLock::new(
+      Log::new_in_memory(Config {
+        max_store_bytes_per_segment: 64,
+        ..Config::default()
+      })
+      .unwrap(),
+    );
+
+    // 3 records per segment, 2 segments.
+    for i in 0..6 {
+      log.write().unwrap().append(vec![i]).unwrap();
+    }
+
+    let (read_started, wait_for_read) = mpsc::channel();
+    let (finish_read, read_finished) = mpsc::channel::<()>();
+
+    let log = &log;
+
+    std::thread::scope(|scope| {
+      scope.spawn(move || {
+        // The lock around the log is only held to get the segment.
+        let segment = log.read().unwrap().segment_for(1).unwrap();
+
+        let segment = segment.read().unwrap();
+
+        read_started.send(()).unwrap();
+
+        // Stands in for a slow read of the first segment.
+        read_finished.recv().unwrap();
+
+        assert_eq!(vec![1], segment.read(1).unwrap().value);
+      });
+
+      wait_for_read.recv().unwrap();
+
+      // Neither has to wait for the read of the first segment.
+      assert_eq!(vec![4], log.read().unwrap().read(4).unwrap().value);
+      assert_eq!(6, log.write().unwrap().append(vec![6]).unwrap());
+
+      finish_read.send(()).unwrap();
+    });
   }
 }
@@ -0,0 +1,211 @@
+/// Implements the gRPC health checking protocol.
+///
+/// The health of the empty service name is the liveness of the
+/// process and is always `SERVING`. The health of the log service
+/// is its readiness and is only `SERVING` once the log has been
+/// recovered, so load balancers don't send requests to a server
+/// that is still opening its log.
+use std::{
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+
+use anyhow::Result;
+use tokio::sync::oneshot;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+  api::health::v1::{
+    health_check_response::ServingStatus, health_server::HealthServer, HealthCheckRequest,
+    HealthCheckResponse,
+  },
+  commit_log::Log,
+};
+
+/// Service name used to ask whether the process is up.
+pub const LIVENESS_SERVICE: &str = "";
+
+/// Service name used to ask whether the log accepts requests.
+pub const READINESS_SERVICE: &str = "log.v1.Log";
+
+#[derive(Debug, Clone, Default)]
+pub struct HealthService {
+  ready: Arc<AtomicBool>,
+}
+
+impl HealthService {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn is_ready(&self) -> bool {
+    self.ready.load(Ordering::SeqCst)
+  }
+
+  pub fn set_ready(&self, ready: bool) {
+    self.ready.store(ready, Ordering::SeqCst);
+  }
+
+  /// Runs recovery, which opens the log, while only the health
+  /// service is served by server at address and reports the log
+  /// as not ready.
+  ///
+  /// The health service is stopped once recovery returns, so the
+  /// server that serves the log can take over address, and the log
+  /// is marked as ready if it was opened.
+  pub async fn serve_while_recovering<F>(
+    &self,
+    mut server: Server,
+    address: SocketAddr,
+    recovery: F,
+  ) -> Result<Log>
+  where
+    F: FnOnce() -> Result<Log> + Send + 'static,
+  {
+    let (recovered_sender, recovered) = oneshot::channel::<()>();
+
+    let server = tokio::spawn(
+      server
+        .add_service(HealthServer::new(self.clone()))
+        .serve_with_shutdown(address, async {
+          let _ = recovered.await;
+        }),
+    );
+
+    // Recovery reads files, it is kept away from the threads that serve requests.
+    let log = tokio::task::spawn_blocking(recovery).await?;
+
+    let _ = recovered_sender.send(());
+
+    server.await??;
+
+    let log = log?;
+
+    self.set_ready(true);
+
+    Ok(log)
+  }
+
+  fn status_of(&self, service: &str) -> Option<ServingStatus> {
+    match service {
+      LIVENESS_SERVICE => Some(ServingStatus::Serving),
+      READINESS_SERVICE if self.is_ready() => Some(ServingStatus::Serving),
+      READINESS_SERVICE => Some(ServingStatus::NotServing),
+      _ => None,
+    }
+  }
+}
+
+#[tonic::async_trait]
+impl crate::api::health::v1::health_server::Health for HealthService {
+  async fn check(
+    &self,
+    request: Request<HealthCheckRequest>,
+  ) -> Result<Response<HealthCheckResponse>, Status> {
+    let service = request.into_inner().service;
+
+    match self.status_of(&service) {
+      Some(status) => Ok(Response::new(HealthCheckResponse {
+        status: status as i32,
+      })),
+      None => Err(Status::not_found(format!("unknown service {}", service))),
+    }
+  }
+
+  type WatchStream = ReceiverStream<Result<HealthCheckResponse, Status>>;
+
+  async fn watch(
+    &self,
+    _request: Request<HealthCheckRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    Err(Status::unimplemented("health can only be checked"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{api::health::v1::health_server::Health as _, commit_log};
+
+  async fn check(health: &HealthService, service: &str) -> ServingStatus {
+    let status = health
+      .check(Request::new(HealthCheckRequest {
+        service: service.to_owned(),
+      }))
+      .await
+      .unwrap()
+      .into_inner()
+      .status;
+
+    ServingStatus::from_i32(status).unwrap()
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn readiness_is_serving_only_after_recovery_finishes() {
+    let health = HealthService::new();
+
+    let (finish_recovery, recovery_finished) = std::sync::mpsc::channel::<()>();
+
+    let recovery = tokio::spawn({
+      let health = health.clone();
+
+      async move {
+        health
+          .serve_while_recovering(
+            Server::builder(),
+            "127.0.0.1:0".parse().unwrap(),
+            move || {
+              recovery_finished.recv().unwrap();
+              Log::new_in_memory(commit_log::Config::default())
+            },
+          )
+          .await
+      }
+    });
+
+    assert_eq!(
+      ServingStatus::Serving,
+      check(&health, LIVENESS_SERVICE).await
+    );
+    assert_eq!(
+      ServingStatus::NotServing,
+      check(&health, READINESS_SERVICE).await
+    );
+
+    finish_recovery.send(()).unwrap();
+
+    recovery.await.unwrap().unwrap();
+
+    assert_eq!(
+      ServingStatus::Serving,
+      check(&health, READINESS_SERVICE).await
+    );
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn readiness_stays_not_serving_if_recovery_fails() {
+    let health = HealthService::new();
+
+    assert!(health
+      .serve_while_recovering(Server::builder(), "127.0.0.1:0".parse().unwrap(), || {
+        Err(anyhow::anyhow!("corrupted segment"))
+      })
+      .await
+      .is_err());
+
+    assert_eq!(
+      ServingStatus::NotServing,
+      check(&health, READINESS_SERVICE).await
+    );
+    assert!(health
+      .check(Request::new(HealthCheckRequest {
+        service: String::from("unknown"),
+      }))
+      .await
+      .is_err());
+  }
+}
@@ -24,7 +24,7 @@
 /// Secondly, in most operating systems the memory region mapped
 /// actually is the kernel's page cache, meaning that no copies need to be
 /// created in user space.
-use std::{fs::File, io::Write};
+use std::{fs::File, io::Write, os::unix::prelude::FileExt, path::Path};
 
 use anyhow::Result;
 use memmap::MmapMut;
@@ -41,11 +41,12 @@ use crate::segment;
 /// The offset is stored as 4 bytes and the position as 8.
 static OFFSET_WIDTH: u64 = 4;
 static POSITION_WIDTH: u64 = 8;
-static ENTRY_WIDTH: u64 = OFFSET_WIDTH + POSITION_WIDTH;
+pub static ENTRY_WIDTH: u64 = OFFSET_WIDTH + POSITION_WIDTH;
 
 #[derive(Debug)]
 pub struct Index {
-  file: File,
+  /// None when the index only lives in memory.
+  file: Option<File>,
   /// Contains the size of the index and
   /// where to write the next entry appended to the index.
   size: u64,
@@ -63,6 +64,21 @@ pub enum IndexError {
   IndexIsFull,
   #[error("index with len {index_len:?} does not contain offset {offset:?}")]
   OffsetOutOfBounds { offset: u64, index_len: u64 },
+  #[error("offset {offset} is {relative_offset} offsets after the base offset, which does not fit in an index entry")]
+  RelativeOffsetOverflow { offset: u64, relative_offset: u64 },
+}
+
+/// Returns offset relative to base_offset as it is stored in an index entry.
+///
+/// Returns `IndexError::RelativeOffsetOverflow` if it does
+/// not fit in the `OFFSET_WIDTH` bytes of an entry.
+pub fn relative_offset(base_offset: u64, offset: u64) -> Result<u32, IndexError> {
+  let relative_offset = offset - base_offset;
+
+  u32::try_from(relative_offset).map_err(|_| IndexError::RelativeOffsetOverflow {
+    offset,
+    relative_offset,
+  })
 }
 
 impl Index {
@@ -74,17 +90,33 @@ impl Index {
 
     // Grow file to the max index size before memory mapping it
     // because we cannot resize the file after it is memory mapped.
-    file.set_len(config.segment.max_index_bytes)?;
+    //
+    // Indexes that have been grown may already be bigger than the max size.
+    file.set_len(config.segment.max_index_bytes.max(initial_file_size))?;
 
     let mmap = unsafe { MmapMut::map_mut(&file)? };
 
     Ok(Self {
-      file,
+      file: Some(file),
       mmap,
       size: initial_file_size,
     })
   }
 
+  /// Creates an index that is not backed by a file.
+  ///
+  /// The entries live in an anonymous memory map and
+  /// are lost when the index is dropped.
+  pub fn in_memory(config: Config) -> Result<Self> {
+    let mmap = MmapMut::map_anon(config.segment.max_index_bytes as usize)?;
+
+    Ok(Self {
+      file: None,
+      mmap,
+      size: 0,
+    })
+  }
+
   /// Returns the index size.
   ///
   /// The index size is the sum of all entries in the index.
@@ -92,24 +124,61 @@ impl Index {
     self.size
   }
 
+  /// Returns how many bytes are reserved for the index.
+  ///
+  /// The index file is grown to its max size while the index is
+  /// open, so it is usually bigger than Index::size.
+  pub fn allocated_size(&self) -> u64 {
+    self.mmap.len() as u64
+  }
+
   /// Returns how many entries the index contains.
-  fn len(&self) -> u64 {
+  pub fn len(&self) -> u64 {
     self.size / ENTRY_WIDTH
   }
 
   /// Returns true when the index contains no entries.
-  fn is_empty(&self) -> bool {
+  pub fn is_empty(&self) -> bool {
     self.size == 0
   }
 
   /// Returns true when the index has the maximum
   /// amount of entries.
-  fn is_full(&self) -> bool {
-    // TODO: fix me
-    return false;
+  pub fn is_full(&self) -> bool {
     self.size + ENTRY_WIDTH > (self.mmap.len() as u64)
   }
 
+  /// Doubles the space reserved for the index so more entries fit.
+  ///
+  /// The memory map cannot be resized, so the file is grown
+  /// and mapped again.
+  pub fn grow(&mut self) -> Result<()> {
+    let len = (self.mmap.len() as u64 * 2).max(ENTRY_WIDTH);
+
+    info!(len, "growing index");
+
+    let mmap = match &self.file {
+      Some(file) => {
+        self.mmap.flush()?;
+
+        file.set_len(len)?;
+
+        unsafe { MmapMut::map_mut(file)? }
+      }
+      None => {
+        let mut mmap = MmapMut::map_anon(len as usize)?;
+
+        mmap[..self.mmap.len()].copy_from_slice(&self.mmap);
+
+        mmap
+      }
+    };
+
+    self.mmap = mmap;
+
+    Ok(())
+  }
+
   /// Appends a new entry to the index.
   ///
   /// Each index entry is made of two values and occupy 12 bytes:
@@ -154,8 +223,7 @@ impl Index {
 
     let position_starts_at = ((offset * ENTRY_WIDTH) as usize) + OFFSET_WIDTH as usize;
 
-    let position_range =
-      position_starts_at..(position_starts_at as usize + POSITION_WIDTH as usize);
+    let position_range = position_starts_at..(position_starts_at + POSITION_WIDTH as usize);
 
     let mut buffer = [0u8; 8];
 
@@ -167,6 +235,72 @@ impl Index {
     Ok(position)
   }
 
+  /// Returns the offset contained by the nth entry.
+  pub fn offset_at(&self, n: u64) -> u32 {
+    let offset_starts_at = (n * ENTRY_WIDTH) as usize;
+
+    let mut buffer = [0u8; 4];
+
+    buffer[..]
+      .copy_from_slice(&self.mmap[offset_starts_at..offset_starts_at + OFFSET_WIDTH as usize]);
+
+    u32::from_be_bytes(buffer)
+  }
+
+  /// Returns which entry contains offset, relative to the
+  /// segment's base offset.
+  ///
+  /// The nth entry usually contains offset n, but offsets may have
+  /// gaps when they are not assigned sequentially, in which case
+  /// the entries are binary searched since their offsets increase.
+  pub fn entry_of(&self, offset: u64) -> Result<u64, IndexError> {
+    if offset < self.len() && self.offset_at(offset) as u64 == offset {
+      return Ok(offset);
+    }
+
+    let (mut low, mut high) = (0, self.len());
+
+    while low < high {
+      let middle = low + (high - low) / 2;
+
+      match (self.offset_at(middle) as u64).cmp(&offset) {
+        std::cmp::Ordering::Equal => return Ok(middle),
+        std::cmp::Ordering::Less => low = middle + 1,
+        std::cmp::Ordering::Greater => high = middle,
+      }
+    }
+
+    Err(IndexError::OffsetOutOfBounds {
+      offset,
+      index_len: self.len(),
+    })
+  }
+
+  /// Replaces the position of the entry for offset.
+  ///
+  /// Like in Index::read, offset is relative to the segment's base offset.
+  pub fn rewrite(&mut self, offset: u64, position: u64) -> Result<(), IndexError> {
+    if offset >= self.len() {
+      return Err(IndexError::OffsetOutOfBounds {
+        offset,
+        index_len: self.len(),
+      });
+    }
+
+    let position_starts_at = ((offset * ENTRY_WIDTH) as usize) + OFFSET_WIDTH as usize;
+
+    let position_range = position_starts_at..(position_starts_at + POSITION_WIDTH as usize);
+
+    self.mmap[position_range].copy_from_slice(&position.to_be_bytes());
+
+    Ok(())
+  }
+
+  /// Drops every entry after the first len entries.
+  pub fn truncate(&mut self, len: u64) {
+    self.size = self.size.min(len * ENTRY_WIDTH);
+  }
+
   // Returns the offset contained by the last index entry.
   pub fn last_offset(&self) -> Option<u32> {
     if self.is_empty() {
@@ -179,7 +313,7 @@ impl Index {
 
     let mut buffer = [0u8; 4];
 
-    // Copy position bytes(8 bytes) to buffer.
+    // Copy offset bytes(4 bytes) to buffer.
     buffer[..].copy_from_slice(&self.mmap[offset_range]);
 
     let offset = u32::from_be_bytes(buffer);
@@ -187,6 +321,83 @@ impl Index {
     Some(offset)
   }
 
+  /// Returns the offset contained by the last entry of the index
+  /// file at path.
+  ///
+  /// The file is not memory mapped and its size is not changed,
+  /// which makes it cheap to call when only offsets are needed.
+  ///
+  /// The file of an open index is grown to its max size, so it ends
+  /// with zeroed entries. Only the first entry may contain offset 0,
+  /// so the last entry is the last one with a non-zero offset, found
+  /// by binary search since offsets only grow. A grown file whose
+  /// second entry is zeroed may or may not contain the first entry,
+  /// Some(0) is returned either way.
+  pub fn read_last_offset(path: &Path) -> Result<Option<u32>> {
+    let file = File::open(path)?;
+
+    let len = file.metadata()?.len() / ENTRY_WIDTH;
+
+    if len == 0 {
+      return Ok(None);
+    }
+
+    let read_offset = |entry: u64| -> Result<u32> {
+      let mut buffer = [0u8; 4];
+
+      file.read_exact_at(&mut buffer, entry * ENTRY_WIDTH)?;
+
+      Ok(u32::from_be_bytes(buffer))
+    };
+
+    // Entries in 1..low contain non-zero offsets, entries in high..len are zeroed.
+    let (mut low, mut high) = (1, len);
+
+    while low < high {
+      let middle = low + (high - low) / 2;
+
+      if read_offset(middle)? == 0 {
+        high = middle;
+      } else {
+        low = middle + 1;
+      }
+    }
+
+    Ok(Some(read_offset(low - 1)?))
+  }
+
+  /// Syncs memory-mapped file to the persisted file.
+  pub fn flush(&self) -> Result<(), std::io::Error> {
+    self.mmap.flush()
+  }
+
+  /// Syncs memory-mapped file to the persisted file and truncates
+  /// the persisted file to the amount of data that's actually in it,
+  /// like Index::close, but keeps the index open.
+  ///
+  /// Lets the file be opened by another index while this one is
+  /// still open. Index::untrim must be called before appending to
+  /// this index again.
+  pub fn trim(&self) -> Result<(), std::io::Error> {
+    self.mmap.flush()?;
+
+    if let Some(file) = &self.file {
+      file.set_len(self.size)?;
+    }
+
+    Ok(())
+  }
+
+  /// Grows the persisted file back to the size of the memory map
+  /// after Index::trim.
+  pub fn untrim(&self) -> Result<(), std::io::Error> {
+    if let Some(file) = &self.file {
+      file.set_len(self.mmap.len() as u64)?;
+    }
+
+    Ok(())
+  }
+
   /// Syncs memory-mapped file to the persisted file,
   /// flushes persisted file contents to stable storage
   /// and truncates the persisted file to the amount of data
@@ -196,11 +407,11 @@ impl Index {
 
     self.mmap.flush()?;
 
-    self.file.set_len(self.size)?;
-
-    self.file.flush()?;
+    if let Some(mut file) = self.file.take() {
+      file.set_len(self.size)?;
 
-    drop(self.file);
+      file.flush()?;
+    }
 
     Ok(())
   }
@@ -221,9 +432,8 @@ mod tests {
       file.into_file(),
       Config {
         segment: segment::Config {
-          initial_offset: 0,
-          max_store_bytes: 0,
           max_index_bytes: 1024,
+          ..Default::default()
         },
       },
     )
@@ -240,9 +450,8 @@ mod tests {
       file_copy,
       Config {
         segment: segment::Config {
-          initial_offset: 0,
-          max_store_bytes: 0,
           max_index_bytes: 1024,
+          ..Default::default()
         },
       },
     )
@@ -260,9 +469,8 @@ mod tests {
       file_write.into_file(),
       Config {
         segment: segment::Config {
-          initial_offset: 0,
-          max_store_bytes: 0,
           max_index_bytes: 1024,
+          ..Default::default()
         },
       },
     )
@@ -308,9 +516,8 @@ mod tests {
       file_write.into_file(),
       Config {
         segment: segment::Config {
-          initial_offset: 0,
-          max_store_bytes: 0,
           max_index_bytes: 1024,
+          ..Default::default()
         },
       },
     )
@@ -345,9 +552,8 @@ mod tests {
       file_write.into_file(),
       Config {
         segment: segment::Config {
-          initial_offset: 0,
-          max_store_bytes: 0,
           max_index_bytes: 1024,
+          ..Default::default()
         },
       },
     )
@@ -366,15 +572,87 @@ mod tests {
     assert_eq!(Ok(42), index.read(4));
   }
 
+  #[test_log::test]
+  fn rewrite_replaces_the_position_of_an_existing_entry() {
+    let mut index = Index::new(
+      NamedTempFile::new().unwrap().into_file(),
+      Config {
+        segment: segment::Config {
+          max_index_bytes: 1024,
+          ..Default::default()
+        },
+      },
+    )
+    .unwrap();
+
+    index.write(0, 10).unwrap();
+    index.write(1, 20).unwrap();
+
+    index.rewrite(0, 15).unwrap();
+
+    assert_eq!(Ok(15), index.read(0));
+    assert_eq!(Ok(20), index.read(1));
+    assert_eq!(Some(1), index.last_offset());
+
+    assert_eq!(
+      Err(IndexError::OffsetOutOfBounds {
+        offset: 2,
+        index_len: 2,
+      }),
+      index.rewrite(2, 30)
+    );
+  }
+
+  #[test_log::test]
+  fn read_last_offset_skips_the_zeroed_entries_of_an_open_index() {
+    let file = NamedTempFile::new().unwrap();
+
+    let mut index = Index::new(
+      file.reopen().unwrap(),
+      Config {
+        segment: segment::Config {
+          max_index_bytes: 1024,
+          ..Default::default()
+        },
+      },
+    )
+    .unwrap();
+
+    // The file is grown to the max index size.
+    assert_eq!(1024, file.as_file().metadata().unwrap().len());
+
+    // The first entry cannot be told apart from a zeroed one.
+    assert_eq!(Some(0), Index::read_last_offset(file.path()).unwrap());
+
+    for offset in 0..10 {
+      index.write(offset, offset as u64 * 10).unwrap();
+
+      assert_eq!(Some(offset), Index::read_last_offset(file.path()).unwrap());
+    }
+
+    // A full index has no zeroed entries.
+    while !index.is_full() {
+      index.write(index.len() as u32, 0).unwrap();
+    }
+
+    assert_eq!(
+      Some(index.len() as u32 - 1),
+      Index::read_last_offset(file.path()).unwrap()
+    );
+
+    index.close().unwrap();
+
+    assert_eq!(Some(84), Index::read_last_offset(file.path()).unwrap());
+  }
+
   #[test_log::test]
   fn last_offset_returns_the_offset_contained_by_the_last_index_entry() {
     let mut index = Index::new(
       NamedTempFile::new().unwrap().into_file(),
       Config {
         segment: segment::Config {
-          initial_offset: 0,
-          max_store_bytes: 0,
           max_index_bytes: 1024,
+          ..Default::default()
         },
       },
     )
@@ -402,9 +680,8 @@ mod tests {
       NamedTempFile::new().unwrap().into_file(),
       Config {
         segment: segment::Config {
-          initial_offset: 0,
-          max_store_bytes: 0,
           max_index_bytes: 1024,
+          ..Default::default()
         },
       },
     )
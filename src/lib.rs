@@ -0,0 +1,10 @@
+pub mod api;
+pub mod auth;
+pub mod backend;
+pub mod commit_log;
+pub mod health;
+pub mod index;
+pub mod segment;
+pub mod server;
+pub mod store;
+pub mod wal;
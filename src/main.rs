@@ -1,18 +1,13 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, ops::ControlFlow, path::Path, time::Duration};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use dotenv::dotenv;
-use tonic::transport::Server;
-use tracing::info;
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tracing::{error, info, warn};
 
-use crate::commit_log::Log;
-
-mod api;
-mod commit_log;
-mod index;
-mod segment;
-mod server;
-mod store;
+use proglog::{
+  api, auth::TokenAuth, commit_log, commit_log::Log, health, segment::Segment, server,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -25,21 +20,252 @@ async fn main() -> Result<()> {
 
   tracing_subscriber::fmt::init();
 
+  let args: Vec<String> = std::env::args().skip(1).collect();
+
+  match &args[..] {
+    [] => {}
+    [command, directory, base_offset] if command == "validate-segment" => {
+      return validate_segment(directory, base_offset.parse()?);
+    }
+    [command, directory] if command == "verify-log" => {
+      return verify_log(directory);
+    }
+    _ => bail!("usage: proglog [validate-segment <dir> <base_offset> | verify-log <dir>]"),
+  }
+
   let host = std::env::var("HOST")?;
   let port = std::env::var("PORT")?.parse::<u16>()?;
   let address: SocketAddr = format!("{}:{}", host, port).parse()?;
 
-  let log_server = api::v1::log_server::LogServer::new(server::LogServer::new(Log::new(
-    String::from("./log_dir"),
-    commit_log::Config::default(),
-  )?));
+  let auth = TokenAuth::from_env()?;
+
+  let tls = tls_config_from_env()?;
+
+  if tls.is_none() {
+    warn!("TLS_CERT_PATH and TLS_KEY_PATH are not set, serving in plaintext");
+  }
+
+  let mut server = build_server(tls)?;
+
+  let health = health::HealthService::new();
+
+  info!("recovering log, serving health checks at {}", &address);
+
+  let log = health
+    .serve_while_recovering(server.clone(), address, || {
+      Log::new(String::from("./log_dir"), commit_log::Config::default())
+    })
+    .await?;
+
+  let config = server::Config::default();
+
+  let maintenance_interval = config.maintenance_interval;
+
+  let log_server = server::LogServer::new(log, config);
+
+  let maintenance = tokio::spawn(run_maintenance(log_server.clone(), maintenance_interval));
 
   info!("starting server at {}", &address);
 
-  Server::builder()
-    .add_service(log_server)
-    .serve(address)
+  server
+    .add_service(api::health::v1::health_server::HealthServer::new(health))
+    .add_service(api::v1::log_server::LogServer::with_interceptor(
+      log_server.clone(),
+      auth,
+    ))
+    .serve_with_shutdown(address, async {
+      let _ = tokio::signal::ctrl_c().await;
+    })
     .await?;
 
+  // The task has a handle to the log, which must be the last one to close it.
+  maintenance.abort();
+  let _ = maintenance.await;
+
+  info!("closing log");
+
+  log_server.shutdown().await?;
+
   Ok(())
 }
+
+/// Calls LogServer::run_maintenance every interval until the task is aborted.
+///
+/// A failed run is logged and the next one is tried anyway.
+async fn run_maintenance(log_server: server::LogServer, interval: Duration) {
+  let mut interval = tokio::time::interval(interval);
+
+  loop {
+    interval.tick().await;
+
+    if let Err(error) = log_server.run_maintenance().await {
+      error!(?error, "log maintenance failed");
+    }
+  }
+}
+
+/// Returns the server every service is served by, which
+/// uses TLS when tls is set and plaintext otherwise.
+fn build_server(tls: Option<ServerTlsConfig>) -> Result<Server> {
+  let server = Server::builder();
+
+  match tls {
+    None => Ok(server),
+    Some(tls) => Ok(server.tls_config(tls)?),
+  }
+}
+
+/// Returns the TLS config for the certificate at TLS_CERT_PATH and the
+/// key at TLS_KEY_PATH, or None when neither is set.
+fn tls_config_from_env() -> Result<Option<ServerTlsConfig>> {
+  tls_config_from_paths(
+    std::env::var("TLS_CERT_PATH").ok(),
+    std::env::var("TLS_KEY_PATH").ok(),
+  )
+}
+
+/// Returns the TLS config for the certificate and key at the paths, or None
+/// when neither is set. Setting only one of them is an error so a typo does
+/// not silently start the server in plaintext.
+fn tls_config_from_paths(
+  cert_path: Option<String>,
+  key_path: Option<String>,
+) -> Result<Option<ServerTlsConfig>> {
+  match (cert_path, key_path) {
+    (Some(cert_path), Some(key_path)) => Ok(Some(tls_config(
+      Path::new(&cert_path),
+      Path::new(&key_path),
+    )?)),
+    (Some(_), None) => bail!("TLS_CERT_PATH is set but TLS_KEY_PATH is not"),
+    (None, Some(_)) => bail!("TLS_KEY_PATH is set but TLS_CERT_PATH is not"),
+    (None, None) => Ok(None),
+  }
+}
+
+/// Returns the TLS config for the PEM encoded certificate and key at the paths.
+fn tls_config(cert_path: &Path, key_path: &Path) -> Result<ServerTlsConfig> {
+  let cert = std::fs::read(cert_path)
+    .with_context(|| format!("failed to read TLS certificate {}", cert_path.display()))?;
+
+  let key = std::fs::read(key_path)
+    .with_context(|| format!("failed to read TLS key {}", key_path.display()))?;
+
+  Ok(ServerTlsConfig::new().identity(Identity::from_pem(cert, key)))
+}
+
+/// Checks that the store and the index of a single segment agree.
+fn validate_segment(directory: &str, base_offset: u64) -> Result<()> {
+  // Opening a segment that does not exist would create it.
+  if !Path::new(directory)
+    .join(format!("{}.store", base_offset))
+    .exists()
+  {
+    bail!(
+      "there is no segment at offset {} in {}",
+      base_offset,
+      directory
+    );
+  }
+
+  let segment = Segment::new(
+    directory,
+    base_offset,
+    commit_log::Config::default().segment_config(),
+  )?;
+
+  let result = segment.validate();
+
+  segment.close()?;
+
+  let records = result?;
+
+  info!(
+    "segment {} in {} is valid, {} records checked",
+    base_offset, directory, records
+  );
+
+  Ok(())
+}
+
+/// Checks every segment of the log in directory, logging progress as it goes.
+fn verify_log(directory: &str) -> Result<()> {
+  if !Path::new(directory).is_dir() {
+    bail!("there is no log in {}", directory);
+  }
+
+  let log = Log::new(directory.to_owned(), commit_log::Config::default())?;
+
+  let result = log.verify(|progress| {
+    info!(
+      segment = progress.segment,
+      records_checked = progress.records_checked,
+      "verified {}/{} bytes",
+      progress.bytes_checked,
+      progress.bytes_total
+    );
+
+    ControlFlow::Continue(())
+  });
+
+  log.close()?;
+
+  result?;
+
+  info!("log in {} is valid", directory);
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use super::*;
+
+  fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+      .join("fixtures/tls")
+      .join(name)
+  }
+
+  #[test_log::test]
+  fn tls_config_is_built_from_pem_files() {
+    let tls = tls_config(&fixture("server.pem"), &fixture("server.key")).unwrap();
+
+    assert!(build_server(Some(tls)).is_ok());
+  }
+
+  #[test_log::test]
+  fn build_server_fails_when_the_tls_key_is_not_a_key() {
+    // The certificate is not a private key.
+    let tls = tls_config(&fixture("server.pem"), &fixture("server.pem")).unwrap();
+
+    assert!(build_server(Some(tls)).is_err());
+  }
+
+  #[test_log::test]
+  fn tls_config_fails_when_a_file_does_not_exist() {
+    assert!(tls_config(&fixture("server.pem"), &fixture("missing.key")).is_err());
+  }
+
+  #[test_log::test]
+  fn tls_config_from_paths_requires_both_paths() {
+    let cert_path = Some(fixture("server.pem").display().to_string());
+    let key_path = Some(fixture("server.key").display().to_string());
+
+    assert!(tls_config_from_paths(cert_path.clone(), key_path.clone())
+      .unwrap()
+      .is_some());
+
+    assert!(tls_config_from_paths(None, None).unwrap().is_none());
+
+    assert!(tls_config_from_paths(cert_path, None).is_err());
+
+    assert!(tls_config_from_paths(None, key_path).is_err());
+  }
+
+  #[test_log::test]
+  fn build_server_uses_plaintext_without_a_tls_config() {
+    assert!(build_server(None).is_ok());
+  }
+}
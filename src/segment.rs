@@ -1,18 +1,28 @@
 use std::{
-  fs::OpenOptions,
-  io::Cursor,
+  cmp::Ordering,
+  collections::HashMap,
+  ffi::CString,
+  fs::{File, OpenOptions},
+  os::unix::{
+    fs::OpenOptionsExt,
+    prelude::{AsRawFd, FromRawFd},
+  },
   path::{Path, PathBuf},
+  sync::RwLock,
+  time::{SystemTime, UNIX_EPOCH},
 };
 
-use tracing::{info, instrument};
+use thiserror::Error;
+use tracing::{info, instrument, warn};
 
 use anyhow::Result;
 use prost::Message;
 
 use crate::{
   api,
+  backend::{MemoryBackend, StorageBackend},
   index::{self, Index},
-  store::Store,
+  store::{Store, HEADER_WIDTH},
 };
 
 /// The segment wraps the index and store types to coordinate operations
@@ -29,46 +39,220 @@ pub struct Config {
   pub max_index_bytes: u64,
   pub max_store_bytes: u64,
   pub initial_offset: u64,
+  /// When true, a read that finds a record whose offset is not the
+  /// requested one looks for the record in the store and fixes the
+  /// index entry instead of failing.
+  pub read_repair: bool,
+  /// When true, the store file is written using direct I/O,
+  /// see `DirectBackend`. Only supported on Linux.
+  pub direct_io: bool,
+  /// When true, a full index is grown instead of making the segment
+  /// maxed, so only max_store_bytes decides when the segment is maxed.
+  pub grow_index: bool,
+  /// When set, the store flushes its buffered appends once
+  /// this many bytes are buffered, see `Store::with_flush_after_bytes`.
+  pub flush_after_bytes: Option<u64>,
+  /// Permissions of the store and index files when
+  /// they are created, the umask still applies.
+  pub file_mode: Option<u32>,
+  /// When true, records appended to new segments are stored without
+  /// their offset, which is taken from the index when they are read,
+  /// see Segment::encode. Segments that already contain records keep
+  /// the format they were written with, see `OMITTED_OFFSETS_EXTENSION`.
+  pub omit_record_offsets: bool,
+  /// When set, store entries bigger than this many bytes are not
+  /// appended, and are considered corrupted when they are read,
+  /// see `Store::with_max_entry_bytes`.
+  pub max_entry_bytes: Option<u64>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      max_index_bytes: 1024,
+      max_store_bytes: 1024,
+      initial_offset: 0,
+      read_repair: false,
+      direct_io: false,
+      grow_index: false,
+      flush_after_bytes: None,
+      file_mode: None,
+      omit_record_offsets: false,
+      max_entry_bytes: None,
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum SegmentError {
+  #[error("index entry for offset {expected} points to the record with offset {found}")]
+  OffsetMismatch { expected: u64, found: u64 },
+  #[error("cannot append at offset {offset}, offsets lower than {next_offset} have been used")]
+  OffsetTooLow { offset: u64, next_offset: u64 },
+  #[error("the store entry at position {position} is not a valid record")]
+  CorruptedRecord { position: u64 },
+  #[error("found the record with offset {found} where an offset of at least {min} was expected")]
+  OffsetOutOfOrder { min: u64, found: u64 },
+  #[error("index entry for offset {offset} points to position {index_position} but the record is at position {store_position}")]
+  IndexMismatch {
+    offset: u64,
+    index_position: u64,
+    store_position: u64,
+  },
+  #[error("the index has {index_entries} entries but the store has {store_records} records")]
+  EntryCountMismatch {
+    index_entries: u64,
+    store_records: u64,
+  },
+}
+
+/// What Segment::recover changed to make the segment consistent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentRecovery {
+  pub base_offset: u64,
+  /// Bytes removed from the end of the store because
+  /// they did not contain valid records.
+  pub truncated_store_bytes: u64,
+  /// True when the index did not match the store
+  /// and was rebuilt from it.
+  pub rebuilt_index: bool,
+  pub previous_next_offset: u64,
+  pub next_offset: u64,
+}
+
+impl SegmentRecovery {
+  /// Returns true when the segment was already consistent.
+  pub fn is_clean(&self) -> bool {
+    self.truncated_store_bytes == 0
+      && !self.rebuilt_index
+      && self.previous_next_offset == self.next_offset
+  }
 }
 
 #[derive(Debug)]
 pub struct Segment {
-  store_file_path: PathBuf,
-  index_file_path: PathBuf,
+  /// Paths are None when the segment only lives in memory.
+  store_file_path: Option<PathBuf>,
+  index_file_path: Option<PathBuf>,
+  /// Set when the segment was created by Segment::new_in,
+  /// files are removed relative to it instead of by path.
+  directory: Option<File>,
   store: Store,
-  index: Index,
+  /// Behind a lock so reads can repair index entries.
+  index: RwLock<Index>,
   /// Contains the offset used to calculate offsets relative to the
   /// offset where this segment starts.
   base_offset: u64,
   /// Contains the offset that will be used to append new records.
   next_offset: u64,
+  /// When true, every store entry is compressed, see Segment::write_compressed.
+  compressed: bool,
+  /// When true, store entries do not contain record offsets,
+  /// see Config::omit_record_offsets.
+  omits_record_offsets: bool,
   config: Config,
 }
 
+/// Name used for the files of a pre-rolled segment, they are
+/// renamed after the segment base offset once it becomes active.
+pub const PRE_ROLLED_FILE_STEM: &str = "next";
+
+/// Extension of the empty file that marks a segment
+/// whose store entries are compressed.
+const COMPRESSED_EXTENSION: &str = "compressed";
+
+/// Extension of the empty file that marks a segment whose store
+/// entries do not contain record offsets.
+///
+/// The format is recorded per segment instead of being taken from
+/// the config, so changing Config::omit_record_offsets does not
+/// change how the records already in a segment are read.
+const OMITTED_OFFSETS_EXTENSION: &str = "offsetless";
+
+/// Directory, inside the segment directory, where the compressed
+/// files of each segment are written, in a directory named after the
+/// segment, before they replace the originals.
+const COMPRESSING_DIRECTORY: &str = "compressing";
+
 impl Segment {
-  #[instrument]
   pub fn new(directory: &str, base_offset: u64, config: Config) -> Result<Self> {
-    let store_file_path = Path::new(directory).join(format!("{}.store", base_offset));
+    Self::open(directory, &base_offset.to_string(), base_offset, config)
+  }
+
+  /// Creates an empty segment whose files are ready to be used
+  /// before its base offset is known.
+  ///
+  /// Segment::rebase must be called before appending to it.
+  pub fn pre_roll(directory: &str, config: Config) -> Result<Self> {
+    // Files may have been left behind if the program did not exit cleanly.
+    for extension in ["store", "index", OMITTED_OFFSETS_EXTENSION] {
+      let path = Path::new(directory).join(format!("{}.{}", PRE_ROLLED_FILE_STEM, extension));
+
+      if path.exists() {
+        std::fs::remove_file(path)?;
+      }
+    }
+
+    Self::open(directory, PRE_ROLLED_FILE_STEM, 0, config)
+  }
+
+  #[instrument]
+  fn open(directory: &str, file_stem: &str, base_offset: u64, config: Config) -> Result<Self> {
+    let compressed = Path::new(directory)
+      .join(format!("{}.{}", file_stem, COMPRESSED_EXTENSION))
+      .exists();
+
+    // The compression was interrupted after its files were complete.
+    if compressed {
+      for extension in ["store", "index"] {
+        let file_name = format!("{}.{}", file_stem, extension);
+        let compressed_file_path =
+          compressing_directory(Path::new(directory), file_stem).join(&file_name);
+
+        if compressed_file_path.exists() {
+          std::fs::rename(compressed_file_path, Path::new(directory).join(file_name))?;
+        }
+      }
+
+      // Only the marker of omitted offsets may be left, the
+      // original one is kept next to the compressed files.
+      remove_compressing_directory(Path::new(directory), file_stem)?;
+    }
+
+    let store_file_path = Path::new(directory).join(format!("{}.store", file_stem));
 
     info!("creating store file {:?}", store_file_path);
 
-    let store_file = OpenOptions::new()
-      .read(true)
-      .create(true)
-      .append(true)
-      .open(store_file_path.clone())?;
+    let mut options = OpenOptions::new();
+
+    options.read(true).create(true);
+
+    if let Some(mode) = config.file_mode {
+      options.mode(mode);
+    }
 
-    let store = Store::new(store_file)?;
+    // Direct I/O writes at explicit positions, which O_APPEND would ignore.
+    if config.direct_io {
+      options.write(true).custom_flags(direct_io_flag()?);
+    } else {
+      options.append(true);
+    }
 
-    let index_file_path = Path::new(directory).join(format!("{}.index", base_offset));
+    let store = open_store(options.open(store_file_path.clone())?, &config)?;
+
+    let index_file_path = Path::new(directory).join(format!("{}.index", file_stem));
 
     info!("creating index file {:?}", index_file_path);
 
-    let index_file = OpenOptions::new()
-      .read(true)
-      .write(true)
-      .create(true)
-      .open(index_file_path.clone())?;
+    let mut options = OpenOptions::new();
+
+    options.read(true).write(true).create(true).truncate(false);
+
+    if let Some(mode) = config.file_mode {
+      options.mode(mode);
+    }
+
+    let index_file = options.open(index_file_path.clone())?;
 
     let index = Index::new(
       index_file,
@@ -77,6 +261,128 @@ impl Segment {
       },
     )?;
 
+    let omitted_offsets_path =
+      Path::new(directory).join(format!("{}.{}", file_stem, OMITTED_OFFSETS_EXTENSION));
+
+    let omits_record_offsets =
+      omitted_offsets_path.exists() || (config.omit_record_offsets && store.size() == 0);
+
+    if omits_record_offsets && !omitted_offsets_path.exists() {
+      File::create(omitted_offsets_path)?;
+    }
+
+    let mut segment = Self::from_parts(
+      Some(store_file_path),
+      store,
+      Some(index_file_path),
+      index,
+      base_offset,
+      config,
+    );
+
+    segment.compressed = compressed;
+    segment.omits_record_offsets = omits_record_offsets;
+
+    Ok(segment)
+  }
+
+  /// Same as Segment::new but the store and index files are created
+  /// relative to an already open directory.
+  ///
+  /// Calling `directory.sync_all()` afterwards durably records
+  /// the new files in the directory.
+  pub fn new_in(directory: &File, base_offset: u64, config: Config) -> Result<Self> {
+    let flags = if config.direct_io {
+      libc::O_RDWR | libc::O_CREAT | direct_io_flag()?
+    } else {
+      libc::O_RDWR | libc::O_CREAT | libc::O_APPEND
+    };
+
+    let mode = config.file_mode.unwrap_or(0o644);
+
+    let store_file = open_at(directory, &format!("{}.store", base_offset), flags, mode)?;
+
+    let store = open_store(store_file, &config)?;
+
+    let index_file = open_at(
+      directory,
+      &format!("{}.index", base_offset),
+      libc::O_RDWR | libc::O_CREAT,
+      mode,
+    )?;
+
+    let index = Index::new(
+      index_file,
+      index::Config {
+        segment: config.clone(),
+      },
+    )?;
+
+    let omitted_offsets_file_name = format!("{}.{}", base_offset, OMITTED_OFFSETS_EXTENSION);
+
+    let omitted_offsets_marked =
+      open_at(directory, &omitted_offsets_file_name, libc::O_RDONLY, mode).is_ok();
+
+    let omits_record_offsets =
+      omitted_offsets_marked || (config.omit_record_offsets && store.size() == 0);
+
+    if omits_record_offsets && !omitted_offsets_marked {
+      open_at(
+        directory,
+        &omitted_offsets_file_name,
+        libc::O_WRONLY | libc::O_CREAT,
+        mode,
+      )?;
+    }
+
+    let mut segment = Self::from_parts(None, store, None, index, base_offset, config);
+
+    segment.directory = Some(directory.try_clone()?);
+    segment.omits_record_offsets = omits_record_offsets;
+
+    Ok(segment)
+  }
+
+  /// Creates a segment whose store and index are kept in memory.
+  ///
+  /// Nothing is written to disk, which makes it useful for tests.
+  pub fn in_memory(base_offset: u64, config: Config) -> Result<Self> {
+    Self::with_backend(base_offset, config, Box::new(MemoryBackend::new()))
+  }
+
+  /// Creates a segment whose store keeps its entries in `backend`
+  /// and whose index is kept in memory.
+  pub fn with_backend(
+    base_offset: u64,
+    config: Config,
+    backend: Box<dyn StorageBackend>,
+  ) -> Result<Self> {
+    let store = Store::with_backend(backend)
+      .with_flush_after_bytes(config.flush_after_bytes)
+      .with_max_entry_bytes(config.max_entry_bytes.unwrap_or(u64::MAX));
+
+    let index = Index::in_memory(index::Config {
+      segment: config.clone(),
+    })?;
+
+    Ok(Self::from_parts(
+      None,
+      store,
+      None,
+      index,
+      base_offset,
+      config,
+    ))
+  }
+
+  fn from_parts(
+    store_file_path: Option<PathBuf>,
+    store: Store,
+    index_file_path: Option<PathBuf>,
+    index: Index,
+    base_offset: u64,
+    config: Config,
+  ) -> Self {
     // If the index is empty, the next offset is the the first
     // offset(the base offset).
     // if the index has entries, the next offset is the offset
@@ -86,74 +392,780 @@ impl Segment {
       None => base_offset,
     };
 
-    Ok(Segment {
+    Segment {
       base_offset,
       next_offset,
-      config,
       index_file_path,
-      index,
+      index: RwLock::new(index),
       store_file_path,
+      directory: None,
       store,
-    })
+      compressed: false,
+      omits_record_offsets: config.omit_record_offsets,
+      config,
+    }
   }
 
   /// Creates a new record and writes it to the store and
   /// to the index.
   /// The offset of the new record is returned.
   pub fn append(&mut self, value: Vec<u8>) -> Result<u64> {
-    let offset = self.next_offset;
+    self.append_with_headers(value, HashMap::new())
+  }
 
-    let record = api::v1::Record { value, offset };
+  /// Same as Segment::append but the record also contains headers.
+  pub fn append_with_headers(
+    &mut self,
+    value: Vec<u8>,
+    headers: HashMap<String, Vec<u8>>,
+  ) -> Result<u64> {
+    self.append_at(self.next_offset, value, headers)
+  }
 
-    let mut buffer = Vec::with_capacity(record.encoded_len());
-    // SAFETY: unwrap() is safe because we reserved the buffer capacity.
-    record.encode(&mut buffer).unwrap();
+  /// Same as Segment::append_with_headers but the record is
+  /// appended at offset instead of at the next offset.
+  ///
+  /// Offsets between the next offset and offset are skipped.
+  /// Returns `SegmentError::OffsetTooLow` if offset is lower
+  /// than the next offset.
+  pub fn append_at(
+    &mut self,
+    offset: u64,
+    value: Vec<u8>,
+    headers: HashMap<String, Vec<u8>>,
+  ) -> Result<u64> {
+    let record = api::v1::Record {
+      value,
+      offset,
+      headers,
+      timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+      ..Default::default()
+    };
 
-    let append_output = self.store.append(&buffer)?;
+    self.append_encoded(offset, &self.encode(&record))
+  }
 
-    self.index.write(
-      (self.next_offset - self.base_offset) as u32,
-      append_output.appended_at,
-    )?;
+  /// Encodes record the way this segment stores it.
+  ///
+  /// When record offsets are omitted, the offset is left out, which saves
+  /// its bytes in every store entry, but reads can no longer tell that an
+  /// index entry points to the wrong record, so read repair does nothing.
+  pub fn encode(&self, record: &api::v1::Record) -> Vec<u8> {
+    if !self.omits_record_offsets || record.offset == 0 {
+      return encode_record(record);
+    }
 
-    self.next_offset += 1;
+    encode_record(&api::v1::Record {
+      offset: 0,
+      ..record.clone()
+    })
+  }
+
+  /// Same as Segment::append_at but the record has already
+  /// been encoded with Segment::encode.
+  pub fn append_encoded(&mut self, offset: u64, bytes: &[u8]) -> Result<u64> {
+    if offset < self.next_offset {
+      return Err(
+        SegmentError::OffsetTooLow {
+          offset,
+          next_offset: self.next_offset,
+        }
+        .into(),
+      );
+    }
+
+    // Fail before the record reaches the store.
+    index::relative_offset(self.base_offset, offset)?;
+
+    if self.config.grow_index && self.index.get_mut().unwrap().is_full() {
+      self.index.get_mut().unwrap().grow()?;
+    }
+
+    let append_output = self.store.append(bytes)?;
+
+    self.complete_append(offset, append_output.appended_at)?;
 
     Ok(offset)
   }
 
+  /// Writes the index entry of a record that has already
+  /// been appended to the store at position.
+  ///
+  /// Used to finish appends that were interrupted after
+  /// the record reached the store.
+  pub fn complete_append(&mut self, offset: u64, position: u64) -> Result<()> {
+    self
+      .index
+      .get_mut()
+      .unwrap()
+      .write(index::relative_offset(self.base_offset, offset)?, position)?;
+
+    self.next_offset = offset + 1;
+
+    Ok(())
+  }
+
+  /// Returns true when the store entry at position contains bytes.
+  pub fn store_contains(&self, position: u64, bytes: &[u8]) -> bool {
+    matches!(self.store.read(position), Ok(entry) if entry == bytes)
+  }
+
+  /// Writes the buffered store entries and the index entries to their files.
+  pub fn flush(&self) -> Result<()> {
+    self.store.flush()?;
+    self.index.read().unwrap().flush()?;
+
+    Ok(())
+  }
+
+  /// Same as Segment::flush but also waits for the store and
+  /// the index entries to reach the disk.
+  pub fn sync(&self) -> Result<()> {
+    self.store.sync()?;
+    self.index.read().unwrap().flush()?;
+
+    Ok(())
+  }
+
+  /// Writes the buffered store entries to the store file and
+  /// truncates the index file to its entries, see Index::trim.
+  ///
+  /// After this the segment files can be opened again by another
+  /// segment while this one is still open. Segment::untrim must be
+  /// called before appending to this segment again.
+  pub fn trim(&self) -> Result<()> {
+    self.store.flush()?;
+    self.index.read().unwrap().trim()?;
+
+    Ok(())
+  }
+
+  /// Undoes Segment::trim.
+  pub fn untrim(&self) -> Result<()> {
+    self.index.read().unwrap().untrim()?;
+
+    Ok(())
+  }
+
   /// Returns the record for given offset.
+  ///
+  /// Returns `SegmentError::OffsetMismatch` if the index entry for
+  /// offset points to another record, unless read repair is enabled.
   pub fn read(&self, offset: u64) -> Result<api::v1::Record> {
-    let position = self.index.read(offset - self.base_offset)?;
+    self.read_into(offset, &mut Vec::new())
+  }
+
+  /// Same as Segment::read but the store entry is read into
+  /// buffer, so buffers can be reused across reads.
+  pub fn read_into(&self, offset: u64, buffer: &mut Vec<u8>) -> Result<api::v1::Record> {
+    let relative_offset = offset - self.base_offset;
+
+    let (entry, position) = {
+      let index = self.index.read().unwrap();
+
+      let entry = index.entry_of(relative_offset)?;
+
+      (entry, index.read(entry)?)
+    };
+
+    let mut record = self.read_record_into(position, buffer)?;
+
+    if self.omits_record_offsets {
+      record.offset = offset;
+    }
+
+    if record.offset == offset {
+      return Ok(record);
+    }
+
+    let mismatch = SegmentError::OffsetMismatch {
+      expected: offset,
+      found: record.offset,
+    };
+
+    if !self.config.read_repair {
+      return Err(mismatch.into());
+    }
+
+    warn!(
+      offset,
+      record.offset, "index entry points to the wrong record"
+    );
+
+    let (position, record) = match self.find_in_store(offset)? {
+      None => return Err(mismatch.into()),
+      Some(found) => found,
+    };
+
+    self.index.write().unwrap().rewrite(entry, position)?;
+
+    info!(offset, position, "repaired index entry");
+
+    Ok(record)
+  }
+
+  /// Decodes the record stored at position in the store.
+  ///
+  /// position must be where a store entry begins. The index is only
+  /// used to find the offset of the record when record offsets are
+  /// omitted, the offset is 0 if no index entry points to position.
+  pub fn read_record_at(&self, position: u64) -> Result<api::v1::Record> {
+    let mut record = self.read_record_into(position, &mut Vec::new())?;
+
+    if self.omits_record_offsets {
+      let index = self.index.read().unwrap();
+
+      // Entries are appended in store order, so their positions increase.
+      let (mut low, mut high) = (0, index.len());
+
+      while low < high {
+        let middle = low + (high - low) / 2;
+
+        match index.read(middle)?.cmp(&position) {
+          Ordering::Less => low = middle + 1,
+          Ordering::Greater => high = middle,
+          Ordering::Equal => {
+            record.offset = self.base_offset + index.offset_at(middle) as u64;
+            break;
+          }
+        }
+      }
+    }
+
+    Ok(record)
+  }
 
-    let bytes = self.store.read(position)?;
+  /// Returns the position the index has for offset.
+  pub fn position_of(&self, offset: u64) -> Result<u64> {
+    let index = self.index.read().unwrap();
 
-    let record = api::v1::Record::decode(&mut Cursor::new(bytes))?;
+    let entry = index.entry_of(offset - self.base_offset)?;
+
+    Ok(index.read(entry)?)
+  }
+
+  /// Returns every record in the store, from the first one,
+  /// with the position of its entry. The index is not used,
+  /// unless record offsets are omitted, then the offset of a
+  /// record is taken from the index entry with the same position
+  /// in the index as the record has in the store.
+  ///
+  /// Stops after the first entry that cannot be read.
+  pub fn records_with_positions(
+    &self,
+  ) -> impl Iterator<Item = Result<(u64, api::v1::Record)>> + '_ {
+    let mut position = 0;
+    let mut entry = 0;
+    let mut failed = false;
+
+    std::iter::from_fn(move || {
+      if failed || position >= self.store.size() {
+        return None;
+      }
+
+      let entry_position = position;
+
+      let result = self.store.read(entry_position).and_then(|bytes| {
+        position += (HEADER_WIDTH + bytes.len()) as u64;
+
+        let mut record = self.decode_entry(&bytes)?;
+
+        if self.omits_record_offsets {
+          let index = self.index.read().unwrap();
+
+          if entry < index.len() {
+            record.offset = self.base_offset + index.offset_at(entry) as u64;
+          }
+        }
+
+        Ok(record)
+      });
+
+      entry += 1;
+
+      failed = result.is_err();
+
+      Some(result.map(|record| (entry_position, record)))
+    })
+  }
+
+  /// Decodes the record stored at position, using buffer to read the store entry.
+  fn read_record_into(&self, position: u64, buffer: &mut Vec<u8>) -> Result<api::v1::Record> {
+    self.store.read_into(position, buffer)?;
+
+    self.decode_entry(buffer)
+  }
+
+  /// Decodes the record contained by a store entry.
+  fn decode_entry(&self, bytes: &[u8]) -> Result<api::v1::Record> {
+    let record = if self.compressed {
+      api::v1::Record::decode(&zstd::decode_all(bytes)?[..])?
+    } else {
+      api::v1::Record::decode(bytes)?
+    };
 
     Ok(record)
   }
 
+  /// Looks at every entry in the store, from the first one,
+  /// until the record with offset is found.
+  ///
+  /// Returns the record and its position in the store.
+  fn find_in_store(&self, offset: u64) -> Result<Option<(u64, api::v1::Record)>> {
+    for result in self.records_with_positions() {
+      let (position, record) = result?;
+
+      if record.offset == offset {
+        return Ok(Some((position, record)));
+      }
+    }
+
+    Ok(None)
+  }
+
+  /// Checks that the store and the index agree with each other.
+  ///
+  /// Every record in the store is read, from the first one, and checked
+  /// against the index entry with the same position in the index.
+  /// Offsets must increase from the base offset, gaps are allowed
+  /// because records may be appended at explicit offsets.
+  ///
+  /// Returns how many records were checked or the first problem found.
+  pub fn validate(&self) -> Result<u64, SegmentError> {
+    let index = self.index.read().unwrap();
+
+    let mut position = 0;
+    let mut entry = 0;
+    let mut min_offset = self.base_offset;
+
+    while position < self.store.size() {
+      let bytes = self
+        .store
+        .read(position)
+        .map_err(|_| SegmentError::CorruptedRecord { position })?;
+
+      let mut record = self
+        .decode_entry(&bytes)
+        .map_err(|_| SegmentError::CorruptedRecord { position })?;
+
+      // Without stored offsets, the index is the only source of offsets.
+      if self.omits_record_offsets && entry < index.len() {
+        record.offset = self.base_offset + index.offset_at(entry) as u64;
+      }
+
+      if record.offset < min_offset {
+        return Err(SegmentError::OffsetOutOfOrder {
+          min: min_offset,
+          found: record.offset,
+        });
+      }
+
+      if entry >= index.len() {
+        return Err(SegmentError::EntryCountMismatch {
+          index_entries: index.len(),
+          store_records: entry + 1,
+        });
+      }
+
+      let offset = self.base_offset + index.offset_at(entry) as u64;
+
+      if offset != record.offset {
+        return Err(SegmentError::OffsetMismatch {
+          expected: offset,
+          found: record.offset,
+        });
+      }
+
+      // entry is lower than the index length, the read cannot fail.
+      let index_position = index.read(entry).unwrap();
+
+      if index_position != position {
+        return Err(SegmentError::IndexMismatch {
+          offset,
+          index_position,
+          store_position: position,
+        });
+      }
+
+      min_offset = record.offset + 1;
+      entry += 1;
+      position += (HEADER_WIDTH + bytes.len()) as u64;
+    }
+
+    if entry != index.len() {
+      return Err(SegmentError::EntryCountMismatch {
+        index_entries: index.len(),
+        store_records: entry,
+      });
+    }
+
+    Ok(entry)
+  }
+
+  /// Makes the segment consistent after a crash: the store is
+  /// truncated after its last valid record, the index is rebuilt
+  /// from the store when they do not match and the next offset
+  /// is recomputed.
+  #[instrument(skip(self), fields(base_offset = self.base_offset))]
+  pub fn recover(&mut self) -> Result<SegmentRecovery> {
+    let index = self.index.read().unwrap();
+
+    let mut position = 0;
+    let mut min_offset = self.base_offset;
+    let mut entries = Vec::new();
+
+    // Everything after the first entry that cannot be read
+    // was being written when the crash happened.
+    while position < self.store.size() {
+      let bytes = match self.store.read(position) {
+        Ok(bytes) => bytes,
+        Err(_) => break,
+      };
+
+      let mut record = match self.decode_entry(&bytes) {
+        Ok(record) => record,
+        Err(_) => break,
+      };
+
+      // Without stored offsets, the index is the only source of offsets.
+      if self.omits_record_offsets {
+        let entry = entries.len() as u64;
+
+        if entry >= index.len() {
+          break;
+        }
+
+        record.offset = self.base_offset + index.offset_at(entry) as u64;
+      }
+
+      if record.offset < min_offset
+        || index::relative_offset(self.base_offset, record.offset).is_err()
+      {
+        break;
+      }
+
+      entries.push((record.offset, position));
+
+      min_offset = record.offset + 1;
+      position += (HEADER_WIDTH + bytes.len()) as u64;
+    }
+
+    drop(index);
+
+    let truncated_store_bytes = self.store.size() - position;
+
+    if truncated_store_bytes > 0 {
+      warn!(position, truncated_store_bytes, "truncating store");
+      self.store.truncate(position)?;
+    }
+
+    let index = self.index.get_mut().unwrap();
+
+    let index_matches = index.len() == entries.len() as u64
+      && entries
+        .iter()
+        .enumerate()
+        .all(|(entry, &(offset, position))| {
+          let entry = entry as u64;
+          self.base_offset + index.offset_at(entry) as u64 == offset
+            && index.read(entry).ok() == Some(position)
+        });
+
+    if !index_matches {
+      warn!(entries = entries.len(), "rebuilding index");
+
+      index.truncate(0);
+
+      for (offset, position) in entries.iter().copied() {
+        if index.is_full() {
+          index.grow()?;
+        }
+
+        index.write(index::relative_offset(self.base_offset, offset)?, position)?;
+      }
+    }
+
+    let previous_next_offset = self.next_offset;
+
+    self.next_offset = match entries.last() {
+      Some((offset, _)) => offset + 1,
+      None => self.base_offset,
+    };
+
+    Ok(SegmentRecovery {
+      base_offset: self.base_offset,
+      truncated_store_bytes,
+      rebuilt_index: !index_matches,
+      previous_next_offset,
+      next_offset: self.next_offset,
+    })
+  }
+
   /// Returns true when the segment has reached its max size.
   ///
   /// The segment has reached its max size if
-  /// the store is or the index are full.
+  /// the store is or the index are full,
+  /// unless the index is grown when it is full.
   pub fn is_maxed(&self) -> bool {
     self.store.size() >= self.config.max_store_bytes
-      || self.index.size() >= self.config.max_index_bytes
+      || (!self.config.grow_index
+        && self.index.read().unwrap().size() >= self.config.max_index_bytes)
+  }
+
+  /// Makes an empty segment start at base_offset, renaming
+  /// its files to match the new base offset.
+  pub fn rebase(&mut self, base_offset: u64) -> Result<()> {
+    assert_eq!(
+      self.base_offset, self.next_offset,
+      "only empty segments can be rebased"
+    );
+
+    if self.omits_record_offsets {
+      if let Some(store_file_path) = &self.store_file_path {
+        std::fs::rename(
+          store_file_path.with_extension(OMITTED_OFFSETS_EXTENSION),
+          store_file_path.with_file_name(format!("{}.{}", base_offset, OMITTED_OFFSETS_EXTENSION)),
+        )?;
+      }
+    }
+
+    for path in [&mut self.store_file_path, &mut self.index_file_path]
+      .into_iter()
+      .flatten()
+    {
+      let new_path = path.with_file_name(format!(
+        "{}.{}",
+        base_offset,
+        path.extension().unwrap().to_str().unwrap()
+      ));
+
+      info!("renaming {:?} to {:?}", path, new_path);
+
+      std::fs::rename(&path, &new_path)?;
+
+      *path = new_path;
+    }
+
+    if let Some(directory) = &self.directory {
+      let mut extensions = vec!["store", "index"];
+
+      if self.omits_record_offsets {
+        extensions.push(OMITTED_OFFSETS_EXTENSION);
+      }
+
+      for extension in extensions {
+        rename_at(
+          directory,
+          &format!("{}.{}", self.base_offset, extension),
+          &format!("{}.{}", base_offset, extension),
+        )?;
+      }
+    }
+
+    self.base_offset = base_offset;
+    self.next_offset = base_offset;
+
+    Ok(())
+  }
+
+  /// Returns true when the store or the index have used
+  /// at least percent of their max size.
+  pub fn is_filled_past(&self, percent: u64) -> bool {
+    self.store.size() * 100 >= self.config.max_store_bytes * percent
+      || (!self.config.grow_index
+        && self.index.read().unwrap().size() * 100 >= self.config.max_index_bytes * percent)
+  }
+
+  /// Returns how many bytes can still be written to the store
+  /// before the segment reaches its max size.
+  pub fn remaining_bytes(&self) -> u64 {
+    self
+      .config
+      .max_store_bytes
+      .saturating_sub(self.store.size())
+  }
+
+  /// Returns how many bytes the store contains.
+  pub fn store_size(&self) -> u64 {
+    self.store.size()
+  }
+
+  /// Returns how many bytes appended to the store have
+  /// not been written to the store file yet.
+  pub fn buffered_bytes(&self) -> u64 {
+    self.store.buffered_bytes()
+  }
+
+  /// Returns how many bytes the index entries use.
+  pub fn index_size(&self) -> u64 {
+    self.index.read().unwrap().size()
+  }
+
+  /// Returns how many bytes are reserved for the index.
+  pub fn index_allocated_size(&self) -> u64 {
+    self.index.read().unwrap().allocated_size()
   }
 
   /// Closes store and segment files
   /// and then deletes them from disk.
   pub fn remove(self) -> Result<()> {
-    info!("deleting index file {:?}", &self.index_file_path);
+    self.unlink()?;
+
+    self.close()?;
+
+    Ok(())
+  }
+
+  /// Deletes the segment files from disk without closing them,
+  /// the segment can still be read until it is dropped.
+  pub fn unlink(&self) -> Result<()> {
+    if let Some(store_file_path) = &self.store_file_path {
+      for extension in [COMPRESSED_EXTENSION, OMITTED_OFFSETS_EXTENSION] {
+        let marker_path = store_file_path.with_extension(extension);
+
+        if marker_path.exists() {
+          std::fs::remove_file(marker_path)?;
+        }
+      }
+    }
 
-    std::fs::remove_file(self.index_file_path.clone())?;
+    if let Some(index_file_path) = &self.index_file_path {
+      info!("deleting index file {:?}", index_file_path);
 
-    info!("deleting store file {:?}", &self.store_file_path);
+      std::fs::remove_file(index_file_path)?;
+    }
 
-    std::fs::remove_file(self.store_file_path.clone())?;
+    if let Some(store_file_path) = &self.store_file_path {
+      info!("deleting store file {:?}", store_file_path);
+
+      std::fs::remove_file(store_file_path)?;
+    }
+
+    if let Some(directory) = &self.directory {
+      info!(self.base_offset, "deleting index and store files");
+
+      unlink_at(directory, &format!("{}.index", self.base_offset))?;
+      unlink_at(directory, &format!("{}.store", self.base_offset))?;
+
+      if self.omits_record_offsets {
+        unlink_at(
+          directory,
+          &format!("{}.{}", self.base_offset, OMITTED_OFFSETS_EXTENSION),
+        )?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Returns the SHA-256 of the store contents.
+  ///
+  /// Segments whose stores have the same bytes have the same digest,
+  /// which makes it cheap to check that two copies of a segment match.
+  pub fn digest(&self) -> Result<[u8; 32]> {
+    Ok(self.store.digest()?)
+  }
+
+  /// Returns true when the store entries are compressed.
+  pub fn is_compressed(&self) -> bool {
+    self.compressed
+  }
+
+  /// Writes a copy of the segment with every store entry compressed
+  /// with zstd, see Segment::replace_with_compressed.
+  ///
+  /// Meant for segments that are not appended to anymore. The copy is
+  /// written to another directory, the segment files are not touched
+  /// and the segment keeps serving reads. Returns false, without writing
+  /// anything, for segments without file paths and segments that are
+  /// already compressed.
+  pub fn write_compressed(&self) -> Result<bool> {
+    let directory = match &self.store_file_path {
+      Some(path) if !self.compressed => path.parent().unwrap(),
+      _ => return Ok(false),
+    };
+
+    let file_stem = self.base_offset.to_string();
+
+    let compressing_directory = compressing_directory(directory, &file_stem);
+
+    // Left behind by a compression that was interrupted.
+    if compressing_directory.exists() {
+      std::fs::remove_dir_all(&compressing_directory)?;
+    }
+
+    std::fs::create_dir_all(&compressing_directory)?;
+
+    // The store entries are copied as they are, so they keep their format.
+    let mut compressed = Self::open(
+      compressing_directory.to_str().unwrap(),
+      &file_stem,
+      self.base_offset,
+      Config {
+        omit_record_offsets: self.omits_record_offsets,
+        ..self.config.clone()
+      },
+    )?;
+
+    {
+      let index = self.index.read().unwrap();
+
+      for entry in 0..index.len() {
+        let offset = self.base_offset + index.offset_at(entry) as u64;
+
+        let bytes = self.store.read(index.read(entry)?)?;
+
+        compressed.append_encoded(
+          offset,
+          &zstd::encode_all(&bytes[..], zstd::DEFAULT_COMPRESSION_LEVEL)?,
+        )?;
+      }
+    }
+
+    compressed.close()?;
+
+    for extension in ["store", "index"] {
+      File::open(compressing_directory.join(format!("{}.{}", file_stem, extension)))?.sync_all()?;
+    }
+
+    Ok(true)
+  }
+
+  /// Replaces the segment files with the copy written by
+  /// Segment::write_compressed and opens the segment again,
+  /// reads decompress the entries transparently.
+  ///
+  /// Once the copy is marked as complete, Segment::open finishes an
+  /// interrupted replacement, so the files are left either uncompressed
+  /// or compressed. If this fails, opening the segment again from its
+  /// directory gets whichever of the two is complete.
+  pub fn replace_with_compressed(self) -> Result<Self> {
+    let directory = match &self.store_file_path {
+      Some(path) if !self.compressed => path.parent().unwrap().to_owned(),
+      _ => return Ok(self),
+    };
+
+    let (base_offset, config) = (self.base_offset, self.config.clone());
+
+    let file_stem = base_offset.to_string();
 
     self.close()?;
 
+    // From here on, Segment::open replaces the original files
+    // with the compressed ones if they have not been replaced yet.
+    File::create(directory.join(format!("{}.{}", file_stem, COMPRESSED_EXTENSION)))?.sync_all()?;
+    File::open(&directory)?.sync_all()?;
+
+    Self::new(directory.to_str().unwrap(), base_offset, config)
+  }
+
+  /// Deletes the copy written by Segment::write_compressed
+  /// when it is not going to replace the segment files.
+  pub fn discard_compressed(&self) -> Result<()> {
+    if let Some(path) = &self.store_file_path {
+      remove_compressing_directory(path.parent().unwrap(), &self.base_offset.to_string())?;
+    }
+
     Ok(())
   }
 
@@ -161,7 +1173,7 @@ impl Segment {
   pub fn close(self) -> Result<()> {
     info!(self.base_offset, self.next_offset, "closing segment");
 
-    self.index.close()?;
+    self.index.into_inner().unwrap().close()?;
 
     self.store.close()?;
 
@@ -177,6 +1189,140 @@ impl Segment {
   pub fn next_offset(&self) -> u64 {
     self.next_offset
   }
+
+  /// Returns the directory that contains the segment files,
+  /// None when the segment only lives in memory.
+  pub fn directory(&self) -> Option<&Path> {
+    self.store_file_path.as_deref().and_then(Path::parent)
+  }
+}
+
+/// Encodes record the way it is written to the store.
+pub fn encode_record(record: &api::v1::Record) -> Vec<u8> {
+  let mut buffer = Vec::with_capacity(record.encoded_len());
+  // SAFETY: unwrap() is safe because we reserved the buffer capacity.
+  record.encode(&mut buffer).unwrap();
+  buffer
+}
+
+/// Returns the flag that opens files for direct I/O.
+#[cfg(target_os = "linux")]
+fn direct_io_flag() -> std::io::Result<libc::c_int> {
+  Ok(libc::O_DIRECT)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn direct_io_flag() -> std::io::Result<libc::c_int> {
+  Err(std::io::Error::new(
+    std::io::ErrorKind::Unsupported,
+    "direct I/O is only supported on Linux",
+  ))
+}
+
+/// Creates the store for store_file, which was opened for direct I/O
+/// when config says so.
+fn open_store(store_file: File, config: &Config) -> Result<Store> {
+  let max_entry_bytes = config.max_entry_bytes.unwrap_or(u64::MAX);
+
+  #[cfg(target_os = "linux")]
+  if config.direct_io {
+    return Ok(Store::new_direct(store_file)?.with_max_entry_bytes(max_entry_bytes));
+  }
+
+  Ok(
+    Store::new(store_file)?
+      .with_flush_after_bytes(config.flush_after_bytes)
+      .with_max_entry_bytes(max_entry_bytes),
+  )
+}
+
+/// Returns the directory where Segment::write_compressed writes
+/// the compressed copy of the segment whose files are named file_stem.
+fn compressing_directory(directory: &Path, file_stem: &str) -> PathBuf {
+  directory.join(COMPRESSING_DIRECTORY).join(file_stem)
+}
+
+/// Deletes the compressing directory of the segment whose files are
+/// named file_stem, and `COMPRESSING_DIRECTORY` once it is empty.
+fn remove_compressing_directory(directory: &Path, file_stem: &str) -> Result<()> {
+  let segment_directory = compressing_directory(directory, file_stem);
+
+  if segment_directory.exists() {
+    std::fs::remove_dir_all(segment_directory)?;
+  }
+
+  let compressing_directory = directory.join(COMPRESSING_DIRECTORY);
+
+  // Other segments may be being compressed.
+  if compressing_directory.exists() && std::fs::read_dir(&compressing_directory)?.next().is_none() {
+    std::fs::remove_dir(compressing_directory)?;
+  }
+
+  Ok(())
+}
+
+/// Opens file_name relative to directory, creating it if flags say so.
+fn open_at(
+  directory: &File,
+  file_name: &str,
+  flags: libc::c_int,
+  mode: u32,
+) -> std::io::Result<File> {
+  let file_name = CString::new(file_name)?;
+
+  // SAFETY: file_name is a valid nul terminated string and
+  // directory is an open file descriptor.
+  let fd = unsafe {
+    libc::openat(
+      directory.as_raw_fd(),
+      file_name.as_ptr(),
+      flags | libc::O_CLOEXEC,
+      mode as libc::c_uint,
+    )
+  };
+
+  if fd < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+
+  // SAFETY: fd was just opened and nothing else owns it.
+  Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Deletes file_name relative to directory.
+fn unlink_at(directory: &File, file_name: &str) -> std::io::Result<()> {
+  let file_name = CString::new(file_name)?;
+
+  // SAFETY: file_name is a valid nul terminated string and
+  // directory is an open file descriptor.
+  if unsafe { libc::unlinkat(directory.as_raw_fd(), file_name.as_ptr(), 0) } < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+
+  Ok(())
+}
+
+/// Renames from to to, both relative to directory.
+fn rename_at(directory: &File, from: &str, to: &str) -> std::io::Result<()> {
+  let from = CString::new(from)?;
+  let to = CString::new(to)?;
+
+  // SAFETY: from and to are valid nul terminated strings and
+  // directory is an open file descriptor.
+  let result = unsafe {
+    libc::renameat(
+      directory.as_raw_fd(),
+      from.as_ptr(),
+      directory.as_raw_fd(),
+      to.as_ptr(),
+    )
+  };
+
+  if result < 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+
+  Ok(())
 }
 
 /// Returns the nearest and lesser multiple of k in j.
@@ -185,7 +1331,9 @@ impl Segment {
 /// # Examples
 ///
 /// ```
-/// assert_eq!(9, nearest_multiple(9, 4));
+/// use proglog::segment::nearest_multiple;
+///
+/// assert_eq!(8, nearest_multiple(9, 4));
 /// ```
 pub fn nearest_multiple(j: u64, k: u64) -> u64 {
   (j / k) * k
@@ -194,7 +1342,15 @@ pub fn nearest_multiple(j: u64, k: u64) -> u64 {
 #[cfg(test)]
 mod tests {
   use super::*;
-  use tempfile;
+
+  /// Reads the record at offset without its timestamp,
+  /// which depends on when the record was appended.
+  fn read_untimed(segment: &Segment, offset: u64) -> api::v1::Record {
+    api::v1::Record {
+      timestamp_ms: 0,
+      ..segment.read(offset).unwrap()
+    }
+  }
 
   #[test_log::test]
   fn append_then_read() {
@@ -202,9 +1358,8 @@ mod tests {
       tempfile::tempdir().unwrap().into_path().to_str().unwrap(),
       0,
       Config {
-        initial_offset: 0,
-        max_index_bytes: 1024,
         max_store_bytes: 128,
+        ..Default::default()
       },
     )
     .unwrap();
@@ -217,8 +1372,9 @@ mod tests {
       api::v1::Record {
         value: bytes.clone(),
         offset: 0,
+        ..Default::default()
       },
-      segment.read(offset).unwrap()
+      read_untimed(&segment, offset)
     );
 
     let offset = segment.append(bytes.clone()).unwrap();
@@ -228,31 +1384,179 @@ mod tests {
         value: bytes,
         // TODO: is this correct?
         offset: 1,
+        ..Default::default()
+      },
+      read_untimed(&segment, offset)
+    );
+  }
+
+  #[test_log::test]
+  fn segment_created_in_an_open_directory_reads_back_like_one_created_by_path() {
+    let directory_path = tempfile::tempdir().unwrap().into_path();
+    let directory = File::open(&directory_path).unwrap();
+
+    let config = Config::default();
+
+    let mut segment = Segment::new_in(&directory, 16, config.clone()).unwrap();
+
+    let values = vec!["a".as_bytes().to_vec(), "bc".as_bytes().to_vec()];
+
+    for value in &values {
+      segment.append(value.clone()).unwrap();
+    }
+
+    segment.close().unwrap();
+
+    directory.sync_all().unwrap();
+
+    let segment = Segment::new(directory_path.to_str().unwrap(), 16, config).unwrap();
+
+    for (offset, value) in (16..).zip(values) {
+      assert_eq!(
+        api::v1::Record {
+          value,
+          offset,
+          ..Default::default()
+        },
+        read_untimed(&segment, offset)
+      );
+    }
+
+    let segment = Segment::new_in(&directory, 16, segment.config.clone()).unwrap();
+
+    segment.remove().unwrap();
+
+    assert!(!directory_path.join("16.store").exists());
+    assert!(!directory_path.join("16.index").exists());
+  }
+
+  fn corrupted_segment(read_repair: bool) -> Segment {
+    let mut segment = Segment::in_memory(
+      0,
+      Config {
+        read_repair,
+        ..Default::default()
       },
-      segment.read(offset).unwrap()
+    )
+    .unwrap();
+
+    for value in ["a", "b", "c"] {
+      segment.append(value.as_bytes().to_vec()).unwrap();
+    }
+
+    // Make the entry for offset 1 point to the record with offset 0.
+    segment.index.get_mut().unwrap().rewrite(1, 0).unwrap();
+
+    segment
+  }
+
+  #[test_log::test]
+  fn read_fails_when_the_index_entry_points_to_another_record() {
+    let segment = corrupted_segment(false);
+
+    assert_eq!(
+      Some(&SegmentError::OffsetMismatch {
+        expected: 1,
+        found: 0
+      }),
+      segment.read(1).unwrap_err().downcast_ref::<SegmentError>()
     );
   }
 
+  #[test_log::test]
+  fn validate_reports_the_index_entry_that_points_to_another_record() {
+    let segment = corrupted_segment(false);
+
+    // The second record starts after 8 bytes for the length,
+    // 4 bytes for the crc32 and 12 bytes for the first record.
+    assert_eq!(
+      Err(SegmentError::IndexMismatch {
+        offset: 1,
+        index_position: 0,
+        store_position: 24
+      }),
+      segment.validate()
+    );
+
+    segment.index.write().unwrap().rewrite(1, 24).unwrap();
+
+    assert_eq!(Ok(3), segment.validate());
+  }
+
+  #[test_log::test]
+  fn read_repair_fixes_the_index_entry_that_points_to_another_record() {
+    let segment = corrupted_segment(true);
+
+    for _ in 0..2 {
+      assert_eq!(
+        api::v1::Record {
+          value: "b".as_bytes().to_vec(),
+          offset: 1,
+          ..Default::default()
+        },
+        read_untimed(&segment, 1)
+      );
+    }
+
+    // The entry points to the second record: 8 bytes for the length,
+    // 4 bytes for the crc32 and 12 bytes for the first record come before it.
+    assert_eq!(Ok(24), segment.index.read().unwrap().read(1));
+  }
+
+  #[test_log::test]
+  fn appended_records_have_the_time_they_were_appended_at() {
+    let mut segment = Segment::in_memory(0, Config::default()).unwrap();
+
+    let now_ms = || {
+      SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+    };
+
+    let before_ms = now_ms();
+
+    segment.append("a".as_bytes().to_vec()).unwrap();
+    segment.append("b".as_bytes().to_vec()).unwrap();
+
+    let (first, second) = (segment.read(0).unwrap(), segment.read(1).unwrap());
+
+    assert!(before_ms <= first.timestamp_ms);
+    assert!(first.timestamp_ms <= second.timestamp_ms);
+    assert!(second.timestamp_ms <= now_ms());
+
+    // Records stored before records had a timestamp are read with 0.
+    let record = encode_record(&api::v1::Record {
+      value: "c".as_bytes().to_vec(),
+      offset: 2,
+      ..Default::default()
+    });
+
+    segment.append_encoded(2, &record).unwrap();
+
+    assert_eq!(0, segment.read(2).unwrap().timestamp_ms);
+  }
+
   #[test_log::test]
   fn test_is_maxed_returns_true_when_store_file_is_full() {
     let mut segment = Segment::new(
       tempfile::tempdir().unwrap().into_path().to_str().unwrap(),
       0,
       Config {
-        initial_offset: 0,
         max_index_bytes: 128,
         max_store_bytes: 128,
+        ..Default::default()
       },
     )
     .unwrap();
 
-    assert_eq!(false, segment.is_maxed());
+    assert!(!segment.is_maxed());
 
     // Append long big enough to make store file full.
     segment.append(vec![0u8; 128]).unwrap();
 
     // true because store file is full.
-    assert_eq!(true, segment.is_maxed());
+    assert!(segment.is_maxed());
   }
 
   #[test_log::test]
@@ -261,14 +1565,14 @@ mod tests {
       tempfile::tempdir().unwrap().into_path().to_str().unwrap(),
       0,
       Config {
-        initial_offset: 0,
         max_index_bytes: 24,
         max_store_bytes: 128,
+        ..Default::default()
       },
     )
     .unwrap();
 
-    assert_eq!(false, segment.is_maxed());
+    assert!(!segment.is_maxed());
 
     // Append two entries to the index, each occupying 12 bytes:
     // 4 for the offset and 8 for the position.
@@ -276,6 +1580,36 @@ mod tests {
     segment.append(vec![0u8; 128]).unwrap();
 
     // true because index file is full.
-    assert_eq!(true, segment.is_maxed());
+    assert!(segment.is_maxed());
+  }
+
+  #[test_log::test]
+  fn append_fails_when_the_relative_offset_does_not_fit_in_the_index() {
+    let mut segment = Segment::in_memory(0, Config::default()).unwrap();
+
+    let last = u32::MAX as u64;
+
+    assert_eq!(
+      last,
+      segment.append_at(last, vec![1], HashMap::new()).unwrap()
+    );
+    assert_eq!(vec![1], segment.read(last).unwrap().value);
+
+    let store_size = segment.store_size();
+
+    assert_eq!(
+      Some(&index::IndexError::RelativeOffsetOverflow {
+        offset: last + 1,
+        relative_offset: last + 1,
+      }),
+      segment
+        .append(vec![2])
+        .unwrap_err()
+        .downcast_ref::<index::IndexError>()
+    );
+
+    // Nothing was written.
+    assert_eq!(store_size, segment.store_size());
+    assert_eq!(last + 1, segment.next_offset());
   }
 }
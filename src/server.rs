@@ -1,36 +1,344 @@
-use std::sync::Arc;
+use std::{future::Future, sync::Arc, time::Duration};
 
-use tokio::sync::{mpsc, RwLock};
-use tokio_stream::wrappers::ReceiverStream;
+use thiserror::Error;
+use tokio::{
+  sync::{mpsc, watch, Notify, RwLock},
+  time::Instant,
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
 
-use crate::{api, commit_log::Log};
-use tracing::error;
+use crate::{
+  api,
+  commit_log::{CommitLogError, Log},
+  store::StoreError,
+};
+use tracing::{debug, error, warn};
 
 #[derive(Debug, Clone)]
 pub struct LogServer {
   log: Arc<RwLock<Log>>,
+  /// Wakes up streaming consumers that are waiting for new records.
+  appended: Arc<Notify>,
+  /// Set to true by LogServer::shutdown, stops the stream tasks.
+  stop: Arc<watch::Sender<bool>>,
+  stopping: watch::Receiver<bool>,
+  config: Config,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+  /// How long a read may take before the request is answered
+  /// with `Status::deadline_exceeded`.
+  pub read_timeout: Duration,
+  /// How many records consume_stream coalesces into a single response.
+  ///
+  /// When 1, each response contains one record in the `record` field.
+  pub consume_batch_size: usize,
+  /// How long consume_stream waits for a batch to fill up
+  /// before sending it anyway.
+  pub consume_batch_linger: Duration,
+  /// When true, produced record values are logged verbatim.
+  ///
+  /// Values may contain sensitive data, so by default only
+  /// their length and crc32 are logged. Meant for debugging.
+  pub log_record_values: bool,
+  /// How often LogServer::run_maintenance should be called.
+  pub maintenance_interval: Duration,
+  /// How long LogServer::shutdown waits for the stream
+  /// tasks to drop their handles to the log.
+  pub shutdown_timeout: Duration,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      read_timeout: Duration::from_secs(5),
+      consume_batch_size: 1,
+      consume_batch_linger: Duration::from_millis(10),
+      log_record_values: false,
+      maintenance_interval: Duration::from_secs(60),
+      shutdown_timeout: Duration::from_secs(10),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum ServerError {
+  #[error("the log cannot be closed because {0} other handles to it are alive")]
+  LogInUse(usize),
 }
 
 impl LogServer {
-  pub fn new(log: Log) -> Self {
+  pub fn new(log: Log, config: Config) -> Self {
+    let (stop, stopping) = watch::channel(false);
+
     Self {
       log: Arc::new(RwLock::new(log)),
+      appended: Arc::new(Notify::new()),
+      stop: Arc::new(stop),
+      stopping,
+      config,
+    }
+  }
+
+  /// Stops the stream tasks and closes the log, flushing every segment.
+  ///
+  /// The stream tasks have their own handles to the log, which they
+  /// drop shortly after being stopped, so this waits up to
+  /// `Config::shutdown_timeout` for this server to hold the last handle.
+  /// Every other clone of the server must have been dropped as well,
+  /// otherwise `ServerError::LogInUse` is returned and the log is left open.
+  pub async fn shutdown(self) -> anyhow::Result<()> {
+    let _ = self.stop.send(true);
+
+    let deadline = Instant::now() + self.config.shutdown_timeout;
+
+    let mut log = self.log;
+
+    loop {
+      log = match Arc::try_unwrap(log) {
+        Ok(log) => return log.into_inner().close(),
+        Err(log) if Instant::now() >= deadline => {
+          return Err(ServerError::LogInUse(Arc::strong_count(&log) - 1).into())
+        }
+        Err(log) => log,
+      };
+
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  }
+
+  /// Spawns a stream task that is dropped, with its
+  /// handle to the log, once the server is shut down.
+  fn spawn_stream_task(&self, task: impl Future<Output = ()> + Send + 'static) {
+    let mut stopping = self.stopping.clone();
+
+    tokio::spawn(async move {
+      tokio::select! {
+        _ = task => {}
+        _ = async {
+          while !*stopping.borrow() {
+            // The server was dropped without being shut down.
+            if stopping.changed().await.is_err() {
+              std::future::pending::<()>().await;
+            }
+          }
+        } => {}
+      }
+    });
+  }
+
+  /// Does the periodic work of the log: rolls the active segment if no
+  /// record has been appended for a while, see Log::roll_if_idle, and
+  /// compresses the sealed segments, see Log::compress_sealed_segments.
+  ///
+  /// Meant to be called every `Config::maintenance_interval`. The
+  /// compressed copies are written on a blocking thread without holding
+  /// the lock around the log, it is only held to swap each copy in.
+  pub async fn run_maintenance(&self) -> anyhow::Result<()> {
+    self
+      .log
+      .write()
+      .await
+      .roll_if_idle(Instant::now().into_std())?;
+
+    let segments = self.log.read().await.uncompressed_sealed_segments();
+
+    for segment in segments {
+      let written = tokio::task::spawn_blocking({
+        let segment = Arc::clone(&segment);
+        move || segment.read().unwrap().write_compressed()
+      })
+      .await??;
+
+      if written {
+        self.log.write().await.replace_with_compressed(segment)?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// Reads the record at offset on a blocking thread so a read
+/// stuck on a slow disk does not stall the other requests.
+///
+/// The lock around the log is only held to find the segment that
+/// contains offset, so appends do not wait for the read.
+///
+/// Returns `CommitLogError::ReadTimeout` if the read does not
+/// finish within timeout, waiting for the lock is not counted.
+async fn read_with_timeout(
+  log: Arc<RwLock<Log>>,
+  offset: u64,
+  timeout: Duration,
+) -> anyhow::Result<api::v1::Record> {
+  let segment = log
+    .read()
+    .await
+    .segment_for(offset)
+    .ok_or(CommitLogError::OffsetOutOfBounds(offset))?;
+
+  let read = tokio::task::spawn_blocking(move || segment.read().unwrap().read(offset));
+
+  match tokio::time::timeout(timeout, read).await {
+    Ok(result) => result?,
+    Err(_) => Err(CommitLogError::ReadTimeout(offset).into()),
+  }
+}
+
+/// Appends the record of each request and sends back its offset, until
+/// the requests end, fail or the responses are no longer received.
+async fn produce_records<S>(
+  log: Arc<RwLock<Log>>,
+  appended: Arc<Notify>,
+  log_record_values: bool,
+  mut requests: S,
+  tx: mpsc::Sender<Result<api::v1::ProduceResponse, Status>>,
+) where
+  S: Stream<Item = Result<api::v1::ProduceRequest, Status>> + Unpin,
+{
+  while let Some(request) = requests.next().await {
+    let request = match request {
+      Ok(request) => request,
+      Err(status) => {
+        warn!(%status, "failed to receive produce request");
+        return;
+      }
+    };
+
+    debug!(
+      value = %loggable_value(&request.value, log_record_values),
+      "producing record"
+    );
+
+    let response = match log.write().await.append_record(api::v1::Record {
+      value: request.value,
+      headers: request.headers,
+      schema_id: request.schema_id,
+      ..Default::default()
+    }) {
+      Ok(offset) => {
+        appended.notify_waiters();
+
+        Ok(api::v1::ProduceResponse { offset })
+      }
+      Err(e) => {
+        error!("{}", e);
+        Err(append_error_to_status(&e))
+      }
+    };
+
+    // The producer has gone away.
+    if tx.send(response).await.is_err() {
+      debug!("produce stream closed by the client");
+      return;
     }
   }
 }
 
+/// Returns the CRC-32 of the values of records, in order.
+fn values_crc32<'a>(records: impl IntoIterator<Item = &'a api::v1::Record>) -> u32 {
+  let mut hasher = crc32fast::Hasher::new();
+
+  for record in records {
+    hasher.update(&record.value);
+  }
+
+  hasher.finalize()
+}
+
+/// Returns how value is shown in the logs, its length and crc32
+/// unless verbose is true.
+fn loggable_value(value: &[u8], verbose: bool) -> String {
+  if verbose {
+    format!("{:?}", String::from_utf8_lossy(value))
+  } else {
+    format!(
+      "<redacted {} bytes, crc32 {:08x}>",
+      value.len(),
+      crc32fast::hash(value)
+    )
+  }
+}
+
+/// Returns the response that contains a single record.
+fn single_record_response(record: Option<api::v1::Record>) -> api::v1::ConsumeResponse {
+  api::v1::ConsumeResponse {
+    crc32: values_crc32(&record),
+    record,
+    records: Vec::new(),
+  }
+}
+
+/// Sends the records in batch as a single response and empties batch.
+///
+/// Returns false if the consumer has gone away.
+async fn send_batch(
+  tx: &mpsc::Sender<Result<api::v1::ConsumeResponse, Status>>,
+  batch: &mut Vec<api::v1::Record>,
+  batch_size: usize,
+) -> bool {
+  let response = if batch_size == 1 {
+    single_record_response(batch.pop())
+  } else {
+    api::v1::ConsumeResponse {
+      crc32: values_crc32(batch.iter()),
+      record: None,
+      records: std::mem::take(batch),
+    }
+  };
+
+  tx.send(Ok(response)).await.is_ok()
+}
+
+/// Maps errors returned by the log to the status sent to clients.
+fn read_error_to_status(e: &anyhow::Error) -> Status {
+  match e.downcast_ref::<CommitLogError>() {
+    Some(CommitLogError::ReadTimeout(_)) => Status::deadline_exceeded("read timed out"),
+    _ => Status::unavailable("service unavailable"),
+  }
+}
+
+/// Maps errors returned by the log on appends to the status sent to clients.
+fn append_error_to_status(e: &anyhow::Error) -> Status {
+  match e.downcast_ref::<CommitLogError>() {
+    Some(e @ CommitLogError::RecordTooLarge { .. }) => Status::invalid_argument(e.to_string()),
+    _ => match e.downcast_ref::<StoreError>() {
+      Some(e @ StoreError::EntryTooLarge { .. }) => Status::invalid_argument(e.to_string()),
+      _ => Status::unavailable("service unavailable"),
+    },
+  }
+}
+
 #[tonic::async_trait]
 impl api::v1::log_server::Log for LogServer {
   async fn produce(
     &self,
     request: Request<api::v1::ProduceRequest>,
   ) -> Result<Response<api::v1::ProduceResponse>, Status> {
-    match self.log.write().await.append(request.into_inner().value) {
-      Ok(offset) => Ok(Response::new(api::v1::ProduceResponse { offset })),
+    let request = request.into_inner();
+
+    debug!(
+      value = %loggable_value(&request.value, self.config.log_record_values),
+      "producing record"
+    );
+
+    match self.log.write().await.append_record(api::v1::Record {
+      value: request.value,
+      headers: request.headers,
+      schema_id: request.schema_id,
+      ..Default::default()
+    }) {
+      Ok(offset) => {
+        self.appended.notify_waiters();
+
+        Ok(Response::new(api::v1::ProduceResponse { offset }))
+      }
       Err(e) => {
         error!("{}", e);
-        Err(Status::unavailable("service unavailable"))
+        Err(append_error_to_status(&e))
       }
     }
   }
@@ -39,13 +347,13 @@ impl api::v1::log_server::Log for LogServer {
     &self,
     request: Request<api::v1::ConsumeRequest>,
   ) -> Result<Response<api::v1::ConsumeResponse>, Status> {
-    match self.log.read().await.read(request.into_inner().offset) {
-      Ok(record) => Ok(Response::new(api::v1::ConsumeResponse {
-        record: Some(record),
-      })),
+    let offset = request.into_inner().offset;
+
+    match read_with_timeout(Arc::clone(&self.log), offset, self.config.read_timeout).await {
+      Ok(record) => Ok(Response::new(single_record_response(Some(record)))),
       Err(e) => {
         error!("{}", e);
-        Err(Status::unavailable("service unavailable"))
+        Err(read_error_to_status(&e))
       }
     }
   }
@@ -56,25 +364,90 @@ impl api::v1::log_server::Log for LogServer {
     &self,
     request: Request<api::v1::ConsumeRequest>,
   ) -> Result<Response<Self::consume_streamStream>, Status> {
-    let mut offset = request.into_inner().offset;
+    let request = request.into_inner();
+
+    let mut offset = {
+      let log = self.log.read().await;
+
+      match request.start() {
+        api::v1::StartPosition::Offset => request.offset,
+        api::v1::StartPosition::Earliest => log.lowest_offset(),
+        api::v1::StartPosition::Latest => log.highest_offset(),
+      }
+    };
 
     let (tx, rx) = mpsc::channel(4);
 
     let log = Arc::clone(&self.log);
+    let appended = Arc::clone(&self.appended);
+    let read_timeout = self.config.read_timeout;
+    let batch_size = self.config.consume_batch_size.max(1);
+    let batch_linger = self.config.consume_batch_linger;
+    let end_at_tail = request.end_at_tail;
+
+    self.spawn_stream_task(async move {
+      // Records that have been read but not sent yet.
+      let mut batch = Vec::with_capacity(batch_size);
+      // When the batch is sent even if it is not full.
+      let mut flush_at = Instant::now();
 
-    tokio::spawn(async move {
       loop {
-        match log.read().await.read(offset) {
+        // Created before reading so an append that happens
+        // right after the read is not missed.
+        let notified = appended.notified();
+
+        let result = read_with_timeout(Arc::clone(&log), offset, read_timeout).await;
+
+        match result {
           Ok(record) => {
-            tx.send(Ok(api::v1::ConsumeResponse {
-              record: Some(record),
-            }))
-            .await;
-          }
-          Err(e) => {
-            error!("{}", e);
-            tx.send(Err(Status::ok("DONE"))).await;
+            offset += 1;
+
+            if batch.is_empty() {
+              flush_at = Instant::now() + batch_linger;
+            }
+
+            batch.push(record);
+
+            // The consumer has gone away.
+            if batch.len() == batch_size && !send_batch(&tx, &mut batch, batch_size).await {
+              return;
+            }
           }
+          Err(e) => match e.downcast_ref::<CommitLogError>() {
+            // Caught up with the log and the consumer does not want to wait.
+            Some(CommitLogError::OffsetOutOfBounds(_)) if end_at_tail => {
+              if !batch.is_empty() {
+                send_batch(&tx, &mut batch, batch_size).await;
+              }
+
+              return;
+            }
+            // Caught up with the log, wait for the next record.
+            Some(CommitLogError::OffsetOutOfBounds(_)) if batch.is_empty() => {
+              tokio::select! {
+                _ = notified => {}
+                _ = tx.closed() => return,
+              }
+            }
+            // Caught up with the log while a batch is being filled,
+            // wait for the next record but not past the batch deadline.
+            Some(CommitLogError::OffsetOutOfBounds(_)) => {
+              tokio::select! {
+                _ = notified => {}
+                _ = tokio::time::sleep_until(flush_at) => {
+                  if !send_batch(&tx, &mut batch, batch_size).await {
+                    return;
+                  }
+                }
+                _ = tx.closed() => return,
+              }
+            }
+            _ => {
+              error!("{}", e);
+              let _ = tx.send(Err(read_error_to_status(&e))).await;
+              return;
+            }
+          },
         }
       }
     });
@@ -88,23 +461,61 @@ impl api::v1::log_server::Log for LogServer {
     &self,
     request: Request<Streaming<api::v1::ProduceRequest>>,
   ) -> Result<Response<Self::produce_streamStream>, Status> {
-    let mut request_streamer = request.into_inner();
+    let request_streamer = request.into_inner();
 
     let (tx, rx) = mpsc::channel(4);
 
     let log = Arc::clone(&self.log);
+    let appended = Arc::clone(&self.appended);
+    let log_record_values = self.config.log_record_values;
 
-    tokio::spawn(async move {
-      while let Some(request) = request_streamer.message().await.unwrap() {
-        match log.write().await.append(request.value) {
-          Ok(offset) => {
-            tx.send(Ok(api::v1::ProduceResponse { offset })).await;
-          }
+    self.spawn_stream_task(produce_records(
+      log,
+      appended,
+      log_record_values,
+      request_streamer,
+      tx,
+    ));
+
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  type consume_rangeStream = ReceiverStream<Result<api::v1::ConsumeResponse, Status>>;
+
+  async fn consume_range(
+    &self,
+    request: Request<api::v1::ConsumeRangeRequest>,
+  ) -> Result<Response<Self::consume_rangeStream>, Status> {
+    let api::v1::ConsumeRangeRequest { start, end } = request.into_inner();
+
+    let (tx, rx) = mpsc::channel(4);
+
+    let log = Arc::clone(&self.log);
+    let read_timeout = self.config.read_timeout;
+
+    self.spawn_stream_task(async move {
+      for offset in start..end {
+        let response = match read_with_timeout(Arc::clone(&log), offset, read_timeout).await {
+          Ok(record) => Ok(single_record_response(Some(record))),
           Err(e) => {
             error!("{}", e);
-            tx.send(Err(Status::unavailable("service unavailable")))
-              .await;
+
+            // Records that have not been appended yet are not waited for.
+            match e.downcast_ref::<CommitLogError>() {
+              Some(CommitLogError::OffsetOutOfBounds(_)) => Err(Status::out_of_range(format!(
+                "offset {} is not in the log",
+                offset
+              ))),
+              _ => Err(read_error_to_status(&e)),
+            }
           }
+        };
+
+        let is_err = response.is_err();
+
+        // The consumer has gone away.
+        if tx.send(response).await.is_err() || is_err {
+          return;
         }
       }
     });
@@ -112,3 +523,632 @@ impl api::v1::log_server::Log for LogServer {
     Ok(Response::new(ReceiverStream::new(rx)))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    api::v1::log_server::Log as _,
+    backend::{FaultyBackend, MemoryBackend},
+    commit_log,
+    segment::{self, Segment},
+  };
+  use std::collections::HashMap;
+  use tokio_stream::StreamExt;
+
+  fn new_server() -> LogServer {
+    LogServer::new(
+      Log::new_in_memory(commit_log::Config::default()).unwrap(),
+      Config::default(),
+    )
+  }
+
+  async fn produce(server: &LogServer, value: &str) -> u64 {
+    server
+      .produce(Request::new(api::v1::ProduceRequest {
+        value: value.as_bytes().to_vec(),
+        ..Default::default()
+      }))
+      .await
+      .unwrap()
+      .into_inner()
+      .offset
+  }
+
+  /// Shared buffer that the formatted log output is written to.
+  #[derive(Clone, Default)]
+  struct CapturedOutput(Arc<std::sync::Mutex<Vec<u8>>>);
+
+  impl std::io::Write for CapturedOutput {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(buffer);
+      Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  /// Produces value and returns what was logged while doing it.
+  async fn produce_and_capture_logs(config: Config, value: &str) -> String {
+    let server = LogServer::new(
+      Log::new_in_memory(commit_log::Config::default()).unwrap(),
+      config,
+    );
+
+    let output = CapturedOutput::default();
+
+    let subscriber = tracing_subscriber::fmt()
+      .with_max_level(tracing::Level::DEBUG)
+      .with_ansi(false)
+      .with_writer({
+        let output = output.clone();
+        move || output.clone()
+      })
+      .finish();
+
+    {
+      let _guard = tracing::subscriber::set_default(subscriber);
+
+      produce(&server, value).await;
+    }
+
+    let output = output.0.lock().unwrap();
+
+    String::from_utf8_lossy(&output).into_owned()
+  }
+
+  #[tokio::test]
+  async fn produced_values_are_redacted_from_logs_unless_verbose() {
+    let value = "secret-value";
+
+    let output = produce_and_capture_logs(Config::default(), value).await;
+
+    assert!(output.contains("producing record"));
+    assert!(!output.contains(value));
+    assert!(output.contains(&format!("{} bytes", value.len())));
+
+    let output = produce_and_capture_logs(
+      Config {
+        log_record_values: true,
+        ..Config::default()
+      },
+      value,
+    )
+    .await;
+
+    assert!(output.contains(value));
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn shutdown_closes_the_log() {
+    let directory = tempfile::tempdir().unwrap();
+    let directory = directory.path().to_str().unwrap().to_owned();
+
+    let server = LogServer::new(
+      Log::new(directory.clone(), commit_log::Config::default()).unwrap(),
+      Config {
+        shutdown_timeout: Duration::from_millis(50),
+        ..Config::default()
+      },
+    );
+
+    produce(&server, "a").await;
+    produce(&server, "b").await;
+
+    let clone = server.clone();
+
+    assert_eq!(
+      Some(&ServerError::LogInUse(1)),
+      clone
+        .shutdown()
+        .await
+        .unwrap_err()
+        .downcast_ref::<ServerError>()
+    );
+
+    // The failed shutdown dropped its handle.
+    server.shutdown().await.unwrap();
+
+    let log = Log::new(directory, commit_log::Config::default()).unwrap();
+
+    assert_eq!("a".as_bytes().to_vec(), log.read(0).unwrap().value);
+    assert_eq!("b".as_bytes().to_vec(), log.read(1).unwrap().value);
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn maintenance_rolls_idle_segments_and_compresses_them() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let server = LogServer::new(
+      Log::new(
+        directory.path().to_str().unwrap().to_owned(),
+        commit_log::Config::builder()
+          .idle_roll_timeout(Duration::from_millis(10))
+          .build(),
+      )
+      .unwrap(),
+      Config::default(),
+    );
+
+    let value = "a".repeat(200);
+
+    produce(&server, &value).await;
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    server.run_maintenance().await.unwrap();
+
+    let segment = server.log.read().await.segment_for(0).unwrap();
+
+    assert!(segment.read().unwrap().is_compressed());
+    drop(segment);
+
+    assert_eq!(1, produce(&server, "b").await);
+
+    let response = server
+      .consume(Request::new(api::v1::ConsumeRequest {
+        offset: 0,
+        ..Default::default()
+      }))
+      .await
+      .unwrap()
+      .into_inner();
+
+    assert_eq!(value.into_bytes(), response.record.unwrap().value);
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn shutdown_stops_live_streams_and_closes_the_log() {
+    let directory = tempfile::tempdir().unwrap();
+    let directory = directory.path().to_str().unwrap().to_owned();
+
+    let server = LogServer::new(
+      Log::new(directory.clone(), commit_log::Config::default()).unwrap(),
+      Config::default(),
+    );
+
+    produce(&server, "a").await;
+
+    // The consumer is still connected, waiting for the next record.
+    let mut stream = server
+      .consume_stream(Request::new(api::v1::ConsumeRequest {
+        offset: 0,
+        ..Default::default()
+      }))
+      .await
+      .unwrap()
+      .into_inner();
+
+    assert_eq!(
+      "a".as_bytes().to_vec(),
+      stream.next().await.unwrap().unwrap().record.unwrap().value
+    );
+
+    tokio::time::timeout(Duration::from_secs(1), server.shutdown())
+      .await
+      .unwrap()
+      .unwrap();
+
+    // The stream ends once its task is stopped.
+    assert!(stream.next().await.is_none());
+
+    let log = Log::new(directory, commit_log::Config::default()).unwrap();
+
+    assert_eq!("a".as_bytes().to_vec(), log.read(0).unwrap().value);
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn consume_times_out_on_slow_reads_and_the_log_stays_responsive() {
+    let log_config = commit_log::Config::default();
+
+    // Read 0 is the length of the first record read.
+    let backend = FaultyBackend::new(Box::new(MemoryBackend::new()))
+      .delay_read_at(0, Duration::from_millis(500));
+
+    // The segment is full after one record, so the
+    // next record is appended to a new segment.
+    let segment = Segment::with_backend(
+      0,
+      segment::Config {
+        max_store_bytes: 1,
+        ..Default::default()
+      },
+      Box::new(backend),
+    )
+    .unwrap();
+
+    let server = LogServer::new(
+      Log::with_segments(String::new(), log_config, vec![segment], true).unwrap(),
+      Config {
+        read_timeout: Duration::from_millis(50),
+        ..Config::default()
+      },
+    );
+
+    produce(&server, "a").await;
+    produce(&server, "b").await;
+
+    let consume = |offset| {
+      server.consume(Request::new(api::v1::ConsumeRequest {
+        offset,
+        ..Default::default()
+      }))
+    };
+
+    assert_eq!(
+      tonic::Code::DeadlineExceeded,
+      consume(0).await.unwrap_err().code()
+    );
+
+    // Other reads are still served while the slow one is stuck.
+    let record = consume(1).await.unwrap().into_inner().record.unwrap();
+
+    assert_eq!("b".as_bytes().to_vec(), record.value);
+
+    // So are appends, well before the slow read finishes.
+    let offset = tokio::time::timeout(Duration::from_millis(250), produce(&server, "c"))
+      .await
+      .unwrap();
+
+    assert_eq!(2, offset);
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn produced_headers_are_returned_on_consume() {
+    let server = new_server();
+
+    let headers: HashMap<String, Vec<u8>> = [
+      ("trace-id".to_owned(), "abc123".as_bytes().to_vec()),
+      ("content-type".to_owned(), "text/plain".as_bytes().to_vec()),
+    ]
+    .into_iter()
+    .collect();
+
+    let offset = server
+      .produce(Request::new(api::v1::ProduceRequest {
+        value: "a".as_bytes().to_vec(),
+        headers: headers.clone(),
+        ..Default::default()
+      }))
+      .await
+      .unwrap()
+      .into_inner()
+      .offset;
+
+    let record = server
+      .consume(Request::new(api::v1::ConsumeRequest {
+        offset,
+        ..Default::default()
+      }))
+      .await
+      .unwrap()
+      .into_inner()
+      .record
+      .unwrap();
+
+    assert_eq!("a".as_bytes().to_vec(), record.value);
+    assert_eq!(headers, record.headers);
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn produced_schema_ids_are_returned_on_consume() {
+    let server = new_server();
+
+    for (value, schema_id) in [("a", 7), ("b", 0), ("c", 12)] {
+      server
+        .produce(Request::new(api::v1::ProduceRequest {
+          value: value.as_bytes().to_vec(),
+          schema_id,
+          ..Default::default()
+        }))
+        .await
+        .unwrap();
+    }
+
+    // Like a client that does not know about schema ids.
+    produce(&server, "d").await;
+
+    let mut schema_ids = Vec::new();
+
+    for offset in 0..4 {
+      let record = server
+        .consume(Request::new(api::v1::ConsumeRequest {
+          offset,
+          ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+        .record
+        .unwrap();
+
+      schema_ids.push(record.schema_id);
+    }
+
+    assert_eq!(vec![7, 0, 12, 0], schema_ids);
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn produce_rejects_records_bigger_than_the_max_record_size() {
+    let server = LogServer::new(
+      Log::new_in_memory(commit_log::Config::builder().max_record_bytes(4).build()).unwrap(),
+      Config::default(),
+    );
+
+    let status = server
+      .produce(Request::new(api::v1::ProduceRequest {
+        value: "hello".as_bytes().to_vec(),
+        ..Default::default()
+      }))
+      .await
+      .unwrap_err();
+
+    assert_eq!(tonic::Code::InvalidArgument, status.code());
+    assert_eq!(
+      "record too large, its value has 5 bytes but the maximum is 4 bytes",
+      status.message()
+    );
+
+    assert_eq!(0, produce(&server, "abcd").await);
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn consume_responses_contain_the_crc32_of_the_values() {
+    let server = new_server();
+
+    let offset = produce(&server, "hello world").await;
+
+    let response = server
+      .consume(Request::new(api::v1::ConsumeRequest {
+        offset,
+        ..Default::default()
+      }))
+      .await
+      .unwrap()
+      .into_inner();
+
+    assert_eq!(
+      crc32fast::hash(&response.record.unwrap().value),
+      response.crc32
+    );
+    // Known CRC-32 of "hello world".
+    assert_eq!(0x0d4a1185, response.crc32);
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn consume_stream_coalesces_records_into_batches() {
+    let server = LogServer::new(
+      Log::new_in_memory(commit_log::Config::default()).unwrap(),
+      Config {
+        consume_batch_size: 5,
+        ..Config::default()
+      },
+    );
+
+    for i in 0..20 {
+      produce(&server, &i.to_string()).await;
+    }
+
+    let mut stream = server
+      .consume_stream(Request::new(api::v1::ConsumeRequest {
+        offset: 0,
+        ..Default::default()
+      }))
+      .await
+      .unwrap()
+      .into_inner();
+
+    for batch in 0..4 {
+      let response = stream.next().await.unwrap().unwrap();
+
+      let offsets: Vec<u64> = response
+        .records
+        .iter()
+        .map(|record| record.offset)
+        .collect();
+
+      assert_eq!((batch * 5..batch * 5 + 5).collect::<Vec<u64>>(), offsets);
+    }
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn consume_stream_ends_at_the_tail_when_asked_to() {
+    let server = new_server();
+
+    for value in ["a", "b", "c"] {
+      produce(&server, value).await;
+    }
+
+    let stream = server
+      .consume_stream(Request::new(api::v1::ConsumeRequest {
+        offset: 0,
+        end_at_tail: true,
+        ..Default::default()
+      }))
+      .await
+      .unwrap()
+      .into_inner();
+
+    let values: Vec<Vec<u8>> = tokio::time::timeout(
+      Duration::from_secs(1),
+      stream
+        .map(|response| response.unwrap().record.unwrap().value)
+        .collect(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()], values);
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn consume_range_streams_the_range_and_then_ends() {
+    let server = new_server();
+
+    for i in 0..8 {
+      produce(&server, &i.to_string()).await;
+    }
+
+    let stream = server
+      .consume_range(Request::new(api::v1::ConsumeRangeRequest {
+        start: 2,
+        end: 5,
+      }))
+      .await
+      .unwrap()
+      .into_inner();
+
+    let offsets: Vec<u64> = stream
+      .map(|response| response.unwrap().record.unwrap().offset)
+      .collect()
+      .await;
+
+    assert_eq!(vec![2, 3, 4], offsets);
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn consume_stream_at_earliest_follows_the_log_after_catching_up() {
+    let server = new_server();
+
+    for i in 0..20 {
+      produce(&server, &i.to_string()).await;
+    }
+
+    let mut stream = server
+      .consume_stream(Request::new(api::v1::ConsumeRequest {
+        offset: 0,
+        start: api::v1::StartPosition::Earliest as i32,
+        ..Default::default()
+      }))
+      .await
+      .unwrap()
+      .into_inner();
+
+    // Records are appended while the stream is catching up.
+    let producer = {
+      let server = server.clone();
+
+      tokio::spawn(async move {
+        for i in 20..40 {
+          produce(&server, &i.to_string()).await;
+          tokio::task::yield_now().await;
+        }
+      })
+    };
+
+    for expected_offset in 0..40 {
+      let record = stream.next().await.unwrap().unwrap().record.unwrap();
+
+      assert_eq!(expected_offset, record.offset);
+      assert_eq!(
+        expected_offset.to_string().as_bytes().to_vec(),
+        record.value
+      );
+    }
+
+    producer.await.unwrap();
+
+    // Nothing is delivered twice.
+    assert!(
+      tokio::time::timeout(Duration::from_millis(50), stream.next())
+        .await
+        .is_err()
+    );
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn consume_stream_at_latest_only_delivers_records_appended_after_subscribing() {
+    let server = new_server();
+
+    produce(&server, "a").await;
+
+    let mut stream = server
+      .consume_stream(Request::new(api::v1::ConsumeRequest {
+        offset: 0,
+        start: api::v1::StartPosition::Latest as i32,
+        ..Default::default()
+      }))
+      .await
+      .unwrap()
+      .into_inner();
+
+    produce(&server, "b").await;
+    produce(&server, "c").await;
+
+    for (expected_offset, expected_value) in [(1, "b"), (2, "c")] {
+      let record = stream.next().await.unwrap().unwrap().record.unwrap();
+
+      assert_eq!(expected_offset, record.offset);
+      assert_eq!(expected_value.as_bytes().to_vec(), record.value);
+    }
+  }
+
+  #[test_log::test(tokio::test)]
+  async fn produce_records_stops_when_the_client_stream_fails_or_ends() {
+    let server = new_server();
+
+    let request = |value: &str| api::v1::ProduceRequest {
+      value: value.as_bytes().to_vec(),
+      ..Default::default()
+    };
+
+    let produce_records = |requests| {
+      let (tx, rx) = mpsc::channel(4);
+
+      let task = tokio::spawn(produce_records(
+        Arc::clone(&server.log),
+        Arc::clone(&server.appended),
+        false,
+        ReceiverStream::new(requests),
+        tx,
+      ));
+
+      (task, rx)
+    };
+
+    let finished = |task| tokio::time::timeout(Duration::from_secs(1), task);
+
+    // The client stream fails mid-stream.
+    let (requests, requests_rx) = mpsc::channel(4);
+    let (task, mut responses) = produce_records(requests_rx);
+
+    requests.send(Ok(request("a"))).await.unwrap();
+    assert_eq!(0, responses.recv().await.unwrap().unwrap().offset);
+
+    requests
+      .send(Err(Status::unknown("connection reset")))
+      .await
+      .unwrap();
+    let _ = requests.send(Ok(request("b"))).await;
+
+    finished(task).await.unwrap().unwrap();
+    assert!(responses.recv().await.is_none());
+
+    // The client drops its side mid-stream.
+    let (requests, requests_rx) = mpsc::channel(4);
+    let (task, mut responses) = produce_records(requests_rx);
+
+    requests.send(Ok(request("c"))).await.unwrap();
+    assert_eq!(1, responses.recv().await.unwrap().unwrap().offset);
+
+    drop(requests);
+
+    finished(task).await.unwrap().unwrap();
+    assert!(responses.recv().await.is_none());
+
+    // The client stops receiving responses.
+    let (requests, requests_rx) = mpsc::channel(4);
+    let (task, responses) = produce_records(requests_rx);
+
+    drop(responses);
+
+    requests.send(Ok(request("d"))).await.unwrap();
+
+    finished(task).await.unwrap().unwrap();
+
+    // a, c and d were appended, b was not.
+    assert_eq!(3, server.log.read().await.highest_offset());
+  }
+}
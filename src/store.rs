@@ -1,27 +1,107 @@
 /// Store represents a file where records are stored.
 use std::{
-  fs::{File, Metadata},
-  io::{BufWriter, Write},
-  os::unix::prelude::FileExt,
+  fs::File,
+  ops::{Deref, DerefMut},
   sync::Mutex,
 };
 
 use anyhow::Result;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
 use tracing::info;
 
-const LEN_WIDTH: usize = 8;
+#[cfg(target_os = "linux")]
+use crate::backend::DirectBackend;
+use crate::backend::{FileBackend, StorageBackend};
+
+/// Number of bytes used to store the length of each entry.
+pub const LEN_WIDTH: usize = 8;
+
+/// Number of bytes used to store the crc32 of each entry.
+pub const CRC_WIDTH: usize = 4;
+
+/// Number of bytes written before the contents of each entry.
+pub const HEADER_WIDTH: usize = LEN_WIDTH + CRC_WIDTH;
+
+/// How many bytes Store::digest reads at a time.
+const DIGEST_CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Debug)]
 pub struct Store {
-  /// File is wrapped in a BufWriter because it can be inefficient
-  /// to work directly with something that implements Write
-  /// because it may issue too many systems calls.
-  ///
-  /// BufWriter will keep an in-memory buffer of data
-  /// and write it to the underlying writer in batches.
-  writer: Mutex<BufWriter<File>>,
-  file_metadata: Metadata,
+  /// Where the entries are actually stored, a file in most cases.
+  backend: Mutex<Box<dyn StorageBackend>>,
   file_size: u64,
+  /// Entries bigger than this are not appended, and entries whose
+  /// length prefix is bigger than this are considered corrupted
+  /// and are not read.
+  max_entry_bytes: u64,
+  /// When set, appends flush the backend once it
+  /// buffers at least this many bytes.
+  flush_after_bytes: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum StoreError {
+  #[error(
+    "entry length {length} is bigger than the maximum of {max} bytes, the store may be corrupted"
+  )]
+  ImplausibleLength { length: u64, max: u64 },
+  #[error("entry length {length} is bigger than the maximum of {max} bytes")]
+  EntryTooLarge { length: u64, max: u64 },
+  #[error("the crc32 of the entry at position {position} does not match its contents")]
+  ChecksumMismatch { position: u64 },
+}
+
+/// Buffers that are reused to read store entries instead
+/// of allocating a new buffer for each read.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+  buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+/// A buffer taken from a BufferPool, it goes back
+/// to the pool when it is dropped.
+#[derive(Debug)]
+pub struct PooledBuffer<'a> {
+  pool: &'a BufferPool,
+  buffer: Vec<u8>,
+}
+
+impl BufferPool {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Takes a buffer from the pool, a new one is
+  /// allocated if the pool is empty.
+  pub fn checkout(&self) -> PooledBuffer<'_> {
+    PooledBuffer {
+      pool: self,
+      buffer: self.buffers.lock().unwrap().pop().unwrap_or_default(),
+    }
+  }
+}
+
+impl Deref for PooledBuffer<'_> {
+  type Target = Vec<u8>;
+
+  fn deref(&self) -> &Self::Target {
+    &self.buffer
+  }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    &mut self.buffer
+  }
+}
+
+impl Drop for PooledBuffer<'_> {
+  fn drop(&mut self) {
+    let buffer = std::mem::take(&mut self.buffer);
+
+    self.pool.buffers.lock().unwrap().push(buffer);
+  }
 }
 
 #[derive(Debug, PartialEq)]
@@ -32,45 +112,102 @@ pub struct AppendOutput {
 
 impl Store {
   pub fn new(file: File) -> Result<Self> {
-    let file_metadata = file.metadata()?;
+    Ok(Self::with_backend(Box::new(FileBackend::new(file)?)))
+  }
 
-    Ok(Self {
-      writer: Mutex::new(BufWriter::new(file)),
-      file_size: file_metadata.len(),
-      file_metadata,
-    })
+  /// Creates a store that writes to file using direct I/O,
+  /// bypassing the page cache.
+  ///
+  /// file must have been opened with `O_DIRECT` and without `O_APPEND`.
+  #[cfg(target_os = "linux")]
+  pub fn new_direct(file: File) -> Result<Self> {
+    Ok(Self::with_backend(Box::new(DirectBackend::new(file)?)))
+  }
+
+  /// Creates a store that keeps its entries in `backend`.
+  pub fn with_backend(backend: Box<dyn StorageBackend>) -> Self {
+    Self {
+      file_size: backend.size(),
+      backend: Mutex::new(backend),
+      max_entry_bytes: u64::MAX,
+      flush_after_bytes: None,
+    }
+  }
+
+  /// Sets the maximum length an entry may have to be appended or read.
+  pub fn with_max_entry_bytes(mut self, max_entry_bytes: u64) -> Self {
+    self.max_entry_bytes = max_entry_bytes;
+    self
+  }
+
+  /// Makes appends flush the backend once it buffers at least
+  /// flush_after_bytes bytes, so a big buffer does not keep appended
+  /// entries away from the storage indefinitely. None disables it.
+  pub fn with_flush_after_bytes(mut self, flush_after_bytes: Option<u64>) -> Self {
+    self.flush_after_bytes = flush_after_bytes;
+    self
   }
 
   /// Appends a new entry to the store file.
   ///
-  /// Each entry contains the buffer length followed by the buffer
-  /// contents.
+  /// Each entry contains the buffer length, the crc32 of the buffer
+  /// and the buffer contents.
   ///
   /// An entry looks like this:
   ///
-  ///                              Entry
-  /// ┌────────────────────────────────────────────────────────────────┐
-  /// │                                                                │
-  /// │   LEN                   hello world                            │
-  /// │ ┌────┬┬──────────────────────────────────────────────────────┐ │
-  /// │ │ 11 ││ 104, 101, 108, 108, 111, 32, 119, 111, 114, 108, 100 │ │
-  /// │ └────┴┴──────────────────────────────────────────────────────┘ │
-  /// │                                                                │
-  /// └────────────────────────────────────────────────────────────────┘
+  /// ```text
+  ///                                     Entry
+  /// ┌────────────────────────────────────────────────────────────────────────────┐
+  /// │                                                                            │
+  /// │   LEN    CRC32                   hello world                               │
+  /// │ ┌────┬┬──────────┬┬──────────────────────────────────────────────────────┐ │
+  /// │ │ 11 ││ 0d4a1185 ││ 104, 101, 108, 108, 111, 32, 119, 111, 114, 108, 100 │ │
+  /// │ └────┴┴──────────┴┴──────────────────────────────────────────────────────┘ │
+  /// │                                                                            │
+  /// └────────────────────────────────────────────────────────────────────────────┘
+  /// ```
   ///
   /// Returns how many bytes were written to the store file and
   /// the position in the store file where the entry begins.
+  ///
+  /// If the append fails, part of the entry may have reached the
+  /// backend. Those bytes are never indexed and the next entry
+  /// is appended after them.
+  ///
+  /// Entries bigger than the max entry size are not appended,
+  /// see `Store::with_max_entry_bytes`.
   pub fn append(&mut self, buffer: &[u8]) -> Result<AppendOutput> {
-    let mut writer = self.writer.lock().unwrap();
+    // It could not be read back.
+    if buffer.len() as u64 > self.max_entry_bytes {
+      return Err(
+        StoreError::EntryTooLarge {
+          length: buffer.len() as u64,
+          max: self.max_entry_bytes,
+        }
+        .into(),
+      );
+    }
+
+    let mut backend = self.backend.lock().unwrap();
 
-    let appended_at = self.file_size;
+    // Ask the backend instead of trusting file_size because
+    // a failed append may have left a partial entry behind.
+    let appended_at = backend.size();
 
-    writer.write_all(&buffer.len().to_be_bytes())?;
-    writer.write_all(buffer)?;
+    // The length is always LEN_WIDTH bytes, whatever the size of usize is.
+    backend.append(&(buffer.len() as u64).to_be_bytes())?;
+    backend.append(&crc32fast::hash(buffer).to_be_bytes())?;
+    backend.append(buffer)?;
 
-    let bytes_written = (LEN_WIDTH + buffer.len()) as u64;
+    let bytes_written = (HEADER_WIDTH + buffer.len()) as u64;
 
-    self.file_size += bytes_written;
+    self.file_size = appended_at + bytes_written;
+
+    if let Some(flush_after_bytes) = self.flush_after_bytes {
+      if backend.buffered_bytes() >= flush_after_bytes {
+        backend.flush()?;
+      }
+    }
 
     Ok(AppendOutput {
       appended_at,
@@ -83,61 +220,142 @@ impl Store {
   /// First, the entry length is read from the file,
   /// then, the entry contents is read using the entry length
   /// that we jusst read.
+  ///
+  /// Returns `StoreError::ImplausibleLength` without allocating the
+  /// entry buffer if the entry length is bigger than the max entry
+  /// size or than the bytes left in the store after position.
+  ///
+  /// Returns `StoreError::ChecksumMismatch` if the entry contents
+  /// do not match the crc32 stored with them.
   pub fn read(&self, position: u64) -> Result<Vec<u8>> {
-    // Flush BufWriter to ensure that content has been written to the underlying
-    // file before we read it.
-    let mut writer = self.writer.lock().unwrap();
+    let mut buffer = Vec::new();
 
-    let _ = writer.flush()?;
+    self.read_into(position, &mut buffer)?;
 
-    // Buffer that will contain the entry length
-    let mut buffer = [0u8; LEN_WIDTH];
+    Ok(buffer)
+  }
 
-    let file = writer.get_ref();
+  /// Same as Store::read but the entry contents are read into
+  /// buffer, which is resized to the entry length, so buffers
+  /// can be reused across reads.
+  pub fn read_into(&self, position: u64, buffer: &mut Vec<u8>) -> Result<()> {
+    let mut backend = self.backend.lock().unwrap();
 
-    // Read the entry length(first 8 bytes) into the buffer.
-    file.read_exact_at(&mut buffer, position)?;
+    let (entry_length, crc) = read_header(&mut **backend, position)?;
 
-    let entry_length = u64::from_be_bytes(buffer);
+    let max = self.max_entry_bytes.min(
+      backend
+        .size()
+        .saturating_sub(position + HEADER_WIDTH as u64),
+    );
 
-    // Buffer that will contain the entry contents
-    let mut buffer = vec![0u8; entry_length as usize];
+    if entry_length > max {
+      return Err(
+        StoreError::ImplausibleLength {
+          length: entry_length,
+          max,
+        }
+        .into(),
+      );
+    }
 
-    // Read entry contents (entry_length bytes after position + bytes that contain the entry length)
-    file.read_exact_at(&mut buffer, position + LEN_WIDTH as u64)?;
+    // The buffer will contain the entry contents
+    buffer.clear();
+    buffer.resize(entry_length as usize, 0);
 
-    Ok(buffer)
+    // Read entry contents (entry_length bytes after position + bytes that contain the entry header)
+    backend.read_at(buffer, position + HEADER_WIDTH as u64)?;
+
+    if crc32fast::hash(buffer) != crc {
+      return Err(StoreError::ChecksumMismatch { position }.into());
+    }
+
+    Ok(())
   }
 
   /// Same as Store::read but the buffer is provided by the caller.
   ///
   /// An error will be returned if the buffer length is not the same as the
   /// entry contents at position.
-  pub fn read_at(&self, buffer: &mut [u8], position: u64) -> std::io::Result<()> {
-    // Flush BufWriter to ensure that content has been written to the underlying
-    // file before we read it.
-    let mut writer = self.writer.lock().unwrap();
+  pub fn read_at(&self, buffer: &mut [u8], position: u64) -> Result<()> {
+    let mut backend = self.backend.lock().unwrap();
+
+    let (_, crc) = read_header(&mut **backend, position)?;
+
+    backend.read_at(buffer, position + HEADER_WIDTH as u64)?;
+
+    if crc32fast::hash(buffer) != crc {
+      return Err(StoreError::ChecksumMismatch { position }.into());
+    }
+
+    Ok(())
+  }
+
+  /// Returns the SHA-256 of every byte in the store, including
+  /// the bytes that are still buffered.
+  pub fn digest(&self) -> Result<[u8; 32], std::io::Error> {
+    let mut backend = self.backend.lock().unwrap();
+
+    let mut hasher = Sha256::new();
 
-    let _ = writer.flush()?;
+    let mut buffer = vec![0u8; DIGEST_CHUNK_SIZE];
 
-    let file = writer.get_ref();
+    let mut position = 0;
 
-    file.read_exact_at(buffer, position + LEN_WIDTH as u64)
+    while position < self.file_size {
+      let len = DIGEST_CHUNK_SIZE.min((self.file_size - position) as usize);
+
+      backend.read_at(&mut buffer[..len], position)?;
+
+      hasher.update(&buffer[..len]);
+
+      position += len as u64;
+    }
+
+    Ok(hasher.finalize().into())
+  }
+
+  /// Flushes buffered contents to storage.
+  pub fn flush(&self) -> Result<(), std::io::Error> {
+    self.backend.lock().unwrap().flush()
   }
 
-  /// Flushes BufWriter contents to storage.
+  /// Flushes buffered contents to storage and waits for them to reach the disk.
+  pub fn sync(&self) -> Result<(), std::io::Error> {
+    self.backend.lock().unwrap().sync()
+  }
+
+  /// Flushes buffered contents to storage.
   ///
-  /// The BufWriter is dropped as well.
+  /// The backend is dropped as well.
   pub fn close(self) -> Result<(), std::io::Error> {
     info!(self.file_size, "closing store");
 
-    let mut writer = self.writer.lock().unwrap();
+    let mut backend = self.backend.lock().unwrap();
+
+    backend.flush()?;
+
+    Ok(())
+  }
+
+  /// Removes every byte after the first size bytes,
+  /// a partially written entry for example.
+  pub fn truncate(&mut self, size: u64) -> Result<(), std::io::Error> {
+    info!(self.file_size, size, "truncating store");
+
+    self.backend.get_mut().unwrap().truncate(size)?;
 
-    writer.flush()?;
+    self.file_size = size;
 
     Ok(())
   }
 
+  /// Returns how many appended bytes are buffered and
+  /// have not been written to the backend yet.
+  pub fn buffered_bytes(&self) -> u64 {
+    self.backend.lock().unwrap().buffered_bytes()
+  }
+
   /// Returns the store file size.
   ///
   /// The file size is the sum of all entries in the file.
@@ -146,11 +364,27 @@ impl Store {
   }
 }
 
+/// Reads the length and the crc32 of the entry at position.
+fn read_header(backend: &mut dyn StorageBackend, position: u64) -> std::io::Result<(u64, u32)> {
+  let mut header = [0u8; HEADER_WIDTH];
+
+  backend.read_at(&mut header, position)?;
+
+  let (length, crc) = header.split_at(LEN_WIDTH);
+
+  Ok((
+    u64::from_be_bytes(length.try_into().unwrap()),
+    u32::from_be_bytes(crc.try_into().unwrap()),
+  ))
+}
+
 #[cfg(test)]
 mod tests {
+  use std::os::unix::prelude::FileExt;
   use tempfile::NamedTempFile;
 
   use super::*;
+  use crate::backend::MemoryBackend;
 
   #[test_log::test]
   fn test_append() {
@@ -164,22 +398,38 @@ mod tests {
     assert_eq!(
       AppendOutput {
         appended_at: 0,
-        bytes_written: (LEN_WIDTH + bytes.len()) as u64,
+        bytes_written: (HEADER_WIDTH + bytes.len()) as u64,
       },
       store.append(bytes).unwrap(),
     );
 
-    // appended_at should be 19 because the store file
+    // appended_at should be 23 because the store file
     // contains one entry.
     assert_eq!(
       AppendOutput {
-        appended_at: 19,
-        bytes_written: (LEN_WIDTH + bytes.len()) as u64,
+        appended_at: 23,
+        bytes_written: (HEADER_WIDTH + bytes.len()) as u64,
       },
       store.append(bytes).unwrap(),
     );
   }
 
+  #[test_log::test]
+  fn entry_lengths_are_written_as_u64() {
+    let file = NamedTempFile::new().unwrap();
+
+    let mut store = Store::new(file.reopen().unwrap()).unwrap();
+
+    store.append("hello world".as_bytes()).unwrap();
+    store.flush().unwrap();
+
+    let mut length = [0u8; LEN_WIDTH];
+
+    file.as_file().read_exact_at(&mut length, 0).unwrap();
+
+    assert_eq!(11, u64::from_be_bytes(length));
+  }
+
   #[test_log::test]
   fn test_read() {
     let file_write = NamedTempFile::new().unwrap();
@@ -218,6 +468,278 @@ mod tests {
     }
   }
 
+  #[test_log::test]
+  fn test_read_with_memory_backend() {
+    let mut store = Store::with_backend(Box::new(MemoryBackend::new()));
+
+    let tests = vec!["hello world", r#"{"key": "value"}"#];
+
+    for input in tests {
+      let bytes = input.as_bytes();
+
+      let output = store.append(bytes).unwrap();
+
+      assert_eq!(bytes.to_vec(), store.read(output.appended_at).unwrap());
+    }
+  }
+
+  #[test_log::test]
+  fn read_rejects_a_length_prefix_bigger_than_the_store() {
+    let file = NamedTempFile::new().unwrap();
+
+    let mut store = Store::new(file.reopen().unwrap()).unwrap();
+
+    store.append("hello world".as_bytes()).unwrap();
+
+    store.close().unwrap();
+
+    // Corrupt the length prefix so it claims the entry is 16GB long.
+    file
+      .as_file()
+      .write_all_at(&(16u64 << 30).to_be_bytes(), 0)
+      .unwrap();
+
+    let store = Store::new(file.reopen().unwrap()).unwrap();
+
+    assert_eq!(
+      Some(&StoreError::ImplausibleLength {
+        length: 16 << 30,
+        max: 11,
+      }),
+      store.read(0).unwrap_err().downcast_ref::<StoreError>()
+    );
+  }
+
+  #[test_log::test]
+  fn read_rejects_entries_bigger_than_the_max_entry_size() {
+    let mut store = Store::with_backend(Box::new(MemoryBackend::new()));
+
+    let output = store.append("hello world".as_bytes()).unwrap();
+
+    let store = store.with_max_entry_bytes(4);
+
+    assert_eq!(
+      Some(&StoreError::ImplausibleLength { length: 11, max: 4 }),
+      store
+        .read(output.appended_at)
+        .unwrap_err()
+        .downcast_ref::<StoreError>()
+    );
+  }
+
+  #[test_log::test]
+  fn append_rejects_entries_bigger_than_the_max_entry_size() {
+    let mut store = Store::with_backend(Box::new(MemoryBackend::new())).with_max_entry_bytes(4);
+
+    assert_eq!(
+      Some(&StoreError::EntryTooLarge { length: 11, max: 4 }),
+      store
+        .append("hello world".as_bytes())
+        .unwrap_err()
+        .downcast_ref::<StoreError>()
+    );
+
+    // Nothing was written.
+    assert_eq!(0, store.size());
+
+    assert_eq!(0, store.append("abcd".as_bytes()).unwrap().appended_at);
+  }
+
+  #[test_log::test]
+  fn read_rejects_entries_whose_contents_do_not_match_their_crc32() {
+    let file = NamedTempFile::new().unwrap();
+
+    let mut store = Store::new(file.reopen().unwrap()).unwrap();
+
+    store.append("hello world".as_bytes()).unwrap();
+
+    store.close().unwrap();
+
+    // Flip a byte of the contents, 'h' becomes 'i'.
+    file
+      .as_file()
+      .write_all_at(b"i", HEADER_WIDTH as u64)
+      .unwrap();
+
+    let store = Store::new(file.reopen().unwrap()).unwrap();
+
+    assert_eq!(
+      Some(&StoreError::ChecksumMismatch { position: 0 }),
+      store.read(0).unwrap_err().downcast_ref::<StoreError>()
+    );
+
+    let mut buffer = vec![0u8; 11];
+
+    assert_eq!(
+      Some(&StoreError::ChecksumMismatch { position: 0 }),
+      store
+        .read_at(&mut buffer, 0)
+        .unwrap_err()
+        .downcast_ref::<StoreError>()
+    );
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test_log::test]
+  fn test_read_with_direct_io() {
+    use std::{fs::OpenOptions, os::unix::fs::OpenOptionsExt};
+
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("0.store");
+
+    let open = || {
+      OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(&path)
+        .unwrap()
+    };
+
+    let mut store = Store::new_direct(open()).unwrap();
+
+    // The second entry crosses a block boundary.
+    let tests = vec![
+      "hello world".as_bytes().to_vec(),
+      vec![7u8; 5000],
+      r#"{"key": "value"}"#.as_bytes().to_vec(),
+    ];
+
+    let mut positions = Vec::new();
+
+    for bytes in &tests {
+      let output = store.append(bytes).unwrap();
+
+      assert_eq!(bytes.to_vec(), store.read(output.appended_at).unwrap());
+
+      positions.push(output.appended_at);
+    }
+
+    let size = store.size();
+
+    store.close().unwrap();
+
+    // The padding of the last block is gone.
+    assert_eq!(size, std::fs::metadata(&path).unwrap().len());
+
+    // Entries are still there after reopening the file.
+    let mut store = Store::new_direct(open()).unwrap();
+
+    for (bytes, position) in tests.iter().zip(positions) {
+      assert_eq!(bytes.to_vec(), store.read(position).unwrap());
+    }
+
+    let output = store.append("abc".as_bytes()).unwrap();
+
+    assert_eq!(size, output.appended_at);
+    assert_eq!(
+      "abc".as_bytes().to_vec(),
+      store.read(output.appended_at).unwrap()
+    );
+  }
+
+  #[test_log::test]
+  fn read_into_reuses_pooled_buffers() {
+    let mut store = Store::with_backend(Box::new(MemoryBackend::new()));
+
+    let pool = BufferPool::new();
+
+    let long = store.append("hello world".as_bytes()).unwrap();
+    let short = store.append("abc".as_bytes()).unwrap();
+
+    let capacity = {
+      let mut buffer = pool.checkout();
+
+      store.read_into(long.appended_at, &mut buffer).unwrap();
+
+      assert_eq!("hello world".as_bytes(), &buffer[..]);
+
+      buffer.capacity()
+    };
+
+    // The buffer went back to the pool and is reused.
+    let mut buffer = pool.checkout();
+
+    assert_eq!(capacity, buffer.capacity());
+
+    store.read_into(short.appended_at, &mut buffer).unwrap();
+
+    assert_eq!("abc".as_bytes(), &buffer[..]);
+  }
+
+  /// Keeps appends buffered until it is flushed and counts the flushes.
+  #[derive(Debug)]
+  struct CountingBackend {
+    inner: MemoryBackend,
+    buffer: Vec<u8>,
+    flushes: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+  }
+
+  impl StorageBackend for CountingBackend {
+    fn append(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+      self.buffer.extend_from_slice(buffer);
+      Ok(())
+    }
+
+    fn read_at(&mut self, buffer: &mut [u8], position: u64) -> std::io::Result<()> {
+      self.flush()?;
+      self.inner.read_at(buffer, position)
+    }
+
+    fn size(&self) -> u64 {
+      self.inner.size() + self.buffer.len() as u64
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      self.inner.append(&self.buffer)?;
+      self.buffer.clear();
+      self
+        .flushes
+        .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+      Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> std::io::Result<()> {
+      self.flush()?;
+      self.inner.truncate(size)
+    }
+
+    fn buffered_bytes(&self) -> u64 {
+      self.buffer.len() as u64
+    }
+  }
+
+  #[test_log::test]
+  fn append_flushes_once_flush_after_bytes_are_buffered() {
+    let flushes = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    let mut store = Store::with_backend(Box::new(CountingBackend {
+      inner: MemoryBackend::new(),
+      buffer: Vec::new(),
+      flushes: std::sync::Arc::clone(&flushes),
+    }))
+    .with_flush_after_bytes(Some(30));
+
+    let flush_count = || flushes.load(std::sync::atomic::Ordering::SeqCst);
+
+    // 8 bytes for the length + 4 bytes for the crc32 + 11 bytes for the value.
+    store.append("hello world".as_bytes()).unwrap();
+
+    assert_eq!(0, flush_count());
+    assert_eq!(23, store.buffered_bytes());
+
+    store.append("hello world".as_bytes()).unwrap();
+
+    assert_eq!(1, flush_count());
+    assert_eq!(0, store.buffered_bytes());
+
+    store.append("hello world".as_bytes()).unwrap();
+
+    assert_eq!(1, flush_count());
+    assert_eq!("hello world".as_bytes(), store.read(46).unwrap());
+  }
+
   #[test_log::test]
   fn test_size() {
     let file_write = NamedTempFile::new().unwrap();
@@ -230,6 +752,6 @@ mod tests {
 
     store.append(bytes).unwrap();
 
-    assert_eq!(store.size(), (bytes.len() + LEN_WIDTH) as u64);
+    assert_eq!(store.size(), (bytes.len() + HEADER_WIDTH) as u64);
   }
 }
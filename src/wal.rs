@@ -0,0 +1,209 @@
+/// The write-ahead log records an append before it is applied to the
+/// store and to the index of a segment.
+///
+/// Appending a record writes two files, if the process crashes after
+/// the store is written but before the index is, the files disagree.
+/// The entry left in the write-ahead log is used when the log is opened
+/// again to finish or redo the interrupted append.
+///
+/// Only the append in progress is kept: the file is emptied once the
+/// append has been applied.
+use std::{
+  fs::{File, OpenOptions},
+  io::Write,
+  os::unix::prelude::FileExt,
+  path::Path,
+};
+
+use anyhow::Result;
+use tracing::warn;
+
+/// Name of the write-ahead log file inside the log directory.
+pub const FILE_NAME: &str = "append.wal";
+
+/// Number of bytes used by the fixed size fields of an entry,
+/// 4 u64 fields and the crc32.
+const HEADER_WIDTH: u64 = 8 * 4 + 4;
+
+#[derive(Debug)]
+pub struct WriteAheadLog {
+  file: File,
+}
+
+/// An append that has not been applied yet.
+///
+/// An entry looks like this:
+///
+/// ```text
+/// ┌─────────────┬────────┬──────────┬─────┬───────┬────────────────┐
+/// │ base offset │ offset │ position │ LEN │ CRC32 │ encoded record │
+/// └─────────────┴────────┴──────────┴─────┴───────┴────────────────┘
+/// ```
+///
+/// The crc32 covers the fields before it and the encoded record.
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+  /// Base offset of the segment the record is appended to.
+  pub base_offset: u64,
+  pub offset: u64,
+  /// Where the record is appended in the segment store.
+  pub position: u64,
+  /// The record, as encoded by `Segment::encode`.
+  pub bytes: Vec<u8>,
+}
+
+impl WriteAheadLog {
+  /// Opens the write-ahead log stored in directory, creating it
+  /// if it does not exist.
+  pub fn new(directory: &str) -> Result<Self> {
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(Path::new(directory).join(FILE_NAME))?;
+
+    Ok(Self { file })
+  }
+
+  /// Returns the entry in the write-ahead log, if any.
+  ///
+  /// An entry that was not completely written, or whose crc32 does
+  /// not match, is ignored because its append was never started.
+  pub fn read(&self) -> Result<Option<Entry>> {
+    let len = self.file.metadata()?.len();
+
+    if len < HEADER_WIDTH {
+      return Ok(None);
+    }
+
+    let mut header = [0u8; HEADER_WIDTH as usize];
+
+    self.file.read_exact_at(&mut header, 0)?;
+
+    let field = |i: usize| {
+      let mut buffer = [0u8; 8];
+      buffer.copy_from_slice(&header[i * 8..(i + 1) * 8]);
+      u64::from_be_bytes(buffer)
+    };
+
+    let bytes_len = field(3);
+
+    if len < HEADER_WIDTH.saturating_add(bytes_len) {
+      return Ok(None);
+    }
+
+    let mut bytes = vec![0u8; bytes_len as usize];
+
+    self.file.read_exact_at(&mut bytes, HEADER_WIDTH)?;
+
+    let mut crc = [0u8; 4];
+    crc.copy_from_slice(&header[32..]);
+
+    if u32::from_be_bytes(crc) != checksum(&header[..32], &bytes) {
+      warn!("ignoring write-ahead log entry whose checksum does not match");
+      return Ok(None);
+    }
+
+    Ok(Some(Entry {
+      base_offset: field(0),
+      offset: field(1),
+      position: field(2),
+      bytes,
+    }))
+  }
+
+  /// Replaces the entry in the write-ahead log with entry
+  /// and waits for it to reach the disk.
+  pub fn write(&mut self, entry: &Entry) -> Result<()> {
+    let mut buffer = Vec::with_capacity(HEADER_WIDTH as usize + entry.bytes.len());
+
+    for field in [
+      entry.base_offset,
+      entry.offset,
+      entry.position,
+      entry.bytes.len() as u64,
+    ] {
+      buffer.write_all(&field.to_be_bytes())?;
+    }
+
+    let crc = checksum(&buffer, &entry.bytes);
+
+    buffer.write_all(&crc.to_be_bytes())?;
+    buffer.write_all(&entry.bytes)?;
+
+    self.file.set_len(0)?;
+    self.file.write_all_at(&buffer, 0)?;
+    self.file.sync_data()?;
+
+    Ok(())
+  }
+
+  /// Empties the write-ahead log once its entry has been applied.
+  pub fn checkpoint(&mut self) -> Result<()> {
+    self.file.set_len(0)?;
+    self.file.sync_data()?;
+
+    Ok(())
+  }
+}
+
+/// Returns the crc32 of the fields of an entry and its encoded record.
+fn checksum(fields: &[u8], bytes: &[u8]) -> u32 {
+  let mut hasher = crc32fast::Hasher::new();
+
+  hasher.update(fields);
+  hasher.update(bytes);
+
+  hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_log::test]
+  fn read_returns_the_written_entry_until_checkpoint() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let mut wal = WriteAheadLog::new(directory.path().to_str().unwrap()).unwrap();
+
+    assert_eq!(None, wal.read().unwrap());
+
+    let entry = Entry {
+      base_offset: 3,
+      offset: 5,
+      position: 26,
+      bytes: "hello world".as_bytes().to_vec(),
+    };
+
+    wal.write(&entry).unwrap();
+
+    assert_eq!(Some(entry), wal.read().unwrap());
+
+    wal.checkpoint().unwrap();
+
+    assert_eq!(None, wal.read().unwrap());
+  }
+
+  #[test_log::test]
+  fn entries_whose_checksum_does_not_match_are_ignored() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let mut wal = WriteAheadLog::new(directory.path().to_str().unwrap()).unwrap();
+
+    wal
+      .write(&Entry {
+        base_offset: 3,
+        offset: 5,
+        position: 26,
+        bytes: "hello world".as_bytes().to_vec(),
+      })
+      .unwrap();
+
+    // A torn write changed the first byte of the record.
+    wal.file.write_all_at(b"j", HEADER_WIDTH).unwrap();
+
+    assert_eq!(None, wal.read().unwrap());
+  }
+}